@@ -0,0 +1,305 @@
+use crate::db::{BanInfo, NodeInfo};
+use crate::manager::ManagerHandle;
+use crate::p2p::PeerHandle;
+use crate::p2p::dialer::DialTarget;
+use crate::timeline::TxRecord;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use bitcoin::Txid;
+use bitcoin::hashes::Hash;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::info;
+
+// JSON admin API served next to /metrics when --enable-admin-api is set.
+pub fn router(manager: ManagerHandle) -> Router {
+    Router::new()
+        .route("/api/peers", get(list_peers))
+        .route("/api/peers/disconnect", post(disconnect_peer))
+        .route("/api/peers/manual", post(add_manual_peer))
+        .route("/api/nodes", get(lookup_node))
+        .route("/api/bans", get(list_bans))
+        .route("/api/bans/lift", post(lift_ban))
+        .route("/api/txs/:txid", get(tx_timeline))
+        .route(
+            "/api/target-peers",
+            get(get_target_peers).put(set_target_peers),
+        )
+        .with_state(manager)
+}
+
+#[derive(Serialize)]
+struct PeerView {
+    addr: SocketAddr,
+    node_type: &'static str,
+    user_agent: String,
+    direction: &'static str,
+    connected_at: String,
+    v2_transport: bool,
+    wtxid_relay: bool,
+    package_relay: bool,
+    fee_filter_sat_per_kvb: u64,
+    ping_ms: Option<f64>,
+    min_ping_ms: Option<f64>,
+}
+
+impl From<&PeerHandle> for PeerView {
+    fn from(peer: &PeerHandle) -> Self {
+        Self {
+            addr: peer.addr(),
+            node_type: peer.node_type().as_str(),
+            user_agent: peer.user_agent().to_string(),
+            direction: if peer.is_inbound() { "inbound" } else { "outbound" },
+            connected_at: peer.connected_at().to_rfc3339(),
+            v2_transport: peer.v2_transport(),
+            wtxid_relay: peer.wtxid_relay(),
+            package_relay: peer.package_relay(),
+            fee_filter_sat_per_kvb: peer.fee_filter(),
+            ping_ms: peer.ping().map(millis),
+            min_ping_ms: peer.min_ping().map(millis),
+        }
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[derive(Serialize)]
+struct NodeView {
+    addr: String,
+    network: &'static str,
+    node_type: &'static str,
+    user_agent: Option<String>,
+    version: Option<i32>,
+    services: Option<u64>,
+    last_seen: String,
+    last_connected: Option<String>,
+    connection_failures: u32,
+    is_reachable: bool,
+    v2_transport: Option<bool>,
+}
+
+impl From<NodeInfo> for NodeView {
+    fn from(node: NodeInfo) -> Self {
+        Self {
+            addr: node.addr.to_string(),
+            network: node.addr.network().as_str(),
+            node_type: node.node_type.as_str(),
+            user_agent: node.user_agent,
+            version: node.version,
+            services: node.services,
+            last_seen: node.last_seen.to_rfc3339(),
+            last_connected: node.last_connected.map(|t| t.to_rfc3339()),
+            connection_failures: node.connection_failures,
+            is_reachable: node.is_reachable,
+            v2_transport: node.v2_transport,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BanView {
+    ip: IpAddr,
+    reason: String,
+    banned_at: String,
+    banned_until: String,
+}
+
+impl From<BanInfo> for BanView {
+    fn from(ban: BanInfo) -> Self {
+        Self {
+            ip: ban.ip,
+            reason: ban.reason,
+            banned_at: ban.banned_at.to_rfc3339(),
+            banned_until: ban.banned_until.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AnnouncementView {
+    peer: SocketAddr,
+    node_type: &'static str,
+    at: String,
+    delay_ms: u128,
+}
+
+#[derive(Serialize)]
+struct TxTimelineView {
+    txid: Option<String>,
+    wtxid: Option<String>,
+    first_seen: String,
+    announcements: Vec<AnnouncementView>,
+    requested_from: Option<SocketAddr>,
+    requested_at: Option<String>,
+    received_from: Option<SocketAddr>,
+    received_from_type: Option<&'static str>,
+    received_at: Option<String>,
+    relayed_at: Option<String>,
+    relayed_to_peers: Option<usize>,
+}
+
+impl From<TxRecord> for TxTimelineView {
+    fn from(record: TxRecord) -> Self {
+        // txid and wtxid share the same reversed-hex display format.
+        let hex = |hash: [u8; 32]| Txid::from_byte_array(hash).to_string();
+        Self {
+            txid: record.txid.map(hex),
+            wtxid: record.wtxid.map(hex),
+            first_seen: record.first_seen.to_rfc3339(),
+            announcements: record
+                .announcements
+                .iter()
+                .map(|a| AnnouncementView {
+                    peer: a.peer,
+                    node_type: a.node_type.as_str(),
+                    at: a.at.to_rfc3339(),
+                    delay_ms: a.delay.as_millis(),
+                })
+                .collect(),
+            requested_from: record.request.as_ref().map(|r| r.peer),
+            requested_at: record.request.as_ref().map(|r| r.at.to_rfc3339()),
+            received_from: record.arrival.as_ref().map(|a| a.peer),
+            received_from_type: record.arrival.as_ref().map(|a| a.node_type.as_str()),
+            received_at: record.arrival.as_ref().map(|a| a.at.to_rfc3339()),
+            relayed_at: record.relay.as_ref().map(|r| r.at.to_rfc3339()),
+            relayed_to_peers: record.relay.as_ref().map(|r| r.peers),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AddrRequest {
+    addr: String,
+}
+
+#[derive(Deserialize)]
+struct TargetPeersRequest {
+    target_peers: usize,
+}
+
+async fn list_peers(State(manager): State<ManagerHandle>) -> Json<Vec<PeerView>> {
+    let peers = manager.peers().await;
+    Json(peers.iter().map(PeerView::from).collect())
+}
+
+async fn disconnect_peer(
+    State(manager): State<ManagerHandle>,
+    Json(request): Json<AddrRequest>,
+) -> Response {
+    let addr = match request.addr.parse::<SocketAddr>() {
+        Ok(addr) => addr,
+        Err(e) => return error(StatusCode::BAD_REQUEST, format!("invalid addr: {}", e)),
+    };
+
+    if manager.disconnect_peer(addr).await {
+        info!("Disconnecting peer {} on admin request", addr);
+        Json(json!({ "disconnected": addr })).into_response()
+    } else {
+        error(StatusCode::NOT_FOUND, format!("peer {} is not connected", addr))
+    }
+}
+
+async fn add_manual_peer(
+    State(manager): State<ManagerHandle>,
+    Json(request): Json<AddrRequest>,
+) -> Response {
+    let target = match DialTarget::parse(&request.addr, manager.network().default_port()) {
+        Ok(target) => target,
+        Err(e) => return error(StatusCode::BAD_REQUEST, format!("invalid addr: {}", e)),
+    };
+
+    info!("Adding manual peer {} on admin request", target);
+    let response = json!({ "added": target.to_string() });
+    manager.add_manual_peer(target).await;
+    Json(response).into_response()
+}
+
+async fn lookup_node(
+    State(manager): State<ManagerHandle>,
+    Query(request): Query<AddrRequest>,
+) -> Response {
+    // IP addresses are stored canonicalized, so accept e.g. IPv4-mapped forms too.
+    let key = match request.addr.parse::<SocketAddr>() {
+        Ok(addr) => SocketAddr::new(addr.ip().to_canonical(), addr.port()).to_string(),
+        Err(_) => request.addr.to_lowercase(),
+    };
+
+    match manager.db().get_node(&key) {
+        Ok(Some(node)) => Json(NodeView::from(node)).into_response(),
+        Ok(None) => error(StatusCode::NOT_FOUND, format!("node {} not found", key)),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+async fn list_bans(State(manager): State<ManagerHandle>) -> Response {
+    match manager.db().list_bans() {
+        Ok(bans) => Json(bans.into_iter().map(BanView::from).collect::<Vec<_>>()).into_response(),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+// Bans cover the whole IP, so a port in the address is ignored.
+async fn lift_ban(
+    State(manager): State<ManagerHandle>,
+    Json(request): Json<AddrRequest>,
+) -> Response {
+    let ip = match request.addr.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(e) => match request.addr.parse::<SocketAddr>() {
+            Ok(addr) => addr.ip(),
+            Err(_) => return error(StatusCode::BAD_REQUEST, format!("invalid addr: {}", e)),
+        },
+    };
+
+    match manager.db().unban(ip) {
+        Ok(true) => {
+            info!("Lifting ban on {} on admin request", ip);
+            Json(json!({ "unbanned": ip })).into_response()
+        }
+        Ok(false) => error(StatusCode::NOT_FOUND, format!("{} is not banned", ip)),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+// Accepts either the txid or the wtxid.
+async fn tx_timeline(State(manager): State<ManagerHandle>, Path(txid): Path<String>) -> Response {
+    let key = match Txid::from_str(&txid) {
+        Ok(txid) => txid.to_byte_array(),
+        Err(e) => return error(StatusCode::BAD_REQUEST, format!("invalid txid: {}", e)),
+    };
+
+    match manager.tx_record(&key).await {
+        Some(record) => Json(TxTimelineView::from(record)).into_response(),
+        None => error(StatusCode::NOT_FOUND, format!("tx {} not in timeline", txid)),
+    }
+}
+
+async fn get_target_peers(State(manager): State<ManagerHandle>) -> Response {
+    Json(json!({ "target_peers": manager.target_peers() })).into_response()
+}
+
+// Lowering the target does not drop existing connections; the refill loop just stops dialing.
+async fn set_target_peers(
+    State(manager): State<ManagerHandle>,
+    Json(request): Json<TargetPeersRequest>,
+) -> Response {
+    let previous = manager.target_peers();
+    manager.set_target_peers(request.target_peers);
+    info!(
+        "Target peers changed from {} to {} on admin request",
+        previous, request.target_peers
+    );
+    Json(json!({ "target_peers": request.target_peers, "previous": previous })).into_response()
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+// Score at which a peer is disconnected and its IP banned (Bitcoin Core's old -banscore).
+const BAN_THRESHOLD: u32 = 100;
+// How long a ban lasts (Bitcoin Core's default -bantime).
+pub const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Misbehavior {
+    // v1 message that does not start with our network magic; the stream is lost.
+    BadMagic,
+    OversizedMessage,
+    // Well-framed message whose payload does not decode.
+    ParseFailure,
+    InvalidTx,
+    InvalidHeaders,
+    InvalidCompactBlock,
+    // Full block whose txs do not match its header.
+    InvalidBlock,
+    // Tx we neither requested nor were waiting for as an orphan's parent.
+    UnsolicitedTx,
+    // addr or addrv2 with more than 1000 entries.
+    AddrSpam,
+}
+
+impl Misbehavior {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Misbehavior::BadMagic => "bad_magic",
+            Misbehavior::OversizedMessage => "oversized_message",
+            Misbehavior::ParseFailure => "parse_failure",
+            Misbehavior::InvalidTx => "invalid_tx",
+            Misbehavior::InvalidHeaders => "invalid_headers",
+            Misbehavior::InvalidCompactBlock => "invalid_compact_block",
+            Misbehavior::InvalidBlock => "invalid_block",
+            Misbehavior::UnsolicitedTx => "unsolicited_tx",
+            Misbehavior::AddrSpam => "addr_spam",
+        }
+    }
+
+    // Invalid data no honest node sends costs the whole score at once; things a buggy or
+    // differently configured node might do take a few repeats.
+    fn score(self) -> u32 {
+        match self {
+            Misbehavior::BadMagic
+            | Misbehavior::OversizedMessage
+            | Misbehavior::InvalidTx
+            | Misbehavior::InvalidHeaders
+            | Misbehavior::InvalidCompactBlock
+            | Misbehavior::InvalidBlock => BAN_THRESHOLD,
+            Misbehavior::AddrSpam => 20,
+            Misbehavior::ParseFailure => 10,
+            Misbehavior::UnsolicitedTx => 5,
+        }
+    }
+}
+
+// Loopback and unspecified addresses (Bitcoin Core's CNetAddr::IsLocal). Tor and I2P inbound
+// connections all come from there, so a ban would shut out every one of them.
+pub fn is_local(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.octets()[0] == 0,
+        IpAddr::V6(ip) => ip.is_loopback(),
+    }
+}
+
+// Misbehavior scores of connected peers; a reconnecting peer starts from zero.
+#[derive(Default)]
+pub struct MisbehaviorScores {
+    scores: HashMap<SocketAddr, u32>,
+}
+
+impl MisbehaviorScores {
+    // Returns true once when the peer's score reaches the ban threshold.
+    pub fn add(&mut self, peer: SocketAddr, misbehavior: Misbehavior) -> bool {
+        let score = self.scores.entry(peer).or_default();
+        let before = *score;
+        *score = score.saturating_add(misbehavior.score());
+        before < BAN_THRESHOLD && *score >= BAN_THRESHOLD
+    }
+
+    pub fn disconnected(&mut self, peer: SocketAddr) {
+        self.scores.remove(&peer);
+    }
+}
@@ -0,0 +1,149 @@
+use bitcoin::opcodes::all::{OP_ENDIF, OP_IF, OP_PUSHNUM_13, OP_RETURN};
+use bitcoin::script::Instruction;
+use bitcoin::{Script, Transaction};
+
+// Bitcoin Core's default -datacarriersize: 80 bytes of data plus the OP_RETURN and push opcodes.
+pub const MAX_OP_RETURN_RELAY: usize = 83;
+// Ordinals envelope marker pushed right after OP_FALSE OP_IF.
+const ORD_TAG: &[u8] = b"ord";
+const BRC20_TAG: &[u8] = b"\"brc-20\"";
+
+// Content patterns that filtering nodes (Knots in particular) refuse to relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TxCategory {
+    Inscription,
+    Brc20,
+    Runes,
+    LargeOpReturn,
+    BareMultisig,
+    Dust,
+    NonStandardScript,
+}
+
+impl TxCategory {
+    pub const ALL: [TxCategory; 7] = [
+        TxCategory::Inscription,
+        TxCategory::Brc20,
+        TxCategory::Runes,
+        TxCategory::LargeOpReturn,
+        TxCategory::BareMultisig,
+        TxCategory::Dust,
+        TxCategory::NonStandardScript,
+    ];
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|category| category.as_str() == value)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TxCategory::Inscription => "inscription",
+            TxCategory::Brc20 => "brc20",
+            TxCategory::Runes => "runes",
+            TxCategory::LargeOpReturn => "large_op_return",
+            TxCategory::BareMultisig => "bare_multisig",
+            TxCategory::Dust => "dust",
+            TxCategory::NonStandardScript => "non_standard_script",
+        }
+    }
+}
+
+// Every category the tx falls into; empty for plain payments. BRC-20 txs are also inscriptions.
+pub fn classify(tx: &Transaction) -> Vec<TxCategory> {
+    let mut categories = Vec::new();
+
+    let envelopes = tx
+        .input
+        .iter()
+        .filter_map(|input| input.witness.taproot_leaf_script())
+        .filter_map(|leaf| inscription_payload(leaf.script))
+        .collect::<Vec<_>>();
+    if !envelopes.is_empty() {
+        categories.push(TxCategory::Inscription);
+    }
+    if envelopes.iter().any(|payload| contains(payload, BRC20_TAG)) {
+        categories.push(TxCategory::Brc20);
+    }
+
+    let outputs = &tx.output;
+    if outputs.iter().any(|out| is_runestone(&out.script_pubkey)) {
+        categories.push(TxCategory::Runes);
+    }
+    if outputs.iter().any(|out| {
+        out.script_pubkey.is_op_return() && out.script_pubkey.len() > MAX_OP_RETURN_RELAY
+    }) {
+        categories.push(TxCategory::LargeOpReturn);
+    }
+    if outputs.iter().any(|out| out.script_pubkey.is_multisig()) {
+        categories.push(TxCategory::BareMultisig);
+    }
+    if outputs.iter().any(|out| {
+        !out.script_pubkey.is_op_return() && out.value < out.script_pubkey.minimal_non_dust()
+    }) {
+        categories.push(TxCategory::Dust);
+    }
+    if outputs
+        .iter()
+        .any(|out| !is_standard_output(&out.script_pubkey))
+    {
+        categories.push(TxCategory::NonStandardScript);
+    }
+
+    categories
+}
+
+// Concatenated pushes of an `OP_FALSE OP_IF "ord" ... OP_ENDIF` envelope, if the script has one.
+fn inscription_payload(script: &Script) -> Option<Vec<u8>> {
+    let mut instructions = script.instructions();
+    let mut previous: [Option<Instruction>; 2] = [None, None];
+
+    while let Some(Ok(instruction)) = instructions.next() {
+        let opens_envelope = matches!(previous[0], Some(Instruction::PushBytes(b)) if b.is_empty())
+            && matches!(previous[1], Some(Instruction::Op(op)) if op == OP_IF)
+            && matches!(instruction, Instruction::PushBytes(b) if b.as_bytes() == ORD_TAG);
+        if opens_envelope {
+            let mut payload = Vec::new();
+            for instruction in instructions.by_ref() {
+                match instruction {
+                    Ok(Instruction::PushBytes(bytes)) => {
+                        payload.extend_from_slice(bytes.as_bytes())
+                    }
+                    Ok(Instruction::Op(op)) if op == OP_ENDIF => break,
+                    Ok(Instruction::Op(_)) => {}
+                    Err(_) => break,
+                }
+            }
+            return Some(payload);
+        }
+        previous = [previous[1], Some(instruction)];
+    }
+    None
+}
+
+// Runestones are an OP_RETURN output whose first data opcode is OP_13.
+fn is_runestone(script: &Script) -> bool {
+    script
+        .as_bytes()
+        .starts_with(&[OP_RETURN.to_u8(), OP_PUSHNUM_13.to_u8()])
+}
+
+// Output types Bitcoin Core's IsStandard accepts; unknown witness versions count as standard.
+pub fn is_standard_output(script: &Script) -> bool {
+    script.is_p2pkh()
+        || script.is_p2sh()
+        || script.is_witness_program()
+        || script.is_p2pk()
+        || script.is_multisig()
+        || is_null_data(script)
+}
+
+// OP_RETURN followed only by data pushes (TxoutType::NULL_DATA in Core).
+fn is_null_data(script: &Script) -> bool {
+    script.is_op_return() && Script::from_bytes(&script.as_bytes()[1..]).is_push_only()
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
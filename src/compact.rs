@@ -0,0 +1,295 @@
+use bitcoin::bip152::{BlockTransactions, HeaderAndShortIds, ShortId};
+use bitcoin::block::Header;
+use bitcoin::{Block, BlockHash, Transaction, Wtxid};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// BIP152 version we speak; version 2 short ids commit to wtxids and txs carry witnesses.
+pub const COMPACT_BLOCK_VERSION: u64 = 2;
+// Peers asked to push new blocks to us straight away (Bitcoin Core also picks three).
+const MAX_HIGH_BANDWIDTH_FROM: usize = 3;
+// Reconstructed blocks kept to answer getblocktxn and getdata.
+const MAX_RECENT_BLOCKS: usize = 8;
+// Compact blocks waiting for a blocktxn answer.
+const MAX_PENDING_BLOCKS: usize = 16;
+// How long a requested block, or the missing txs of one, is left to one peer before
+// another may be asked.
+const BLOCK_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactBlockError {
+    // Prefilled indexes out of range; no honest peer sends this.
+    Malformed,
+    // Two txs of the block share a short id; only the full block can resolve it.
+    ShortIdCollision,
+}
+
+pub enum Reconstruction {
+    Complete(Block),
+    // Block indexes to request with getblocktxn.
+    Missing(Vec<u64>),
+}
+
+pub struct ReconstructionStats {
+    // Short ids in the compact block (everything but the prefilled txs).
+    pub short_ids: usize,
+    // Short ids matched against the txs we already had.
+    pub cached: usize,
+}
+
+struct PartialBlock {
+    header: Header,
+    txs: Vec<Option<Transaction>>,
+    from: SocketAddr,
+    // When the missing txs were asked for.
+    requested_at: Instant,
+}
+
+impl PartialBlock {
+    fn into_block(self) -> Option<Block> {
+        Some(Block {
+            header: self.header,
+            txdata: self.txs.into_iter().collect::<Option<Vec<_>>>()?,
+        })
+    }
+}
+
+// Compact block relay: which peers want blocks pushed, reconstructions waiting on missing
+// txs, and the last few blocks we can serve.
+#[derive(Default)]
+pub struct CompactBlockState {
+    supported: HashSet<SocketAddr>,
+    // Peers that asked for high-bandwidth mode (sendcmpct with announce set).
+    high_bandwidth_to: HashSet<SocketAddr>,
+    // Peers we asked for high-bandwidth mode, most recently promoted first.
+    high_bandwidth_from: VecDeque<SocketAddr>,
+    requested: HashMap<BlockHash, Instant>,
+    pending: HashMap<BlockHash, PartialBlock>,
+    pending_order: VecDeque<BlockHash>,
+    recent: VecDeque<Block>,
+}
+
+impl CompactBlockState {
+    pub fn received_sendcmpct(&mut self, peer: SocketAddr, announce: bool, version: u64) {
+        if version != COMPACT_BLOCK_VERSION {
+            return;
+        }
+        self.supported.insert(peer);
+        if announce {
+            self.high_bandwidth_to.insert(peer);
+        } else {
+            self.high_bandwidth_to.remove(&peer);
+        }
+    }
+
+    pub fn supports(&self, peer: SocketAddr) -> bool {
+        self.supported.contains(&peer)
+    }
+
+    pub fn wants_high_bandwidth(&self, peer: SocketAddr) -> bool {
+        self.high_bandwidth_to.contains(&peer)
+    }
+
+    // Makes `peer`, which just delivered a new block first, one of our high-bandwidth
+    // announcers. Returns the sendcmpct announce flags to send, as in Core's
+    // MaybeSetPeerAsAnnouncingHeaderAndIDs.
+    pub fn promote(&mut self, peer: SocketAddr) -> Vec<(SocketAddr, bool)> {
+        if !self.supports(peer) {
+            return Vec::new();
+        }
+        if let Some(position) = self.high_bandwidth_from.iter().position(|p| *p == peer) {
+            self.high_bandwidth_from.remove(position);
+            self.high_bandwidth_from.push_front(peer);
+            return Vec::new();
+        }
+
+        let mut changes = vec![(peer, true)];
+        self.high_bandwidth_from.push_front(peer);
+        if self.high_bandwidth_from.len() > MAX_HIGH_BANDWIDTH_FROM
+            && let Some(demoted) = self.high_bandwidth_from.pop_back()
+        {
+            changes.push((demoted, false));
+        }
+        changes
+    }
+
+    pub fn disconnected(&mut self, peer: SocketAddr) {
+        self.supported.remove(&peer);
+        self.high_bandwidth_to.remove(&peer);
+        self.high_bandwidth_from.retain(|p| *p != peer);
+        self.pending.retain(|_, partial| partial.from != peer);
+        let pending = &self.pending;
+        self.pending_order.retain(|hash| pending.contains_key(hash));
+    }
+
+    pub fn recent_block(&self, hash: &BlockHash) -> Option<&Block> {
+        self.recent.iter().find(|block| block.block_hash() == *hash)
+    }
+
+    // Whether the block is already reconstructed or being reconstructed. A reconstruction
+    // whose peer let the missing txs time out no longer counts, so another peer's compact
+    // block can take over.
+    pub fn knows(&self, hash: &BlockHash, now: Instant) -> bool {
+        self.pending.get(hash).is_some_and(|partial| {
+            now.duration_since(partial.requested_at) < BLOCK_REQUEST_TIMEOUT
+        }) || self.recent_block(hash).is_some()
+    }
+
+    // Whether the block's missing txs were asked of `peer`.
+    pub fn awaits(&self, hash: &BlockHash, peer: SocketAddr) -> bool {
+        self.pending.get(hash).is_some_and(|partial| partial.from == peer)
+    }
+
+    // Drops reconstructions whose missing txs timed out, returning each block and the peer
+    // that let it stall.
+    pub fn take_stalled(&mut self, now: Instant) -> Vec<(BlockHash, SocketAddr)> {
+        let stalled: Vec<(BlockHash, SocketAddr)> = self
+            .pending
+            .iter()
+            .filter(|(_, partial)| {
+                now.duration_since(partial.requested_at) >= BLOCK_REQUEST_TIMEOUT
+            })
+            .map(|(hash, partial)| (*hash, partial.from))
+            .collect();
+        for (hash, _) in &stalled {
+            self.pending.remove(hash);
+        }
+        let pending = &self.pending;
+        self.pending_order.retain(|hash| pending.contains_key(hash));
+        stalled
+    }
+
+    // Whether to ask for the compact block; records the request if so.
+    pub fn request(&mut self, hash: BlockHash, now: Instant) -> bool {
+        self.requested
+            .retain(|_, sent| now.duration_since(*sent) < BLOCK_REQUEST_TIMEOUT);
+        if self.knows(&hash, now) || self.requested.contains_key(&hash) {
+            return false;
+        }
+        self.requested.insert(hash, now);
+        true
+    }
+
+    // Fills in a compact block from `candidates` (the txs we relayed or hold as orphans,
+    // with their wtxids).
+    // Anything missing is remembered until `fill` brings it.
+    pub fn reconstruct<'a>(
+        &mut self,
+        from: SocketAddr,
+        compact: &HeaderAndShortIds,
+        candidates: impl Iterator<Item = (Wtxid, &'a Transaction)>,
+    ) -> Result<(Reconstruction, ReconstructionStats), CompactBlockError> {
+        let total = compact.prefilled_txs.len() + compact.short_ids.len();
+        let mut txs: Vec<Option<Transaction>> = vec![None; total];
+
+        // Prefilled indexes are differentially encoded.
+        let mut next = 0usize;
+        for prefilled in &compact.prefilled_txs {
+            let index = next + usize::from(prefilled.idx);
+            let Some(slot) = txs.get_mut(index) else {
+                return Err(CompactBlockError::Malformed);
+            };
+            *slot = Some(prefilled.tx.clone());
+            next = index + 1;
+        }
+
+        let mut slots = HashMap::with_capacity(compact.short_ids.len());
+        let mut empty = (0..total).filter(|index| txs[*index].is_none());
+        for short_id in &compact.short_ids {
+            let index = empty.next().ok_or(CompactBlockError::Malformed)?;
+            if slots.insert(*short_id, index).is_some() {
+                return Err(CompactBlockError::ShortIdCollision);
+            }
+        }
+
+        let keys = ShortId::calculate_siphash_keys(&compact.header, compact.nonce);
+        let mut collided = HashSet::new();
+        for (wtxid, tx) in candidates {
+            let short_id = ShortId::with_siphash_keys(&wtxid, keys);
+            let Some(&index) = slots.get(&short_id) else {
+                continue;
+            };
+            // Two of our txs match one short id: ask for it rather than guess.
+            if txs[index].is_some() || collided.contains(&index) {
+                txs[index] = None;
+                collided.insert(index);
+                continue;
+            }
+            txs[index] = Some(tx.clone());
+        }
+
+        let missing: Vec<u64> = (0..total)
+            .filter(|index| txs[*index].is_none())
+            .map(|index| index as u64)
+            .collect();
+        let stats = ReconstructionStats {
+            short_ids: compact.short_ids.len(),
+            cached: compact.short_ids.len() - missing.len(),
+        };
+
+        let partial = PartialBlock {
+            header: compact.header,
+            txs,
+            from,
+            requested_at: Instant::now(),
+        };
+        if missing.is_empty() {
+            let block = partial.into_block().ok_or(CompactBlockError::Malformed)?;
+            return Ok((Reconstruction::Complete(block), stats));
+        }
+
+        let hash = compact.header.block_hash();
+        // Replaces a stalled reconstruction of the same block.
+        if self.pending.insert(hash, partial).is_some() {
+            self.pending_order.retain(|pending| *pending != hash);
+        }
+        self.pending_order.push_back(hash);
+        while self.pending_order.len() > MAX_PENDING_BLOCKS {
+            if let Some(oldest) = self.pending_order.pop_front() {
+                self.pending.remove(&oldest);
+            }
+        }
+        Ok((Reconstruction::Missing(missing), stats))
+    }
+
+    // Completes a pending block with a blocktxn answer. `None` if we were not waiting for it
+    // from this peer, which leaves the pending block to the peer we asked, or if the answer
+    // does not fit, which drops it.
+    pub fn fill(&mut self, from: SocketAddr, response: BlockTransactions) -> Option<Block> {
+        if self
+            .pending
+            .get(&response.block_hash)
+            .is_none_or(|partial| partial.from != from)
+        {
+            return None;
+        }
+        let mut partial = self.pending.remove(&response.block_hash)?;
+        self.pending_order.retain(|hash| *hash != response.block_hash);
+
+        let mut supplied = response.transactions.into_iter();
+        for slot in partial.txs.iter_mut().filter(|slot| slot.is_none()) {
+            *slot = Some(supplied.next()?);
+        }
+        if supplied.next().is_some() {
+            return None;
+        }
+        partial.into_block()
+    }
+
+    // Keeps a block whose txs match its header so it can be served; false if they do not.
+    pub fn add_block(&mut self, block: Block) -> bool {
+        if !block.check_merkle_root() || !block.check_witness_commitment() {
+            return false;
+        }
+        self.requested.remove(&block.block_hash());
+        if self.recent_block(&block.block_hash()).is_none() {
+            self.recent.push_back(block);
+            if self.recent.len() > MAX_RECENT_BLOCKS {
+                self.recent.pop_front();
+            }
+        }
+        true
+    }
+}
+
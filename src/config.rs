@@ -1,18 +1,40 @@
-use clap::Parser;
+use bitcoin::p2p::Magic;
+use bitcoin::params::Params;
+use clap::{Parser, ValueEnum};
 use std::net::SocketAddr;
 
 #[derive(Parser, Clone, Debug)]
 #[command(name = "crab-router")]
 #[command(about = "Aggressive Bitcoin P2P relay node for topology exploration")]
 pub struct Config {
+    #[arg(long, value_enum, default_value_t = Network::Mainnet)]
+    pub network: Network,
+
     #[arg(long, default_value = "0.0.0.0:15444")]
     pub metrics_addr: SocketAddr,
 
+    /// Outbound connections to keep open; manual peers come on top.
     #[arg(long, default_value = "1000")]
     pub target_peers: usize,
 
-    #[arg(long, default_value = "8333")]
-    pub listen_port: u16,
+    /// Inbound connection slots. When full, a newcomer replaces the least useful inbound
+    /// peer, if any is not protected; 0 disables listening.
+    #[arg(long, default_value = "256")]
+    pub max_inbound_peers: usize,
+
+    /// Serve the JSON admin API under /api on the metrics address. It can drop and add
+    /// peers, so only enable it when the metrics address is not publicly reachable.
+    #[arg(long, default_value_t = false, action = clap::ArgAction::Set)]
+    pub enable_admin_api: bool,
+
+    /// Defaults to the selected network's standard P2P port.
+    #[arg(long)]
+    pub listen_port: Option<u16>,
+
+    /// Fraction of the outbound peer budget dialed over IPv6 first; IPv4 fills what IPv6
+    /// cannot (0 disables IPv6 dialing).
+    #[arg(long, default_value = "0.25")]
+    pub ipv6_peer_share: f64,
 
     #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
     pub enable_discovery: bool,
@@ -25,4 +47,119 @@ pub struct Config {
 
     #[arg(long, default_value = "/Crab Router:1.0.0/")]
     pub user_agent: String,
+
+    /// SOCKS5 proxy for outbound connections, e.g. a local Tor daemon at 127.0.0.1:9050.
+    #[arg(long)]
+    pub proxy: Option<SocketAddr>,
+
+    /// Use random SOCKS5 credentials per connection so Tor isolates each stream.
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub proxy_randomize: bool,
+
+    /// Bitcoin Core REST endpoint (node started with -rest), used to look up input values
+    /// for feerates when parents are not in our tx cache, e.g. 127.0.0.1:8332.
+    #[arg(long)]
+    pub prevout_rest_addr: Option<SocketAddr>,
+
+    /// Answer BIP35 mempool requests with an inv of the cached txs each peer's feefilter
+    /// admits, so freshly started nodes can fill their mempool from us.
+    #[arg(long, default_value_t = false, action = clap::ArgAction::Set)]
+    pub serve_mempool: bool,
+
+    /// Which received txs are dropped instead of relayed. `consensus` only drops txs that
+    /// could never be valid; `standard` also drops what Bitcoin Core's default policy would.
+    #[arg(long, value_enum, default_value_t = PolicyMode::Consensus)]
+    pub policy_mode: PolicyMode,
+
+    /// Peer to keep connected in addition to discovered ones (host:port, .onion needs --proxy).
+    #[arg(long = "addnode")]
+    pub add_nodes: Vec<String>,
+}
+
+impl Config {
+    pub fn listen_port(&self) -> u16 {
+        self.listen_port.unwrap_or(self.network.default_port())
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+impl Network {
+    pub fn magic(&self) -> Magic {
+        match self {
+            Network::Mainnet => Magic::BITCOIN,
+            Network::Testnet4 => Magic::TESTNET4,
+            Network::Signet => Magic::SIGNET,
+            Network::Regtest => Magic::REGTEST,
+        }
+    }
+
+    pub fn params(&self) -> &'static Params {
+        match self {
+            Network::Mainnet => &Params::MAINNET,
+            Network::Testnet4 => &Params::TESTNET4,
+            Network::Signet => &Params::SIGNET,
+            Network::Regtest => &Params::REGTEST,
+        }
+    }
+
+    pub fn default_port(&self) -> u16 {
+        match self {
+            Network::Mainnet => 8333,
+            Network::Testnet4 => 48333,
+            Network::Signet => 38333,
+            Network::Regtest => 18444,
+        }
+    }
+
+    pub fn dns_seeds(&self) -> &'static [&'static str] {
+        match self {
+            Network::Mainnet => &[
+                "seed.bitcoin.sipa.be",
+                "dnsseed.bluematt.me",
+                "seed.bitcoinstats.com",
+            ],
+            Network::Testnet4 => &[
+                "seed.testnet4.bitcoin.sprovoost.nl",
+                "seed.testnet4.wiz.biz",
+            ],
+            Network::Signet => &[
+                "seed.signet.bitcoin.sprovoost.nl",
+                "seed.signet.achow101.com",
+            ],
+            // Regtest has no public network to seed from.
+            Network::Regtest => &[],
+        }
+    }
+
+    // Mainnet keeps the original file name so existing databases are reused.
+    pub fn db_file_name(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "peers.db",
+            Network::Testnet4 => "peers-testnet4.db",
+            Network::Signet => "peers-signet.db",
+            Network::Regtest => "peers-regtest.db",
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Network::Mainnet => "mainnet",
+            Network::Testnet4 => "testnet4",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PolicyMode {
+    Consensus,
+    Standard,
 }
@@ -1,8 +1,18 @@
+use crate::classify::TxCategory;
+use crate::config::Network;
+use crate::p2p::address::{AddrNetwork, NetAddr};
+use crate::report::{MinedCounts, MinedGroup, MinedTx};
+use crate::timeline::Announcement;
 use anyhow::Result;
+use bitcoin::BlockHash;
+use bitcoin::block::Header;
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::p2p::ServiceFlags;
 use chrono::{DateTime, Utc};
 use rusqlite::{Connection, OptionalExtension, params};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::path::PathBuf;
+use std::str::FromStr;
 use std::sync::Mutex;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -31,6 +41,16 @@ impl NodeType {
         }
     }
 
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "knots" => NodeType::Knots,
+            "core" => NodeType::Core,
+            "libre" => NodeType::LibreRelay,
+            "other" => NodeType::Other,
+            _ => NodeType::Unknown,
+        }
+    }
+
     pub fn as_str(&self) -> &'static str {
         match self {
             NodeType::Unknown => "unknown",
@@ -44,7 +64,7 @@ impl NodeType {
 
 #[derive(Debug, Clone)]
 pub struct NodeInfo {
-    pub addr: SocketAddr,
+    pub addr: NetAddr,
     pub node_type: NodeType,
     pub user_agent: Option<String>,
     pub version: Option<i32>,
@@ -53,6 +73,16 @@ pub struct NodeInfo {
     pub last_connected: Option<DateTime<Utc>>,
     pub connection_failures: u32,
     pub is_reachable: bool,
+    // Whether our last connection used BIP324 v2 transport; `None` if never connected.
+    pub v2_transport: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BanInfo {
+    pub ip: IpAddr,
+    pub reason: String,
+    pub banned_at: DateTime<Utc>,
+    pub banned_until: DateTime<Utc>,
 }
 
 pub struct AddressDb {
@@ -60,12 +90,12 @@ pub struct AddressDb {
 }
 
 impl AddressDb {
-    pub fn new(path: Option<PathBuf>) -> Result<Self> {
+    pub fn new(path: Option<PathBuf>, network: Network) -> Result<Self> {
         let path = path.unwrap_or_else(|| {
             dirs::data_dir()
                 .unwrap_or_else(|| PathBuf::from("."))
                 .join("crab-router")
-                .join("peers.db")
+                .join(network.db_file_name())
         });
 
         std::fs::create_dir_all(path.parent().unwrap())?;
@@ -82,11 +112,22 @@ impl AddressDb {
                 last_seen TEXT NOT NULL,
                 last_connected TEXT,
                 connection_failures INTEGER NOT NULL DEFAULT 0,
-                is_reachable INTEGER NOT NULL DEFAULT 1
+                is_reachable INTEGER NOT NULL DEFAULT 1,
+                v2_transport INTEGER,
+                network TEXT NOT NULL DEFAULT 'ipv4'
             )",
             [],
         )?;
 
+        ensure_column(&conn, "nodes", "v2_transport", "INTEGER")?;
+        if ensure_column(&conn, "nodes", "network", "TEXT NOT NULL DEFAULT 'ipv4'")? {
+            // Databases from before addrv2 support only held IP addresses.
+            conn.execute(
+                "UPDATE nodes SET network = 'ipv6' WHERE addr LIKE '[%'",
+                [],
+            )?;
+        }
+
         conn.execute(
             "CREATE INDEX IF NOT EXISTS idx_node_type ON nodes(node_type)",
             [],
@@ -97,6 +138,75 @@ impl AddressDb {
             [],
         )?;
 
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_network ON nodes(network)",
+            [],
+        )?;
+
+        // Header chain above genesis, one consensus-encoded header per height.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS headers (
+                height INTEGER PRIMARY KEY,
+                header BLOB NOT NULL
+            )",
+            [],
+        )?;
+
+        // Every peer that announced a block, in order, once its header validated.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS block_announcements (
+                block_hash TEXT NOT NULL,
+                position INTEGER NOT NULL,
+                peer TEXT NOT NULL,
+                node_type TEXT NOT NULL,
+                announced_at TEXT NOT NULL,
+                delay_ms INTEGER NOT NULL,
+                PRIMARY KEY (block_hash, position)
+            )",
+            [],
+        )?;
+
+        // Blocks whose txs were matched against the relay log, and what we knew of each tx.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS mined_blocks (
+                block_hash TEXT PRIMARY KEY,
+                height INTEGER NOT NULL,
+                recorded_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+
+        // knots_announced is NULL when the relay log no longer tracked the tx.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS mined_txs (
+                block_hash TEXT NOT NULL,
+                txid TEXT NOT NULL,
+                received INTEGER NOT NULL,
+                relayed INTEGER NOT NULL,
+                first_sender TEXT,
+                knots_announced INTEGER,
+                categories TEXT NOT NULL,
+                PRIMARY KEY (block_hash, txid)
+            )",
+            [],
+        )?;
+
+        conn.execute(
+            "CREATE INDEX IF NOT EXISTS idx_mined_height ON mined_blocks(height)",
+            [],
+        )?;
+
+        // Banned IPs; a ban covers every port of the address.
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS bans (
+                ip TEXT PRIMARY KEY,
+                reason TEXT NOT NULL,
+                banned_at TEXT NOT NULL,
+                banned_until TEXT NOT NULL
+            )",
+            [],
+        )?;
+
         Ok(Self {
             conn: Mutex::new(conn),
         })
@@ -114,8 +224,8 @@ impl AddressDb {
             .optional()?
             .is_some();
         conn.execute(
-            "INSERT INTO nodes (addr, node_type, user_agent, version, services, last_seen, last_connected, connection_failures, is_reachable)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            "INSERT INTO nodes (addr, node_type, user_agent, version, services, last_seen, last_connected, connection_failures, is_reachable, v2_transport, network)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)
              ON CONFLICT(addr) DO UPDATE SET
                 node_type = excluded.node_type,
                 user_agent = excluded.user_agent,
@@ -124,7 +234,8 @@ impl AddressDb {
                 last_seen = excluded.last_seen,
                 last_connected = excluded.last_connected,
                 connection_failures = excluded.connection_failures,
-                is_reachable = excluded.is_reachable",
+                is_reachable = excluded.is_reachable,
+                v2_transport = COALESCE(excluded.v2_transport, nodes.v2_transport)",
             params![
                 info.addr.to_string(),
                 info.node_type.as_str(),
@@ -135,50 +246,38 @@ impl AddressDb {
                 info.last_connected.map(|t| t.to_rfc3339()),
                 info.connection_failures,
                 info.is_reachable as i32,
+                info.v2_transport.map(|v2| v2 as i32),
+                info.addr.network().as_str(),
             ],
         )?;
         Ok(!exists)
     }
 
-    pub fn get_by_type(&self, node_type: NodeType, limit: usize) -> Result<Vec<SocketAddr>> {
-        let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT addr FROM nodes WHERE node_type = ?1 AND is_reachable = 1 ORDER BY last_seen DESC LIMIT ?2"
-        )?;
-
-        let addrs: Vec<SocketAddr> = stmt
-            .query_map(params![node_type.as_str(), limit as i64], |row| {
-                let addr_str: String = row.get(0)?;
-                Ok(addr_str.parse::<SocketAddr>().unwrap())
-            })?
-            .filter_map(|r| r.ok())
-            .collect();
-
-        Ok(addrs)
-    }
-
-    pub fn get_random(&self, limit: usize) -> Result<Vec<SocketAddr>> {
+    // `addr` is the stored key, i.e. the `Display` form of a `NetAddr`.
+    pub fn get_node(&self, addr: &str) -> Result<Option<NodeInfo>> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn
-            .prepare("SELECT addr FROM nodes WHERE is_reachable = 1 ORDER BY RANDOM() LIMIT ?1")?;
-
-        let addrs: Vec<SocketAddr> = stmt
-            .query_map(params![limit as i64], |row| {
-                let addr_str: String = row.get(0)?;
-                Ok(addr_str.parse::<SocketAddr>().unwrap())
-            })?
-            .filter_map(|r| r.ok())
-            .collect();
-
-        Ok(addrs)
+        let node = conn
+            .query_row(
+                "SELECT addr, node_type, user_agent, version, services, last_seen, last_connected,
+                        connection_failures, is_reachable, v2_transport, network
+                 FROM nodes WHERE addr = ?1",
+                params![addr],
+                node_from_row,
+            )
+            .optional()?;
+        Ok(node)
     }
 
-    pub fn get_knots_excluding(&self, limit: usize) -> Result<Vec<SocketAddr>> {
+    pub fn get_knots_excluding(
+        &self,
+        limit: usize,
+        network: AddrNetwork,
+    ) -> Result<Vec<SocketAddr>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
             "SELECT addr
              FROM nodes
-             WHERE node_type != 'knots' AND is_reachable = 1
+             WHERE node_type != 'knots' AND is_reachable = 1 AND network = ?2
              ORDER BY
                  CASE node_type
                      WHEN 'libre' THEN 0
@@ -192,7 +291,7 @@ impl AddressDb {
         )?;
 
         let addrs: Vec<SocketAddr> = stmt
-            .query_map(params![limit as i64], |row| {
+            .query_map(params![limit as i64, network.as_str()], |row| {
                 let addr_str: String = row.get(0)?;
                 Ok(addr_str.parse::<SocketAddr>().unwrap())
             })?
@@ -202,6 +301,27 @@ impl AddressDb {
         Ok(addrs)
     }
 
+    // Dial with v2 if the node advertised NODE_P2P_V2 or a previous session used it.
+    pub fn supports_v2_transport(&self, addr: SocketAddr) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn
+            .query_row(
+                "SELECT services, v2_transport FROM nodes WHERE addr = ?1",
+                params![addr.to_string()],
+                |row| Ok((row.get::<_, Option<i64>>(0)?, row.get::<_, Option<i32>>(1)?)),
+            )
+            .optional()?;
+
+        Ok(match row {
+            Some((services, v2_transport)) => {
+                let advertised = services
+                    .is_some_and(|s| (s as u64) & ServiceFlags::P2P_V2.to_u64() != 0);
+                advertised || v2_transport == Some(1)
+            }
+            None => false,
+        })
+    }
+
     pub fn mark_failed(&self, addr: SocketAddr) -> Result<()> {
         let conn = self.conn.lock().unwrap();
         conn.execute(
@@ -222,31 +342,249 @@ impl AddressDb {
         Ok(())
     }
 
-    pub fn count_by_type(&self) -> Result<Vec<(NodeType, i64)>> {
+    pub fn count_by_network(&self) -> Result<Vec<(AddrNetwork, i64)>> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare(
-            "SELECT node_type, COUNT(*) FROM nodes WHERE is_reachable = 1 GROUP BY node_type",
-        )?;
+        let mut stmt = conn.prepare("SELECT network, COUNT(*) FROM nodes GROUP BY network")?;
 
-        let counts: Vec<(NodeType, i64)> = stmt
+        let counts: Vec<(AddrNetwork, i64)> = stmt
             .query_map([], |row| {
-                let type_str: String = row.get(0)?;
+                let network: String = row.get(0)?;
                 let count: i64 = row.get(1)?;
-                let node_type = match type_str.as_str() {
-                    "knots" => NodeType::Knots,
-                    "core" => NodeType::Core,
-                    "libre" => NodeType::LibreRelay,
-                    "other" => NodeType::Other,
-                    _ => NodeType::Unknown,
-                };
-                Ok((node_type, count))
+                Ok((network, count))
             })?
             .filter_map(|r| r.ok())
+            .filter_map(|(network, count)| AddrNetwork::parse(&network).map(|n| (n, count)))
             .collect();
 
         Ok(counts)
     }
 
+    pub fn load_headers(&self) -> Result<Vec<Header>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT header FROM headers ORDER BY height")?;
+        stmt.query_map([], |row| row.get::<_, Vec<u8>>(0))?
+            .map(|bytes| Ok(deserialize(&bytes?)?))
+            .collect()
+    }
+
+    // Replaces every stored header from `from_height` up with `headers`.
+    pub fn store_headers(&self, from_height: u32, headers: &[Header]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM headers WHERE height >= ?1",
+            params![from_height],
+        )?;
+        {
+            let mut stmt = tx.prepare("INSERT INTO headers (height, header) VALUES (?1, ?2)")?;
+            for (height, header) in (from_height..).zip(headers) {
+                stmt.execute(params![height, serialize(header)])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn store_block_announcements(
+        &self,
+        announcements: &[(BlockHash, usize, Announcement)],
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO block_announcements
+                    (block_hash, position, peer, node_type, announced_at, delay_ms)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for (hash, position, announcement) in announcements {
+                stmt.execute(params![
+                    hash.to_string(),
+                    *position as i64,
+                    announcement.peer.to_string(),
+                    announcement.node_type.as_str(),
+                    announcement.at.to_rfc3339(),
+                    announcement.delay.as_millis() as i64,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn store_mined_block(
+        &self,
+        block_hash: BlockHash,
+        height: u32,
+        txs: &[MinedTx],
+    ) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO mined_blocks (block_hash, height, recorded_at)
+             VALUES (?1, ?2, ?3)",
+            params![block_hash.to_string(), height, Utc::now().to_rfc3339()],
+        )?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR REPLACE INTO mined_txs
+                    (block_hash, txid, received, relayed, first_sender, knots_announced,
+                     categories)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            )?;
+            for mined in txs {
+                let categories: Vec<&str> =
+                    mined.categories.iter().map(|category| category.as_str()).collect();
+                stmt.execute(params![
+                    block_hash.to_string(),
+                    mined.txid.to_string(),
+                    mined.received as i32,
+                    mined.relayed as i32,
+                    mined.first_sender.map(|node_type| node_type.as_str()),
+                    mined.knots_announced,
+                    categories.join(","),
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Drops what was recorded for blocks from `height` up, after a reorg replaced them.
+    pub fn delete_mined_blocks_from(&self, height: u32) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "DELETE FROM mined_txs WHERE block_hash IN
+                (SELECT block_hash FROM mined_blocks WHERE height >= ?1)",
+            params![height],
+        )?;
+        tx.execute("DELETE FROM mined_blocks WHERE height >= ?1", params![height])?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Counts for the `limit` highest recorded blocks, highest first, grouped by first sender
+    // and categories so that SQLite does the counting.
+    pub fn load_mined_groups(&self, limit: usize) -> Result<Vec<MinedGroup>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT b.block_hash, b.height, t.first_sender, t.categories, COUNT(t.txid),
+                    COALESCE(SUM(t.received), 0), COALESCE(SUM(t.relayed), 0),
+                    COALESCE(SUM(t.knots_announced = 0), 0),
+                    COALESCE(SUM(t.txid IS NOT NULL AND t.knots_announced IS NULL), 0)
+             FROM (SELECT block_hash, height FROM mined_blocks
+                   ORDER BY height DESC LIMIT ?1) b
+             LEFT JOIN mined_txs t ON t.block_hash = b.block_hash
+             GROUP BY b.block_hash, t.first_sender, t.categories
+             ORDER BY b.height DESC, b.block_hash",
+        )?;
+        stmt.query_map(params![limit as i64], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u32>(1)?,
+                row.get::<_, Option<String>>(2)?,
+                row.get::<_, Option<String>>(3)?,
+                MinedCounts {
+                    txs: row.get(4)?,
+                    received: row.get(5)?,
+                    relayed: row.get(6)?,
+                    never_announced_by_knots: row.get(7)?,
+                    untracked: row.get(8)?,
+                },
+            ))
+        })?
+        .map(|row| {
+            let (block_hash, height, first_sender, categories, counts) = row?;
+            Ok(MinedGroup {
+                block_hash: BlockHash::from_str(&block_hash)?,
+                height,
+                first_sender: first_sender.as_deref().map(NodeType::parse),
+                categories: categories
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split(',')
+                    .filter_map(TxCategory::parse)
+                    .collect(),
+                counts,
+            })
+        })
+        .collect()
+    }
+
+    pub fn ban(&self, ip: IpAddr, reason: &str, until: DateTime<Utc>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT OR REPLACE INTO bans (ip, reason, banned_at, banned_until)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                ip.to_canonical().to_string(),
+                reason,
+                Utc::now().to_rfc3339(),
+                until.to_rfc3339()
+            ],
+        )?;
+        Ok(())
+    }
+
+    // Returns false if the IP was not banned.
+    pub fn unban(&self, ip: IpAddr) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let count = conn.execute(
+            "DELETE FROM bans WHERE ip = ?1",
+            params![ip.to_canonical().to_string()],
+        )?;
+        Ok(count > 0)
+    }
+
+    pub fn is_banned(&self, ip: IpAddr) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let banned = conn
+            .query_row(
+                "SELECT 1 FROM bans WHERE ip = ?1 AND banned_until > ?2",
+                params![ip.to_canonical().to_string(), Utc::now().to_rfc3339()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(banned.is_some())
+    }
+
+    // Bans still in force, most recent first.
+    pub fn list_bans(&self) -> Result<Vec<BanInfo>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT ip, reason, banned_at, banned_until FROM bans
+             WHERE banned_until > ?1 ORDER BY banned_at DESC",
+        )?;
+        stmt.query_map(params![Utc::now().to_rfc3339()], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+            ))
+        })?
+        .map(|row| {
+            let (ip, reason, banned_at, banned_until) = row?;
+            Ok(BanInfo {
+                ip: IpAddr::from_str(&ip)?,
+                reason,
+                banned_at: DateTime::parse_from_rfc3339(&banned_at)?.with_timezone(&Utc),
+                banned_until: DateTime::parse_from_rfc3339(&banned_until)?.with_timezone(&Utc),
+            })
+        })
+        .collect()
+    }
+
+    pub fn prune_expired_bans(&self) -> Result<usize> {
+        let conn = self.conn.lock().unwrap();
+        let count = conn.execute(
+            "DELETE FROM bans WHERE banned_until <= ?1",
+            params![Utc::now().to_rfc3339()],
+        )?;
+        Ok(count)
+    }
+
     pub fn prune_old(&self, before: DateTime<Utc>) -> Result<usize> {
         let conn = self.conn.lock().unwrap();
         let count = conn.execute(
@@ -256,3 +594,59 @@ impl AddressDb {
         Ok(count)
     }
 }
+
+fn node_from_row(row: &rusqlite::Row) -> rusqlite::Result<NodeInfo> {
+    let conversion_error = |idx: usize, message: String| {
+        rusqlite::Error::FromSqlConversionFailure(
+            idx,
+            rusqlite::types::Type::Text,
+            anyhow::anyhow!(message).into(),
+        )
+    };
+    let parse_time = |idx: usize| -> rusqlite::Result<Option<DateTime<Utc>>> {
+        row.get::<_, Option<String>>(idx)?
+            .map(|value| {
+                DateTime::parse_from_rfc3339(&value)
+                    .map(|t| t.with_timezone(&Utc))
+                    .map_err(|e| conversion_error(idx, e.to_string()))
+            })
+            .transpose()
+    };
+
+    let addr: String = row.get(0)?;
+    let network: String = row.get(10)?;
+    let network = AddrNetwork::parse(&network)
+        .ok_or_else(|| conversion_error(10, format!("unknown network {:?}", network)))?;
+    let addr = NetAddr::parse(&addr, network)
+        .ok_or_else(|| conversion_error(0, format!("malformed address {:?}", addr)))?;
+
+    Ok(NodeInfo {
+        addr,
+        node_type: NodeType::parse(&row.get::<_, String>(1)?),
+        user_agent: row.get(2)?,
+        version: row.get(3)?,
+        services: row.get::<_, Option<i64>>(4)?.map(|s| s as u64),
+        last_seen: parse_time(5)?.unwrap_or_default(),
+        last_connected: parse_time(6)?,
+        connection_failures: row.get(7)?,
+        is_reachable: row.get::<_, i32>(8)? != 0,
+        v2_transport: row.get::<_, Option<i32>>(9)?.map(|v2| v2 != 0),
+    })
+}
+
+// Adds a column missing from databases created by older versions; returns true if added.
+fn ensure_column(conn: &Connection, table: &str, column: &str, decl: &str) -> Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|r| r.ok())
+        .any(|name| name == column);
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )?;
+    }
+    Ok(!exists)
+}
@@ -1,6 +1,8 @@
+use crate::config::Network;
 use crate::db::AddressDb;
 use crate::metrics::Metrics;
 use crate::p2p::PeerHandle;
+use crate::p2p::address::NetAddr;
 use crate::p2p::message::{AddressEntry, Message};
 use rand::seq::SliceRandom;
 use std::net::SocketAddr;
@@ -10,17 +12,11 @@ use tokio::sync::RwLock;
 use tokio::time::interval;
 use tracing::{debug, info};
 
-// DNS seeds for mainnet
-const DNS_SEEDS: [&str; 3] = [
-    "seed.bitcoin.sipa.be",
-    "dnsseed.bluematt.me",
-    "seed.bitcoinstats.com",
-];
-
 pub struct DiscoveryService {
     db: Arc<AddressDb>,
     metrics: Arc<RwLock<Metrics>>,
     peers: Arc<RwLock<Vec<PeerHandle>>>,
+    network: Network,
 }
 
 impl DiscoveryService {
@@ -28,8 +24,14 @@ impl DiscoveryService {
         db: Arc<AddressDb>,
         metrics: Arc<RwLock<Metrics>>,
         peers: Arc<RwLock<Vec<PeerHandle>>>,
+        network: Network,
     ) -> Self {
-        Self { db, metrics, peers }
+        Self {
+            db,
+            metrics,
+            peers,
+            network,
+        }
     }
 
     pub async fn run(&self, interval_secs: u64) {
@@ -45,11 +47,18 @@ impl DiscoveryService {
     }
 
     async fn seed_from_dns(&self) {
+        let seeds = self.network.dns_seeds();
+        if seeds.is_empty() {
+            info!("No DNS seeds for {}, skipping DNS seeding", self.network.as_str());
+            return;
+        }
+
         info!("Seeding addresses from DNS seeds...");
         let mut total_new = 0u64;
+        let port = self.network.default_port();
 
-        for seed in &DNS_SEEDS {
-            match tokio::net::lookup_host(format!("{}:8333", seed)).await {
+        for seed in seeds {
+            match tokio::net::lookup_host(format!("{}:{}", seed, port)).await {
                 Ok(addrs) => {
                     let resolved: Vec<SocketAddr> = addrs.collect();
                     let new_nodes = self.store_socket_addrs(resolved.clone(), None).await;
@@ -93,6 +102,16 @@ impl DiscoveryService {
             let _ = peer.send(Message::GetAddr);
         }
 
+        match self.db.count_by_network() {
+            Ok(counts) => {
+                let metrics = self.metrics.write().await;
+                metrics.update_known_nodes(&counts);
+            }
+            Err(e) => {
+                debug!("Failed to count nodes by network: {}", e);
+            }
+        }
+
         // Prune old unreachable nodes
         let cutoff = chrono::Utc::now() - chrono::Duration::days(7);
         match self.db.prune_old(cutoff) {
@@ -107,20 +126,34 @@ impl DiscoveryService {
                 debug!("Failed to prune old nodes: {}", e);
             }
         }
+
+        match self.db.prune_expired_bans() {
+            Ok(pruned) if pruned > 0 => info!("Pruned {} expired bans", pruned),
+            Ok(_) => {}
+            Err(e) => debug!("Failed to prune expired bans: {}", e),
+        }
     }
 
     pub async fn handle_new_addresses(&self, addrs: Vec<AddressEntry>) {
         let mut new_count = 0u64;
 
         for entry in addrs {
-            // Skip non-public addresses
-            if !is_public_addr(entry.addr) {
-                continue;
-            }
+            // Overlay addresses are kept for the census even though we may not reach them.
+            let addr = match entry.addr {
+                NetAddr::Ip(addr) => {
+                    let addr = canonical_addr(addr);
+                    // Skip non-public addresses
+                    if !is_public_addr(addr) {
+                        continue;
+                    }
+                    NetAddr::Ip(addr)
+                }
+                overlay => overlay,
+            };
 
             // Try to add to database
             let info = crate::db::NodeInfo {
-                addr: entry.addr,
+                addr,
                 node_type: crate::db::NodeType::Unknown,
                 user_agent: None,
                 version: None,
@@ -129,6 +162,7 @@ impl DiscoveryService {
                 last_connected: None,
                 connection_failures: 0,
                 is_reachable: true,
+                v2_transport: None,
             };
 
             match self.db.insert_or_update(&info) {
@@ -153,12 +187,13 @@ impl DiscoveryService {
         let mut new_count = 0u64;
 
         for addr in addrs {
+            let addr = canonical_addr(addr);
             if !is_public_addr(addr) {
                 continue;
             }
 
             let info = crate::db::NodeInfo {
-                addr,
+                addr: NetAddr::Ip(addr),
                 node_type: crate::db::NodeType::Unknown,
                 user_agent: None,
                 version: None,
@@ -167,6 +202,7 @@ impl DiscoveryService {
                 last_connected: None,
                 connection_failures: 0,
                 is_reachable: true,
+                v2_transport: None,
             };
 
             match self.db.insert_or_update(&info) {
@@ -195,6 +231,19 @@ fn is_public_addr(addr: SocketAddr) -> bool {
                 && !ip.is_broadcast()
                 && !ip.is_documentation()
         }
-        std::net::IpAddr::V6(ip) => !ip.is_loopback() && !ip.is_multicast() && !ip.is_unspecified(),
+        std::net::IpAddr::V6(ip) => {
+            !ip.is_loopback()
+                && !ip.is_multicast()
+                && !ip.is_unspecified()
+                && !ip.is_unique_local()
+                && !ip.is_unicast_link_local()
+                // 2001:db8::/32 documentation range
+                && (ip.segments()[0] != 0x2001 || ip.segments()[1] != 0x0db8)
+        }
     }
 }
+
+// IPv4-mapped IPv6 addresses are stored as plain IPv4 so each node has one key.
+fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
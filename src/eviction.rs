@@ -0,0 +1,103 @@
+use crate::p2p::PeerHandle;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+// How many inbound peers each protection spares, as in Bitcoin Core's SelectNodeToEvict.
+const PROTECT_BY_NETGROUP: usize = 4;
+const PROTECT_BY_PING: usize = 8;
+const PROTECT_BY_TX: usize = 4;
+const PROTECT_BY_BLOCK: usize = 4;
+
+pub struct EvictionCandidate {
+    pub addr: SocketAddr,
+    pub connected_at: DateTime<Utc>,
+    pub min_ping: Option<Duration>,
+    pub last_tx: i64,
+    pub last_block: i64,
+}
+
+impl From<&PeerHandle> for EvictionCandidate {
+    fn from(peer: &PeerHandle) -> Self {
+        Self {
+            addr: peer.addr(),
+            connected_at: peer.connected_at(),
+            min_ping: peer.min_ping(),
+            last_tx: peer.last_tx(),
+            last_block: peer.last_block(),
+        }
+    }
+}
+
+// Picks the inbound peer to drop for a newcomer, or `None` if every one is protected.
+// Peers that are hard for an attacker to imitate are spared: a few from netgroups chosen by
+// `key`, the fastest, those that recently sent us new txs or blocks, and the older half.
+// The youngest peer of the netgroup with the most connections goes.
+pub fn select_peer_to_evict(
+    mut candidates: Vec<EvictionCandidate>,
+    key: &impl BuildHasher,
+) -> Option<SocketAddr> {
+    let keyed_netgroup = |candidate: &EvictionCandidate| key.hash_one(netgroup(candidate.addr));
+
+    protect(&mut candidates, PROTECT_BY_NETGROUP, |a, b| {
+        keyed_netgroup(a).cmp(&keyed_netgroup(b))
+    });
+    // Unmeasured pings sort as slowest.
+    protect(&mut candidates, PROTECT_BY_PING, |a, b| {
+        b.min_ping
+            .unwrap_or(Duration::MAX)
+            .cmp(&a.min_ping.unwrap_or(Duration::MAX))
+    });
+    protect(&mut candidates, PROTECT_BY_TX, |a, b| a.last_tx.cmp(&b.last_tx));
+    protect(&mut candidates, PROTECT_BY_BLOCK, |a, b| {
+        a.last_block.cmp(&b.last_block)
+    });
+    let older_half = candidates.len() / 2;
+    protect(&mut candidates, older_half, |a, b| {
+        b.connected_at.cmp(&a.connected_at)
+    });
+
+    let mut groups: HashMap<Vec<u8>, Vec<EvictionCandidate>> = HashMap::new();
+    for candidate in candidates {
+        groups
+            .entry(netgroup(candidate.addr))
+            .or_default()
+            .push(candidate);
+    }
+    // Ties go to the group holding the most recent connection.
+    let youngest = |group: &Vec<EvictionCandidate>| group.iter().map(|c| c.connected_at).max();
+    let group = groups
+        .into_values()
+        .max_by(|a, b| a.len().cmp(&b.len()).then(youngest(a).cmp(&youngest(b))))?;
+    group
+        .into_iter()
+        .max_by_key(|candidate| candidate.connected_at)
+        .map(|candidate| candidate.addr)
+}
+
+// Sorts so the `count` best by `order` come last, then removes them from the candidates.
+fn protect(
+    candidates: &mut Vec<EvictionCandidate>,
+    count: usize,
+    order: impl FnMut(&EvictionCandidate, &EvictionCandidate) -> std::cmp::Ordering,
+) {
+    candidates.sort_by(order);
+    let keep = candidates.len().saturating_sub(count);
+    candidates.truncate(keep);
+}
+
+// Address block a single operator can easily fill: /16 for IPv4, /32 for IPv6.
+fn netgroup(addr: SocketAddr) -> Vec<u8> {
+    match addr.ip().to_canonical() {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            vec![4, octets[0], octets[1]]
+        }
+        IpAddr::V6(ip) => {
+            let octets = ip.octets();
+            vec![6, octets[0], octets[1], octets[2], octets[3]]
+        }
+    }
+}
@@ -0,0 +1,497 @@
+use crate::config::Network;
+use bitcoin::block::Header;
+use bitcoin::constants::genesis_block;
+use bitcoin::{BlockHash, CompactTarget, Work};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Most headers a `headers` message may carry (MAX_HEADERS_RESULTS in Bitcoin Core).
+pub const MAX_HEADERS_RESULTS: usize = 2000;
+// How long a peer gets to answer getheaders before we may ask again (or pick another).
+pub const HEADERS_RESPONSE_TIME: Duration = Duration::from_secs(2 * 60);
+// Until the tip is this recent, headers are fetched from one peer at a time.
+const RECENT_TIP_AGE: u64 = 24 * 60 * 60;
+// Headers more than this far ahead of our clock are not accepted yet (MAX_FUTURE_BLOCK_TIME).
+const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+const MEDIAN_TIME_SPAN: usize = 11;
+// Blocks of work below our tip a branch must reach to be kept, once we have a real chain.
+const ANTI_DOS_BLOCKS: u32 = 144;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderError {
+    // The first header's parent is not in our chain; the peer should send what is in between.
+    Unconnected,
+    // Too far in the future for now; not the sender's fault.
+    TimeTooNew,
+    NonContinuous,
+    BadDifficulty,
+    BadPow,
+    TimeTooOld,
+    // Too little work to be worth keeping; honest peers send this on stale forks too.
+    LowWork,
+}
+
+impl HeaderError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            HeaderError::Unconnected => "unconnected",
+            HeaderError::TimeTooNew => "time_too_new",
+            HeaderError::NonContinuous => "non_continuous",
+            HeaderError::BadDifficulty => "bad_difficulty",
+            HeaderError::BadPow => "bad_pow",
+            HeaderError::TimeTooOld => "time_too_old",
+            HeaderError::LowWork => "low_work",
+        }
+    }
+
+    // Headers no honest node would send.
+    pub fn is_invalid(self) -> bool {
+        !matches!(
+            self,
+            HeaderError::Unconnected | HeaderError::TimeTooNew | HeaderError::LowWork
+        )
+    }
+}
+
+// What `HeaderChain::connect` changed: every header from `from_height` up to the tip is new.
+pub struct Connected {
+    pub from_height: u32,
+    pub added: usize,
+    // Headers of the previous best chain that were replaced.
+    pub reorged: usize,
+}
+
+// A validated header and where it sits in the header tree.
+struct IndexEntry {
+    header: Header,
+    height: u32,
+    // Total work of the chain ending in this header.
+    chain_work: Work,
+}
+
+// Every header we validated (PoW, difficulty and timestamps only), side branches included,
+// and the most-work chain through them, as Bitcoin Core's block index and active chain.
+pub struct HeaderChain {
+    network: Network,
+    index: HashMap<BlockHash, IndexEntry>,
+    // The most-work chain by height; the genesis hash is always first.
+    active: Vec<BlockHash>,
+    // Heights 1 up to this of the active chain are in the database.
+    stored_height: u32,
+}
+
+impl HeaderChain {
+    pub fn new(network: Network) -> Self {
+        let genesis = genesis_block(network.params()).header;
+        let hash = genesis.block_hash();
+        let entry = IndexEntry {
+            header: genesis,
+            height: 0,
+            chain_work: genesis.work(),
+        };
+        Self {
+            network,
+            index: HashMap::from([(hash, entry)]),
+            active: vec![hash],
+            stored_height: 0,
+        }
+    }
+
+    // Rebuilds the chain from stored headers (height 1 onwards), keeping the prefix that
+    // still links up to our genesis.
+    pub fn from_stored(network: Network, stored: Vec<Header>) -> Self {
+        let mut chain = Self::new(network);
+        for header in stored {
+            if header.prev_blockhash != chain.tip_hash() {
+                break;
+            }
+            let hash = chain.insert(header);
+            chain.active.push(hash);
+        }
+        chain.stored_height = chain.height();
+        chain
+    }
+
+    pub fn height(&self) -> u32 {
+        (self.active.len() - 1) as u32
+    }
+
+    pub fn tip(&self) -> &Header {
+        &self.tip_entry().header
+    }
+
+    pub fn tip_hash(&self) -> BlockHash {
+        self.active[self.active.len() - 1]
+    }
+
+    // Seconds since the tip was mined, by its header timestamp.
+    pub fn tip_age(&self, now: u64) -> u64 {
+        now.saturating_sub(u64::from(self.tip().time))
+    }
+
+    pub fn is_recent(&self, now: u64) -> bool {
+        self.tip_age(now) < RECENT_TIP_AGE
+    }
+
+    // Whether `hash` is on the most-work chain.
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.height_of(hash).is_some()
+    }
+
+    pub fn height_of(&self, hash: &BlockHash) -> Option<u32> {
+        let height = self.index.get(hash)?.height;
+        (self.active.get(height as usize) == Some(hash)).then_some(height)
+    }
+
+    pub fn headers_from(&self, height: u32) -> Vec<Header> {
+        self.active[(height as usize).min(self.active.len())..]
+            .iter()
+            .map(|hash| self.index[hash].header)
+            .collect()
+    }
+
+    // Headers of the active chain not written out yet, with the height of the first. Nothing
+    // is written before the chain has the network's minimum work, so a cheap fake chain
+    // never ends up on disk.
+    pub fn take_unstored(&mut self) -> Option<(u32, Vec<Header>)> {
+        if self.stored_height == self.height()
+            || self.tip_entry().chain_work < minimum_chain_work(self.network)
+        {
+            return None;
+        }
+        let from_height = self.stored_height + 1;
+        self.stored_height = self.height();
+        Some((from_height, self.headers_from(from_height)))
+    }
+
+    pub fn locator(&self) -> Vec<BlockHash> {
+        self.locator_from(self.tip_hash())
+    }
+
+    // Block locator as in Bitcoin Core, starting at any header we have: the last ten hashes,
+    // then exponentially sparser back to genesis.
+    pub fn locator_from(&self, hash: BlockHash) -> Vec<BlockHash> {
+        let Some(entry) = self.index.get(&hash) else {
+            return self.locator();
+        };
+        let mut locator = Vec::new();
+        let (mut hash, mut height) = (hash, entry.height);
+        let mut step = 1;
+        loop {
+            locator.push(hash);
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+            hash = self.ancestor(hash, height);
+        }
+        locator
+    }
+
+    // Answer to a getheaders: what follows the first locator hash on our chain (or genesis),
+    // up to `stop` or MAX_HEADERS_RESULTS headers.
+    pub fn headers_after(&self, locator: &[BlockHash], stop: &BlockHash) -> Vec<Header> {
+        let start = locator
+            .iter()
+            .find_map(|hash| self.height_of(hash))
+            .map_or(1, |height| height as usize + 1);
+
+        let mut headers = Vec::new();
+        for hash in self.active.iter().skip(start).take(MAX_HEADERS_RESULTS) {
+            headers.push(self.index[hash].header);
+            if hash == stop {
+                break;
+            }
+        }
+        headers
+    }
+
+    // Validates `headers` (consecutive, oldest first) into the index, then switches to the
+    // branch they end on if it now has the most work. Headers before an invalid one are kept.
+    // `now` is the unix time.
+    pub fn connect(
+        &mut self,
+        headers: &[Header],
+        now: u32,
+    ) -> Result<Option<Connected>, HeaderError> {
+        let Some(first) = headers.first() else {
+            return Ok(None);
+        };
+        if headers
+            .windows(2)
+            .any(|pair| pair[1].prev_blockhash != pair[0].block_hash())
+        {
+            return Err(HeaderError::NonContinuous);
+        }
+        let Some(parent) = self.index.get(&first.prev_blockhash) else {
+            return Err(HeaderError::Unconnected);
+        };
+        let branch_work = headers
+            .iter()
+            .fold(parent.chain_work, |work, header| work + header.work());
+        if branch_work < self.anti_dos_threshold() {
+            return Err(HeaderError::LowWork);
+        }
+
+        let mut result = Ok(());
+        let mut last = None;
+        for header in headers {
+            let hash = header.block_hash();
+            // Peers often resend headers we already have.
+            if !self.index.contains_key(&hash) {
+                if let Err(e) = self.check(header, now) {
+                    result = Err(e);
+                    break;
+                }
+                self.insert(*header);
+            }
+            last = Some(hash);
+        }
+        let connected = last.and_then(|hash| self.activate(hash));
+        result.map(|()| connected)
+    }
+
+    // Below this much work a branch is not worth keeping. Like Bitcoin Core's anti-DoS
+    // threshold: a day of blocks short of our tip, but never below the minimum chain work.
+    // Until our own chain has that much, everything is taken (Core would presync instead).
+    fn anti_dos_threshold(&self) -> Work {
+        let minimum = minimum_chain_work(self.network);
+        let tip = self.tip_entry();
+        if tip.chain_work < minimum {
+            return Work::from_be_bytes([0; 32]);
+        }
+        let buffer = (1..ANTI_DOS_BLOCKS).fold(tip.header.work(), |work, _| {
+            work + tip.header.work()
+        });
+        if tip.chain_work > buffer {
+            minimum.max(tip.chain_work - buffer)
+        } else {
+            minimum
+        }
+    }
+
+    // Makes `candidate` the tip if its chain has more work than ours; ties keep the first seen.
+    fn activate(&mut self, candidate: BlockHash) -> Option<Connected> {
+        if self.index[&candidate].chain_work <= self.tip_entry().chain_work {
+            return None;
+        }
+        let mut branch = Vec::new();
+        let mut hash = candidate;
+        while !self.contains(&hash) {
+            branch.push(hash);
+            hash = self.index[&hash].header.prev_blockhash;
+        }
+        let fork = self.index[&hash].height;
+        let reorged = self.height() - fork;
+        self.active.truncate(fork as usize + 1);
+        self.active.extend(branch.iter().rev());
+        self.stored_height = self.stored_height.min(fork);
+        Some(Connected {
+            from_height: fork + 1,
+            added: branch.len(),
+            reorged: reorged as usize,
+        })
+    }
+
+    // Checks `header` in the context of its parent, which must be in the index.
+    fn check(&self, header: &Header, now: u32) -> Result<(), HeaderError> {
+        let parent = &self.index[&header.prev_blockhash];
+        if header.bits != self.next_bits(header, parent) {
+            return Err(HeaderError::BadDifficulty);
+        }
+        header
+            .validate_pow(header.target())
+            .map_err(|_| HeaderError::BadPow)?;
+        if header.time <= self.median_time_past(header.prev_blockhash) {
+            return Err(HeaderError::TimeTooOld);
+        }
+        if header.time > now.saturating_add(MAX_FUTURE_BLOCK_TIME) {
+            return Err(HeaderError::TimeTooNew);
+        }
+        Ok(())
+    }
+
+    // Bitcoin Core's GetNextWorkRequired for a header on top of `parent`.
+    fn next_bits(&self, header: &Header, parent: &IndexEntry) -> CompactTarget {
+        let params = self.network.params();
+        let height = u64::from(parent.height) + 1;
+        let prev = &parent.header;
+        if params.no_pow_retargeting {
+            return prev.bits;
+        }
+
+        let interval = params.difficulty_adjustment_interval();
+        if !height.is_multiple_of(interval) {
+            if !params.allow_min_difficulty_blocks {
+                return prev.bits;
+            }
+            // Testnet: a block 20 minutes after its parent may be mined at minimum difficulty;
+            // otherwise the difficulty of the last regular block applies.
+            let pow_limit = params.max_attainable_target.to_compact_lossy();
+            if u64::from(header.time) > u64::from(prev.time) + 2 * params.pow_target_spacing {
+                return pow_limit;
+            }
+            return self
+                .ancestors(header.prev_blockhash)
+                .find(|entry| {
+                    u64::from(entry.height).is_multiple_of(interval)
+                        || entry.header.bits != pow_limit
+                })
+                .map_or(prev.bits, |entry| entry.header.bits);
+        }
+
+        let Some(first) = self
+            .ancestors(header.prev_blockhash)
+            .nth(interval as usize - 1)
+        else {
+            return prev.bits;
+        };
+        let timespan = u64::from(prev.time.saturating_sub(first.header.time));
+        // BIP94 (testnet4) retargets from the period's first block to close the time warp.
+        let last = if self.network == Network::Testnet4 {
+            first.header.bits
+        } else {
+            prev.bits
+        };
+        CompactTarget::from_next_work_required(last, timespan, params)
+    }
+
+    fn median_time_past(&self, hash: BlockHash) -> u32 {
+        let mut times: Vec<u32> = self
+            .ancestors(hash)
+            .take(MEDIAN_TIME_SPAN)
+            .map(|entry| entry.header.time)
+            .collect();
+        times.sort_unstable();
+        times[times.len() / 2]
+    }
+
+    fn tip_entry(&self) -> &IndexEntry {
+        &self.index[&self.tip_hash()]
+    }
+
+    // `hash` and its parents, back to genesis.
+    fn ancestors(&self, hash: BlockHash) -> impl Iterator<Item = &IndexEntry> {
+        std::iter::successors(self.index.get(&hash), |entry| {
+            self.index.get(&entry.header.prev_blockhash)
+        })
+    }
+
+    // The ancestor of `hash` at `height`, which must not be above it.
+    fn ancestor(&self, mut hash: BlockHash, height: u32) -> BlockHash {
+        loop {
+            if self.contains(&hash) {
+                return self.active[height as usize];
+            }
+            let entry = &self.index[&hash];
+            if entry.height == height {
+                return hash;
+            }
+            hash = entry.header.prev_blockhash;
+        }
+    }
+
+    // Adds a header whose parent is already in the index.
+    fn insert(&mut self, header: Header) -> BlockHash {
+        let parent = &self.index[&header.prev_blockhash];
+        let entry = IndexEntry {
+            header,
+            height: parent.height + 1,
+            chain_work: parent.chain_work + header.work(),
+        };
+        let hash = header.block_hash();
+        self.index.insert(hash, entry);
+        hash
+    }
+}
+
+// Work the real chain is known to have exceeded: far below Bitcoin Core's nMinimumChainWork,
+// so an outdated value never holds back honest headers, but out of reach of a cheap fake
+// chain on mainnet.
+fn minimum_chain_work(network: Network) -> Work {
+    let log2 = match network {
+        Network::Mainnet => 88,
+        Network::Testnet4 => 40,
+        Network::Signet => 36,
+        Network::Regtest => return Work::from_be_bytes([0; 32]),
+    };
+    let mut bytes = [0; 32];
+    bytes[31 - log2 / 8] = 1 << (log2 % 8);
+    Work::from_be_bytes(bytes)
+}
+
+// Which peers we ask for headers, modelled on Bitcoin Core: one peer at a time while the
+// tip is old, every peer once it is recent, and at most one getheaders in flight per peer.
+#[derive(Default)]
+pub struct HeaderSync {
+    // Peers we started syncing from; each is only picked once.
+    started: HashSet<SocketAddr>,
+    sync_peer: Option<SocketAddr>,
+    in_flight: HashMap<SocketAddr, Instant>,
+}
+
+impl HeaderSync {
+    // Peers from `peers` to send an initial getheaders to.
+    pub fn peers_to_start(
+        &mut self,
+        peers: &[SocketAddr],
+        tip_recent: bool,
+        now: Instant,
+    ) -> Vec<SocketAddr> {
+        // A sync peer that never answered is given up on.
+        if let Some(peer) = self.sync_peer
+            && !self.awaiting(peer, now)
+        {
+            self.sync_peer = None;
+        }
+
+        let mut candidates = peers.iter().filter(|peer| !self.started.contains(peer));
+        let chosen: Vec<SocketAddr> = if tip_recent {
+            candidates.copied().collect()
+        } else if self.sync_peer.is_none() {
+            candidates.next().copied().into_iter().collect()
+        } else {
+            Vec::new()
+        };
+        if !tip_recent {
+            self.sync_peer = chosen.first().copied().or(self.sync_peer);
+        }
+        self.started.extend(&chosen);
+        chosen
+    }
+
+    // Whether a getheaders may go to `peer` now; records it as sent if so.
+    pub fn request(&mut self, peer: SocketAddr, now: Instant) -> bool {
+        if self.awaiting(peer, now) {
+            return false;
+        }
+        self.in_flight.insert(peer, now);
+        true
+    }
+
+    // A headers message arrived from `peer`; `more` if it was full and the peer has more.
+    pub fn received(&mut self, peer: SocketAddr, more: bool) {
+        self.in_flight.remove(&peer);
+        if !more && self.sync_peer == Some(peer) {
+            self.sync_peer = None;
+        }
+    }
+
+    pub fn disconnected(&mut self, peer: SocketAddr) {
+        self.started.remove(&peer);
+        self.in_flight.remove(&peer);
+        if self.sync_peer == Some(peer) {
+            self.sync_peer = None;
+        }
+    }
+
+    fn awaiting(&self, peer: SocketAddr, now: Instant) -> bool {
+        self.in_flight
+            .get(&peer)
+            .is_some_and(|sent| now.duration_since(*sent) < HEADERS_RESPONSE_TIME)
+    }
+}
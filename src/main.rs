@@ -1,9 +1,22 @@
+mod api;
+mod ban;
+mod classify;
+mod compact;
 mod config;
 mod db;
 mod discovery;
+mod eviction;
+mod headers;
 mod manager;
 mod metrics;
+mod orphan;
 mod p2p;
+mod prevout;
+mod report;
+mod timeline;
+mod trickle;
+mod txrequest;
+mod validation;
 
 use anyhow::Result;
 use clap::Parser;
@@ -28,23 +41,21 @@ async fn main() -> Result<()> {
     let config = config::Config::parse();
 
     info!("Starting Crab Router v1.0.0");
-    info!("Target peers: {}", config.target_peers);
+    info!("Network: {}", config.network.as_str());
+    info!(
+        "Target peers: {} outbound, up to {} inbound",
+        config.target_peers, config.max_inbound_peers
+    );
     info!("Metrics endpoint: http://{}/metrics", config.metrics_addr);
 
     // Initialize database
-    let db = Arc::new(db::AddressDb::new(None)?);
+    let db = Arc::new(db::AddressDb::new(None, config.network)?);
 
     // Initialize metrics
     let metrics = Arc::new(RwLock::new(metrics::Metrics::new()));
 
-    // Start metrics server
-    let metrics_clone = metrics.clone();
-    tokio::spawn(async move {
-        metrics::serve_metrics(config.metrics_addr, metrics_clone).await;
-    });
-
     // Address advertised in version handshake and used for inbound bind port.
-    let our_addr: SocketAddr = format!("0.0.0.0:{}", config.listen_port).parse()?;
+    let our_addr: SocketAddr = format!("0.0.0.0:{}", config.listen_port()).parse()?;
 
     // Start peer manager
     let mut manager = manager::PeerManager::new(
@@ -52,10 +63,51 @@ async fn main() -> Result<()> {
         metrics.clone(),
         config.target_peers,
         our_addr,
+        config.network,
         config.user_agent.clone(),
         config.peer_timeout_secs,
     );
 
+    manager.set_ipv6_share(config.ipv6_peer_share);
+    manager.set_max_inbound(config.max_inbound_peers);
+    manager.set_serve_mempool(config.serve_mempool);
+    manager.set_policy_mode(config.policy_mode);
+
+    // Resume header sync from the chain stored by previous runs
+    let header_chain = headers::HeaderChain::from_stored(config.network, db.load_headers()?);
+    info!("Header chain at height {}", header_chain.height());
+    manager.set_header_chain(header_chain);
+
+    if let Some(proxy_addr) = config.proxy {
+        info!("Routing outbound connections through SOCKS5 proxy {}", proxy_addr);
+        manager.set_dialer(p2p::dialer::Dialer::new(Some(p2p::dialer::ProxyConfig {
+            addr: proxy_addr,
+            randomize_credentials: config.proxy_randomize,
+        })));
+    }
+
+    if let Some(rest_addr) = config.prevout_rest_addr {
+        info!("Looking up prevouts through Bitcoin Core REST at {}", rest_addr);
+        manager.set_prevout_source(prevout::RestPrevoutSource::new(rest_addr));
+    }
+
+    for node in &config.add_nodes {
+        let target = p2p::dialer::DialTarget::parse(node, config.network.default_port())?;
+        info!("Adding manual peer {}", target);
+        manager.add_manual_peer(target).await;
+    }
+
+    // Start metrics server
+    let metrics_clone = metrics.clone();
+    let report_db = db.clone();
+    let admin = config.enable_admin_api.then(|| manager.handle());
+    if admin.is_some() {
+        info!("Admin API: http://{}/api/peers", config.metrics_addr);
+    }
+    tokio::spawn(async move {
+        metrics::serve_metrics(config.metrics_addr, metrics_clone, report_db, admin).await;
+    });
+
     let peers = manager.peers();
 
     if config.enable_discovery {
@@ -64,6 +116,7 @@ async fn main() -> Result<()> {
             db.clone(),
             metrics.clone(),
             peers.clone(),
+            config.network,
         ));
         manager.set_discovery_service(discovery.clone());
 
@@ -74,8 +127,31 @@ async fn main() -> Result<()> {
         info!("Discovery disabled by configuration");
     }
 
-    // Run peer manager
-    manager.run().await;
+    // Run peer manager until we are asked to stop
+    manager.run(shutdown_signal()).await;
 
+    info!("Crab Router stopped");
     Ok(())
 }
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
@@ -1,73 +1,183 @@
+use crate::ban::{self, BAN_DURATION, Misbehavior, MisbehaviorScores};
+use crate::classify;
+use crate::compact::{
+    COMPACT_BLOCK_VERSION, CompactBlockError, CompactBlockState, Reconstruction,
+};
+use crate::config::{Network, PolicyMode};
 use crate::db::{AddressDb, NodeType};
 use crate::discovery::DiscoveryService;
+use crate::eviction::{self, EvictionCandidate};
+use crate::headers::{Connected, HeaderChain, HeaderError, HeaderSync, MAX_HEADERS_RESULTS};
 use crate::metrics::Metrics;
-use crate::p2p::message::{AddressEntry, Inventory, Message};
+use crate::orphan::{Orphan, OrphanDrop, OrphanPool};
+use crate::p2p::address::{AddrNetwork, NetAddr};
+use crate::p2p::message::{
+    ADVERTISED_PROTOCOL_VERSION, AddressEntry, GetHeadersMessage, Inventory, MAX_PACKAGE_COUNT,
+    MSG_ANCPKGINFO, Message, SendCmpct,
+};
+use crate::p2p::dialer::{self, DialTarget, Dialer};
 use crate::p2p::{AddressMessageKind, Peer, PeerEvent, PeerHandle};
+use crate::prevout::{Lookup, PrevoutLookups, RestPrevoutSource};
+use crate::report::MinedTx;
+use crate::timeline::{BlockTimeline, TxRecord, TxTimeline};
+use crate::trickle::{QueuedInv, TrickleState};
+use crate::txrequest::{INBOUND_PEER_TX_DELAY, TxKey, TxRequestTracker};
+use crate::validation::{self, Rejection};
 use bitcoin::p2p::ServiceFlags;
 use bitcoin::hashes::Hash;
-use bitcoin::{Transaction, Txid, Wtxid};
+use bitcoin::script::Instruction;
+use bitcoin::bip152::{BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds};
+use bitcoin::block::Header;
+use bitcoin::{Amount, Block, BlockHash, OutPoint, ScriptBuf, Transaction, Txid, Wtxid};
 use chrono::Utc;
 use rand::seq::SliceRandom;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
 use std::collections::VecDeque;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch};
 use tokio::time::timeout;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
 const SEEN_TX_CACHE_LIMIT: usize = 100_000;
 const RECENT_TX_CACHE_LIMIT: usize = 20_000;
-const REQUESTED_TXID_TTL: Duration = Duration::from_secs(120);
+// Invalid txs remembered so that no announcer is asked for them again.
+const RECENT_REJECTS_LIMIT: usize = 20_000;
 const OUTBOUND_REFILL_INTERVAL: Duration = Duration::from_secs(3);
 const MAX_CONNECT_ATTEMPTS_PER_TICK: usize = 192;
 const GETADDR_RESPONSE_LIMIT: usize = 50;
+const DEFAULT_IPV6_PEER_SHARE: f64 = 0.25;
+const DEFAULT_MAX_INBOUND_PEERS: usize = 256;
+// Granularity of the inventory trickle timers.
+const TRICKLE_TICK: Duration = Duration::from_millis(100);
+// How often due and timed-out tx requests are checked.
+const TX_REQUEST_TICK: Duration = Duration::from_millis(250);
+// How often new peers are considered for header sync and the tip age metric is refreshed.
+const HEADER_SYNC_TICK: Duration = Duration::from_secs(5);
+// Most new headers at once for which the tip is still fetched as a compact block.
+const MAX_BLOCKS_TO_FETCH: usize = 8;
+// Most headers a peer announces unprompted (MAX_BLOCKS_TO_ANNOUNCE in Bitcoin Core).
+const MAX_BLOCKS_TO_ANNOUNCE: usize = 8;
+// Head start a BIP331 package request gets before an orphan's parents are fetched one by one.
+const PACKAGE_REQUEST_GRACE: Duration = Duration::from_secs(5);
+// How long shutdown waits for peer tasks to report their disconnects.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+// Most entries in one addr or addrv2 message (MAX_ADDR_TO_SEND in Bitcoin Core).
+const MAX_ADDR_TO_SEND: usize = 1000;
+
+// A received tx waiting for its prevout lookup.
+struct PendingTx {
+    from: SocketAddr,
+    tx: Transaction,
+    txid: Txid,
+    wtxid: Wtxid,
+}
 
 #[derive(Default)]
 struct RelayState {
-    seen_txids: HashSet<[u8; 32]>,
-    seen_order: VecDeque<[u8; 32]>,
-    requested_txids: HashMap<[u8; 32], Instant>,
+    seen_txids: HashSet<Txid>,
+    seen_wtxids: HashSet<Wtxid>,
+    seen_order: VecDeque<(Txid, Wtxid)>,
+    requests: TxRequestTracker,
     tx_cache: HashMap<Txid, Transaction>,
     tx_by_wtxid: HashMap<Wtxid, Txid>,
     tx_cache_order: VecDeque<Txid>,
+    // Ancestor generations within the tx cache, used to announce parents first.
+    tx_depth: HashMap<Txid, u32>,
+    // BIP331 ancestor packages (wtxids, child last) of cached txs with cached parents.
+    packages: HashMap<Wtxid, Vec<Wtxid>>,
+    // sat/kvB of cached txs whose input values were known.
+    feerates: HashMap<Txid, u64>,
+    // Txids and wtxids of txs that failed the sanity checks.
+    recent_rejects: HashSet<[u8; 32]>,
+    recent_rejects_order: VecDeque<[u8; 32]>,
 }
 
 impl RelayState {
-    fn mark_requested(&mut self, key: [u8; 32], now: Instant) -> bool {
-        self.cleanup_requested(now);
-        if self.seen_txids.contains(&key) || self.requested_txids.contains_key(&key) {
-            return false;
+    fn is_seen(&self, key: TxKey) -> bool {
+        let seen = match key {
+            TxKey::Txid(txid) => self.seen_txids.contains(&txid),
+            TxKey::Wtxid(wtxid) => self.seen_wtxids.contains(&wtxid),
+        };
+        seen || self.recent_rejects.contains(&key.to_byte_array())
+    }
+
+    fn reject(&mut self, key: TxKey) {
+        let key = key.to_byte_array();
+        if !self.recent_rejects.insert(key) {
+            return;
+        }
+        self.recent_rejects_order.push_back(key);
+        while self.recent_rejects_order.len() > RECENT_REJECTS_LIMIT {
+            if let Some(oldest) = self.recent_rejects_order.pop_front() {
+                self.recent_rejects.remove(&oldest);
+            }
         }
-        self.requested_txids.insert(key, now);
-        true
     }
 
-    fn mark_seen(&mut self, key: [u8; 32]) -> bool {
-        if self.seen_txids.contains(&key) {
-            return false;
+    // Returns whether the txid and the wtxid were each new.
+    fn mark_seen(&mut self, txid: Txid, wtxid: Wtxid) -> (bool, bool) {
+        let new_txid = self.seen_txids.insert(txid);
+        let new_wtxid = self.seen_wtxids.insert(wtxid);
+        if !new_txid && !new_wtxid {
+            return (false, false);
         }
 
-        self.seen_txids.insert(key);
-        self.seen_order.push_back(key);
+        self.seen_order.push_back((txid, wtxid));
         while self.seen_order.len() > SEEN_TX_CACHE_LIMIT {
-            if let Some(oldest) = self.seen_order.pop_front() {
-                self.seen_txids.remove(&oldest);
+            if let Some((oldest_txid, oldest_wtxid)) = self.seen_order.pop_front() {
+                self.seen_txids.remove(&oldest_txid);
+                self.seen_wtxids.remove(&oldest_wtxid);
             }
         }
-        true
+        (new_txid, new_wtxid)
     }
 
-    fn complete_request(&mut self, key: [u8; 32]) {
-        self.requested_txids.remove(&key);
-    }
+    fn insert_tx(&mut self, txid: Txid, tx: Transaction, feerate: Option<u64>) {
+        let depth = tx
+            .input
+            .iter()
+            .filter_map(|input| self.tx_depth.get(&input.previous_output.txid))
+            .max()
+            .map_or(0, |parent_depth| parent_depth + 1);
+        self.tx_depth.insert(txid, depth);
+
+        // Parent packages are already topologically sorted, so concatenating them and
+        // keeping first occurrences keeps every ancestor ahead of its descendants.
+        let wtxid = tx.compute_wtxid();
+        let mut package: Vec<Wtxid> = Vec::new();
+        for input in &tx.input {
+            let Some(parent) = self.tx_cache.get(&input.previous_output.txid) else {
+                continue;
+            };
+            let parent_wtxid = parent.compute_wtxid();
+            let ancestors = self
+                .packages
+                .get(&parent_wtxid)
+                .map_or(std::slice::from_ref(&parent_wtxid), Vec::as_slice);
+            for ancestor in ancestors {
+                if !package.contains(ancestor) {
+                    package.push(*ancestor);
+                }
+            }
+        }
+        if !package.is_empty() && package.len() < MAX_PACKAGE_COUNT {
+            package.push(wtxid);
+            self.packages.insert(wtxid, package);
+        }
 
-    fn insert_tx(&mut self, txid: Txid, tx: Transaction) {
-        self.tx_by_wtxid.insert(tx.compute_wtxid(), txid);
+        self.tx_by_wtxid.insert(wtxid, txid);
+        if let Some(feerate) = feerate {
+            self.feerates.insert(txid, feerate);
+        }
         if !self.tx_cache.contains_key(&txid) {
             self.tx_cache_order.push_back(txid);
         }
@@ -77,38 +187,130 @@ impl RelayState {
             if let Some(oldest) = self.tx_cache_order.pop_front() {
                 self.tx_by_wtxid
                     .retain(|_, mapped_txid| mapped_txid != &oldest);
-                self.tx_cache.remove(&oldest);
+                if let Some(evicted) = self.tx_cache.remove(&oldest) {
+                    self.packages.remove(&evicted.compute_wtxid());
+                }
+                self.tx_depth.remove(&oldest);
+                self.feerates.remove(&oldest);
+            }
+        }
+    }
+
+    // Drops confirmed txs from the cache; they stay seen so they are not fetched again.
+    fn remove_confirmed(&mut self, txids: &HashSet<Txid>) {
+        let mut wtxids = HashSet::new();
+        for txid in txids {
+            if let Some(tx) = self.tx_cache.remove(txid) {
+                wtxids.insert(tx.compute_wtxid());
             }
+            self.tx_depth.remove(txid);
+            self.feerates.remove(txid);
+        }
+        if wtxids.is_empty() {
+            return;
         }
+        self.tx_cache_order.retain(|txid| !txids.contains(txid));
+        self.tx_by_wtxid.retain(|wtxid, _| !wtxids.contains(wtxid));
+        // Confirmed ancestors no longer belong in the packages of their descendants.
+        self.packages.retain(|wtxid, package| {
+            package.retain(|member| !wtxids.contains(member));
+            !wtxids.contains(wtxid) && package.len() > 1
+        });
+    }
+
+    fn tx_depth(&self, txid: &Txid) -> u32 {
+        self.tx_depth.get(txid).copied().unwrap_or(0)
     }
 
     fn get_tx(&self, txid: &Txid) -> Option<Transaction> {
         self.tx_cache.get(txid).cloned()
     }
 
+    fn get_output_value(&self, outpoint: &OutPoint) -> Option<Amount> {
+        self.tx_cache
+            .get(&outpoint.txid)
+            .and_then(|parent| parent.output.get(outpoint.vout as usize))
+            .map(|output| output.value)
+    }
+
+    fn cached_txs_by_wtxid(&self) -> impl Iterator<Item = (Wtxid, &Transaction)> {
+        self.tx_by_wtxid
+            .iter()
+            .filter_map(|(wtxid, txid)| Some((*wtxid, self.tx_cache.get(txid)?)))
+    }
+
     fn get_tx_by_wtxid(&self, wtxid: &Wtxid) -> Option<Transaction> {
         self.tx_by_wtxid
             .get(wtxid)
             .and_then(|txid| self.get_tx(txid))
     }
 
-    fn cleanup_requested(&mut self, now: Instant) {
-        self.requested_txids
-            .retain(|_, requested_at| now.duration_since(*requested_at) < REQUESTED_TXID_TTL);
+    // The tx and its cached ancestors, child last; just the tx itself if it has none.
+    fn ancestor_package(&self, wtxid: &Wtxid) -> Option<Vec<Wtxid>> {
+        if let Some(package) = self.packages.get(wtxid) {
+            return Some(package.clone());
+        }
+        self.tx_by_wtxid.contains_key(wtxid).then(|| vec![*wtxid])
+    }
+
+    // Cached txs the feerate admits, oldest first so parents precede children. Txs with an
+    // unknown feerate are included, as in `relay_tx`.
+    fn cached_announcements(&self, min_feerate: u64) -> Vec<QueuedInv> {
+        self.tx_cache_order
+            .iter()
+            .filter(|txid| {
+                self.feerates
+                    .get(*txid)
+                    .is_none_or(|feerate| *feerate >= min_feerate)
+            })
+            .filter_map(|txid| {
+                let tx = self.tx_cache.get(txid)?;
+                Some(QueuedInv {
+                    txid: *txid,
+                    wtxid: tx.compute_wtxid(),
+                    feerate: self.feerates.get(txid).copied(),
+                    depth: self.tx_depth(txid),
+                })
+            })
+            .collect()
     }
 }
 
 pub struct PeerManager {
     db: Arc<AddressDb>,
     metrics: Arc<RwLock<Metrics>>,
-    target_peers: usize,
+    target_peers: Arc<AtomicUsize>,
+    max_inbound: usize,
+    ipv6_share: f64,
     peers: Arc<RwLock<Vec<PeerHandle>>>,
     pending_outbound: Arc<RwLock<HashSet<SocketAddr>>>,
     relay_state: Arc<RwLock<RelayState>>,
+    timeline: Arc<RwLock<TxTimeline>>,
+    trickle: Arc<RwLock<TrickleState>>,
+    orphans: Arc<RwLock<OrphanPool>>,
     our_addr: SocketAddr,
+    network: Network,
     user_agent: String,
     peer_timeout: Duration,
-    start_height: i32,
+    // Best header chain; its height is the start_height in our version messages.
+    headers: Arc<RwLock<HeaderChain>>,
+    header_sync: RwLock<HeaderSync>,
+    blocks: RwLock<CompactBlockState>,
+    block_timeline: RwLock<BlockTimeline>,
+    misbehavior: RwLock<MisbehaviorScores>,
+    // Peers whose BIP35 mempool request was answered this connection.
+    mempool_answered: RwLock<HashSet<SocketAddr>>,
+    dialer: Arc<Dialer>,
+    manual_peers: Arc<RwLock<Vec<DialTarget>>>,
+    // Distinct peers completing a handshake this run, for the shutdown summary.
+    seen_peers: RwLock<HashSet<SocketAddr>>,
+    prevouts: Option<PrevoutLookups<PendingTx>>,
+    // Answers to `prevouts` lookups, taken by the event loop when it starts.
+    prevout_results: std::sync::Mutex<Option<mpsc::UnboundedReceiver<Lookup<PendingTx>>>>,
+    // Txs whose lookup is outstanding; their children queue behind them.
+    pending_lookups: RwLock<HashSet<Txid>>,
+    serve_mempool: bool,
+    policy_mode: PolicyMode,
     discovery: Option<Arc<DiscoveryService>>,
 }
 
@@ -118,20 +320,40 @@ impl PeerManager {
         metrics: Arc<RwLock<Metrics>>,
         target_peers: usize,
         our_addr: SocketAddr,
+        network: Network,
         user_agent: String,
         peer_timeout_secs: u64,
     ) -> Self {
         Self {
             db,
             metrics,
-            target_peers,
+            target_peers: Arc::new(AtomicUsize::new(target_peers)),
+            max_inbound: DEFAULT_MAX_INBOUND_PEERS,
+            ipv6_share: DEFAULT_IPV6_PEER_SHARE,
             peers: Arc::new(RwLock::new(Vec::new())),
             pending_outbound: Arc::new(RwLock::new(HashSet::new())),
             relay_state: Arc::new(RwLock::new(RelayState::default())),
+            timeline: Arc::new(RwLock::new(TxTimeline::default())),
+            trickle: Arc::new(RwLock::new(TrickleState::default())),
+            orphans: Arc::new(RwLock::new(OrphanPool::default())),
             our_addr,
+            network,
             user_agent,
             peer_timeout: Duration::from_secs(peer_timeout_secs),
-            start_height: 0,
+            headers: Arc::new(RwLock::new(HeaderChain::new(network))),
+            header_sync: RwLock::new(HeaderSync::default()),
+            blocks: RwLock::new(CompactBlockState::default()),
+            block_timeline: RwLock::new(BlockTimeline::default()),
+            misbehavior: RwLock::new(MisbehaviorScores::default()),
+            mempool_answered: RwLock::new(HashSet::new()),
+            dialer: Arc::new(Dialer::default()),
+            manual_peers: Arc::new(RwLock::new(Vec::new())),
+            seen_peers: RwLock::new(HashSet::new()),
+            prevouts: None,
+            prevout_results: std::sync::Mutex::new(None),
+            pending_lookups: RwLock::new(HashSet::new()),
+            serve_mempool: false,
+            policy_mode: PolicyMode::Consensus,
             discovery: None,
         }
     }
@@ -140,12 +362,64 @@ impl PeerManager {
         self.peers.clone()
     }
 
+    // Fraction of the outbound budget reserved for IPv6 peers.
+    pub fn set_ipv6_share(&mut self, share: f64) {
+        self.ipv6_share = share.clamp(0.0, 1.0);
+    }
+
+    // Inbound slots, separate from the outbound target; 0 stops us listening.
+    pub fn set_max_inbound(&mut self, max_inbound: usize) {
+        self.max_inbound = max_inbound;
+    }
+
+    pub fn set_dialer(&mut self, dialer: Dialer) {
+        self.dialer = Arc::new(dialer);
+    }
+
+    pub async fn add_manual_peer(&self, target: DialTarget) {
+        self.handle().add_manual_peer(target).await;
+    }
+
+    pub fn handle(&self) -> ManagerHandle {
+        ManagerHandle {
+            db: self.db.clone(),
+            peers: self.peers.clone(),
+            manual_peers: self.manual_peers.clone(),
+            target_peers: self.target_peers.clone(),
+            timeline: self.timeline.clone(),
+            network: self.network,
+        }
+    }
+
+    // Used for feerates of txs whose parents are not in the tx cache.
+    pub fn set_prevout_source(&mut self, source: RestPrevoutSource) {
+        let (lookups, results) = source.spawn();
+        self.prevouts = Some(lookups);
+        self.prevout_results = std::sync::Mutex::new(Some(results));
+    }
+
+    // Header chain loaded from the database, so sync resumes where the last run stopped.
+    pub fn set_header_chain(&mut self, chain: HeaderChain) {
+        self.headers = Arc::new(RwLock::new(chain));
+    }
+
+    pub fn set_serve_mempool(&mut self, serve: bool) {
+        self.serve_mempool = serve;
+    }
+
+    pub fn set_policy_mode(&mut self, mode: PolicyMode) {
+        self.policy_mode = mode;
+    }
+
     pub fn set_discovery_service(&mut self, discovery: Arc<DiscoveryService>) {
         self.discovery = Some(discovery);
     }
 
-    pub async fn run(&self) {
+    // Runs until `shutdown` resolves, then stops dialing and accepting and disconnects peers.
+    pub async fn run(&self, shutdown: impl Future<Output = ()>) {
+        let started_at = Instant::now();
         let (event_tx, mut event_rx) = mpsc::unbounded_channel();
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
 
         // Spawn inbound listener task
         let listen_db = self.db.clone();
@@ -153,52 +427,131 @@ impl PeerManager {
         let listen_peers = self.peers.clone();
         let listen_event_tx = event_tx.clone();
         let listen_our_addr = self.our_addr;
+        let listen_network = self.network;
         let listen_timeout = self.peer_timeout;
-        let listen_start_height = self.start_height;
+        let listen_headers = self.headers.clone();
         let listen_user_agent = self.user_agent.clone();
+        let listen_max_inbound = self.max_inbound;
+        let mut listen_shutdown = shutdown_rx.clone();
 
         tokio::spawn(async move {
-            let bind_addr =
-                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), listen_our_addr.port());
-            let listener = match TcpListener::bind(bind_addr).await {
-                Ok(listener) => {
-                    info!("Listening for inbound peers on {}", bind_addr);
-                    listener
-                }
-                Err(e) => {
-                    warn!("Failed to bind inbound listener on {}: {}", bind_addr, e);
-                    return;
-                }
-            };
+            if listen_max_inbound == 0 {
+                info!("Not accepting inbound peers (--max-inbound-peers 0)");
+                return;
+            }
+            // Keys the netgroups protected from eviction, so attackers cannot aim for them.
+            let eviction_key = RandomState::new();
+            let pending_inbound = Arc::new(AtomicUsize::new(0));
+            let mut evicting = HashSet::new();
+
+            // Separate v4 and v6 sockets so both bind even where dual-stack is disabled.
+            let (accept_tx, mut accept_rx) = mpsc::unbounded_channel();
+            for bind_addr in [
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), listen_our_addr.port()),
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), listen_our_addr.port()),
+            ] {
+                let listener = match bind_listener(bind_addr) {
+                    Ok(listener) => {
+                        info!("Listening for inbound peers on {}", bind_addr);
+                        listener
+                    }
+                    Err(e) => {
+                        warn!("Failed to bind inbound listener on {}: {}", bind_addr, e);
+                        continue;
+                    }
+                };
+
+                let accept_tx = accept_tx.clone();
+                let mut shutdown = listen_shutdown.clone();
+                tokio::spawn(async move {
+                    loop {
+                        let accepted = tokio::select! {
+                            accepted = listener.accept() => accepted,
+                            _ = shutdown.changed() => break,
+                        };
+                        if accept_tx.send(accepted).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            drop(accept_tx);
 
             loop {
-                match listener.accept().await {
-                    Ok((stream, _)) => {
+                let accepted = tokio::select! {
+                    accepted = accept_rx.recv() => accepted,
+                    _ = listen_shutdown.changed() => return,
+                };
+                match accepted {
+                    Some(Ok((stream, addr))) => {
+                        if listen_db.is_banned(addr.ip()).unwrap_or(false) {
+                            debug!("Refusing inbound connection from banned {}", addr);
+                            let metrics = listen_metrics.read().await;
+                            metrics.banned_inbound_refused.inc();
+                            continue;
+                        }
+                        let admitted = admit_inbound(
+                            &listen_peers,
+                            pending_inbound.load(Ordering::Relaxed),
+                            listen_max_inbound,
+                            &mut evicting,
+                            &eviction_key,
+                        )
+                        .await;
+                        let reason = match admitted {
+                            Ok(None) => None,
+                            Ok(Some(evicted)) => {
+                                info!(
+                                    "Evicting inbound peer {} to make room for {}",
+                                    evicted, addr
+                                );
+                                Some("evicted")
+                            }
+                            Err(reason) => {
+                                debug!("Rejecting inbound peer {}: {}", addr, reason);
+                                Some(reason)
+                            }
+                        };
+                        if let Some(reason) = reason {
+                            let metrics = listen_metrics.read().await;
+                            metrics.inbound_evictions.with_label_values(&[reason]).inc();
+                        }
+                        if admitted.is_err() {
+                            continue;
+                        }
+                        pending_inbound.fetch_add(1, Ordering::Relaxed);
+
+                        let pending_inbound = pending_inbound.clone();
                         let event_tx = listen_event_tx.clone();
                         let db = listen_db.clone();
                         let our_addr = listen_our_addr;
                         let metrics = listen_metrics.clone();
                         let peers = listen_peers.clone();
                         let timeout_duration = listen_timeout;
-                        let start_height = listen_start_height;
+                        let start_height = listen_headers.read().await.height() as i32;
                         let user_agent = listen_user_agent.clone();
+                        let shutdown = listen_shutdown.clone();
 
                         tokio::spawn(async move {
-                            match timeout(
+                            let accepted = timeout(
                                 timeout_duration,
                                 Peer::accept(
                                     stream,
                                     our_addr,
+                                    listen_network,
                                     user_agent,
                                     db.clone(),
                                     event_tx,
                                     start_height,
                                 ),
                             )
-                            .await
-                            {
+                            .await;
+                            pending_inbound.fetch_sub(1, Ordering::Relaxed);
+                            match accepted {
+                                Ok(Ok(_)) if *shutdown.borrow() => {}
                                 Ok(Ok(peer)) => {
                                     let handle = peer.handle();
+                                    let v2_transport = handle.v2_transport();
                                     let mut peers_lock = peers.write().await;
                                     if peers_lock
                                         .iter()
@@ -214,6 +567,9 @@ impl PeerManager {
 
                                     let m = metrics.write().await;
                                     m.total_connections.inc();
+                                    if v2_transport {
+                                        m.v2_transport_connections.inc();
+                                    }
                                 }
                                 Ok(Err(e)) => {
                                     warn!("Failed inbound peer handshake: {}", e);
@@ -224,7 +580,45 @@ impl PeerManager {
                             }
                         });
                     }
-                    Err(e) => warn!("Inbound accept error: {}", e),
+                    Some(Err(e)) => warn!("Inbound accept error: {}", e),
+                    None => return,
+                }
+            }
+        });
+
+        // Spawn inventory trickle task
+        let trickle_peers = self.peers.clone();
+        let trickle_state = self.trickle.clone();
+        let trickle_metrics = self.metrics.clone();
+        let mut trickle_shutdown = shutdown_rx.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TRICKLE_TICK);
+
+            loop {
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = trickle_shutdown.changed() => return,
+                }
+
+                let peers = { trickle_peers.read().await.clone() };
+                let (depth, due) = {
+                    let mut trickle = trickle_state.write().await;
+                    let depth = trickle.depth();
+                    (depth, trickle.take_due(&peers, Instant::now()))
+                };
+
+                let metrics = trickle_metrics.read().await;
+                metrics.inv_queue_depth.set(depth as i64);
+                for (peer, batches) in due {
+                    for batch in batches {
+                        let batch_size = batch.len();
+                        if let Err(e) = peer.send(Message::Inv(batch)) {
+                            warn!("Failed to send inv batch to {}: {}", peer.addr(), e);
+                            break;
+                        }
+                        metrics.inv_batch_size.observe(batch_size as f64);
+                    }
                 }
             }
         });
@@ -235,122 +629,206 @@ impl PeerManager {
         let connect_peers = self.peers.clone();
         let connect_pending = self.pending_outbound.clone();
         let connect_our_addr = self.our_addr;
+        let connect_network = self.network;
         let connect_timeout = self.peer_timeout;
-        let connect_start_height = self.start_height;
+        let connect_headers = self.headers.clone();
         let connect_user_agent = self.user_agent.clone();
-        let target = self.target_peers;
+        let connect_dialer = self.dialer.clone();
+        let connect_manual = self.manual_peers.clone();
+        let connect_target = self.target_peers.clone();
+        let ipv6_share = self.ipv6_share;
+        let mut connect_shutdown = shutdown_rx.clone();
 
         tokio::spawn(async move {
             let mut interval = tokio::time::interval(OUTBOUND_REFILL_INTERVAL);
 
             loop {
-                interval.tick().await;
+                tokio::select! {
+                    _ = interval.tick() => {}
+                    _ = connect_shutdown.changed() => return,
+                }
 
-                let current_count = {
+                let target = connect_target.load(Ordering::Relaxed);
+                let connected_addrs: HashSet<SocketAddr> = {
                     let peers = connect_peers.read().await;
-                    peers.len()
+                    peers.iter().map(PeerHandle::addr).collect()
+                };
+                let pending_addrs = { connect_pending.read().await.clone() };
+
+                // Manual peers are always kept connected, regardless of the target count.
+                let mut dial_targets: Vec<DialTarget> = {
+                    let manual = connect_manual.read().await;
+                    manual
+                        .iter()
+                        .filter(|t| {
+                            !connected_addrs.contains(&t.peer_addr())
+                                && !pending_addrs.contains(&t.peer_addr())
+                        })
+                        .cloned()
+                        .collect()
                 };
 
-                if current_count < target {
-                    let to_connect = target - current_count;
+                // Inbound peers have their own slots and do not count towards the target.
+                let (ipv4_count, ipv6_count) = {
+                    let peers = connect_peers.read().await;
+                    count_by_family(
+                        peers
+                            .iter()
+                            .filter(|peer| !peer.is_inbound())
+                            .map(PeerHandle::addr),
+                    )
+                };
+                // Free slots go to IPv6 first, up to its share; IPv4 takes whatever IPv6
+                // could not fill, so an IPv4-only host still reaches the target.
+                let ipv6_target = ((target as f64) * ipv6_share).round() as usize;
+                let free = target.saturating_sub(ipv4_count + ipv6_count);
+                let ipv6_unfilled = ipv6_target.saturating_sub(ipv6_count).min(free);
+                let mut ipv4_free = free - ipv6_unfilled;
+
+                for network in [AddrNetwork::Ipv6, AddrNetwork::Ipv4] {
+                    let to_connect = match network {
+                        AddrNetwork::Ipv6 => ipv6_unfilled,
+                        _ => ipv4_free,
+                    };
+                    if to_connect == 0 {
+                        continue;
+                    }
                     // Mild over-dialing helps offset handshake failures and churn.
                     let desired_attempts = to_connect + (to_connect / 2);
                     let attempt_budget = desired_attempts.min(MAX_CONNECT_ATTEMPTS_PER_TICK);
-                    let connected_addrs: HashSet<SocketAddr> = {
-                        let peers = connect_peers.read().await;
-                        peers.iter().map(PeerHandle::addr).collect()
-                    };
-                    let pending_addrs = { connect_pending.read().await.clone() };
 
                     let addrs = connect_db
-                        .get_knots_excluding(attempt_budget * 4)
+                        .get_knots_excluding(attempt_budget * 4, network)
                         .unwrap_or_default();
                     let mut attempted = 0usize;
 
                     for addr in addrs {
-                        if addr.ip().is_ipv6() {
-                            continue;
-                        }
                         if connected_addrs.contains(&addr) {
                             continue;
                         }
                         if pending_addrs.contains(&addr) {
                             continue;
                         }
+                        if connect_db.is_banned(addr.ip()).unwrap_or(false) {
+                            continue;
+                        }
                         if attempted >= attempt_budget {
                             break;
                         }
                         attempted += 1;
+                        dial_targets.push(DialTarget::Addr(addr));
+                    }
+                    if network == AddrNetwork::Ipv6 {
+                        ipv4_free += ipv6_unfilled.saturating_sub(attempted);
+                    }
+                }
 
-                        {
-                            let mut pending = connect_pending.write().await;
-                            pending.insert(addr);
-                        }
-
-                        let event_tx = event_tx.clone();
-                        let db = connect_db.clone();
-                        let our_addr = connect_our_addr;
-                        let metrics = connect_metrics.clone();
-                        let peers = connect_peers.clone();
-                        let pending = connect_pending.clone();
-                        let timeout_duration = connect_timeout;
-                        let start_height = connect_start_height;
-                        let user_agent = connect_user_agent.clone();
+                for dial_target in dial_targets {
+                    let addr = dial_target.peer_addr();
+                    {
+                        let mut pending = connect_pending.write().await;
+                        pending.insert(addr);
+                    }
 
-                        tokio::spawn(async move {
-                            match timeout(
-                                timeout_duration,
-                                Peer::connect(
-                                    addr,
-                                    our_addr,
-                                    user_agent,
-                                    db.clone(),
-                                    event_tx,
-                                    start_height,
-                                ),
-                            )
-                            .await
-                            {
-                                Ok(Ok(peer)) => {
-                                    let handle = peer.handle();
-                                    let mut peers_lock = peers.write().await;
-                                    if peers_lock
-                                        .iter()
-                                        .any(|existing| existing.addr() == handle.addr())
-                                    {
-                                        info!("Skipping duplicate outbound peer {}", handle.addr());
-                                    } else {
-                                        peers_lock.push(handle);
-                                        drop(peers_lock);
+                    let event_tx = event_tx.clone();
+                    let db = connect_db.clone();
+                    let our_addr = connect_our_addr;
+                    let metrics = connect_metrics.clone();
+                    let peers = connect_peers.clone();
+                    let pending = connect_pending.clone();
+                    let timeout_duration = connect_timeout;
+                    let start_height = connect_headers.read().await.height() as i32;
+                    let user_agent = connect_user_agent.clone();
+                    let dialer = connect_dialer.clone();
+                    let shutdown = connect_shutdown.clone();
+
+                    tokio::spawn(async move {
+                        match timeout(
+                            timeout_duration,
+                            Peer::connect(
+                                dial_target.clone(),
+                                our_addr,
+                                connect_network,
+                                user_agent,
+                                db.clone(),
+                                event_tx,
+                                start_height,
+                                dialer,
+                            ),
+                        )
+                        .await
+                        {
+                            Ok(Ok(_)) if *shutdown.borrow() => {}
+                            Ok(Ok(peer)) => {
+                                let handle = peer.handle();
+                                let v2_transport = handle.v2_transport();
+                                let mut peers_lock = peers.write().await;
+                                if peers_lock
+                                    .iter()
+                                    .any(|existing| existing.addr() == handle.addr())
+                                {
+                                    info!("Skipping duplicate outbound peer {}", handle.addr());
+                                } else {
+                                    peers_lock.push(handle);
+                                    drop(peers_lock);
 
-                                        tokio::spawn(peer.run());
+                                    tokio::spawn(peer.run());
 
-                                        let m = metrics.write().await;
-                                        m.total_connections.inc();
+                                    let m = metrics.write().await;
+                                    m.total_connections.inc();
+                                    if v2_transport {
+                                        m.v2_transport_connections.inc();
                                     }
                                 }
-                                Ok(Err(e)) => {
-                                    warn!("Failed to connect to {}: {}", addr, e);
-                                    let _ = db.mark_failed(addr);
-                                }
-                                Err(_) => {
-                                    warn!("Connection to {} timed out", addr);
-                                    let _ = db.mark_failed(addr);
-                                }
                             }
-                            let mut pending_lock = pending.write().await;
-                            pending_lock.remove(&addr);
-                        });
-                    }
+                            Ok(Err(e)) => {
+                                warn!("Failed to connect to {}: {}", dial_target, e);
+                                let _ = db.mark_failed(addr);
+                            }
+                            Err(_) => {
+                                warn!("Connection to {} timed out", dial_target);
+                                let _ = db.mark_failed(addr);
+                            }
+                        }
+                        let mut pending_lock = pending.write().await;
+                        pending_lock.remove(&addr);
+                    });
                 }
             }
         });
 
         // Handle events
-        while let Some(event) = event_rx.recv().await {
+        tokio::pin!(shutdown);
+        let mut tx_request_tick = tokio::time::interval(TX_REQUEST_TICK);
+        let mut header_sync_tick = tokio::time::interval(HEADER_SYNC_TICK);
+        let mut prevout_results = self.prevout_results.lock().unwrap().take();
+        loop {
+            let event = tokio::select! {
+                event = event_rx.recv() => event,
+                Some(lookup) = next_lookup(&mut prevout_results) => {
+                    self.prevouts_fetched(lookup).await;
+                    continue;
+                }
+                _ = tx_request_tick.tick() => {
+                    self.send_due_tx_requests().await;
+                    continue;
+                }
+                _ = header_sync_tick.tick() => {
+                    self.sync_headers().await;
+                    self.store_block_announcements().await;
+                    self.retry_stalled_blocks().await;
+                    continue;
+                }
+                _ = &mut shutdown => break,
+            };
+            let Some(event) = event else {
+                break;
+            };
+
             match event {
                 PeerEvent::Connected { addr, version } => {
                     info!("Peer {} connected (agent: {})", addr, version.user_agent);
+                    self.seen_peers.write().await.insert(addr);
                     self.update_peer_counts().await;
                 }
                 PeerEvent::Disconnected { addr, reason } => {
@@ -361,9 +839,26 @@ impl PeerManager {
                         peers.retain(|p| p.addr() != addr);
                     }
 
+                    self.relay_state.write().await.requests.disconnected(addr);
+                    self.trickle.write().await.disconnected(addr);
+                    self.header_sync.write().await.disconnected(addr);
+                    self.blocks.write().await.disconnected(addr);
+                    self.misbehavior.write().await.disconnected(addr);
+                    self.mempool_answered.write().await.remove(&addr);
+
+                    let (orphans_dropped, orphan_pool_size) = {
+                        let mut orphans = self.orphans.write().await;
+                        (orphans.remove_peer(addr), orphans.len())
+                    };
+
                     {
                         let metrics = self.metrics.write().await;
                         metrics.total_disconnections.inc();
+                        metrics
+                            .orphans_dropped
+                            .with_label_values(&[OrphanDrop::PeerDisconnected.as_str()])
+                            .inc_by(orphans_dropped as u64);
+                        metrics.orphan_pool_size.set(orphan_pool_size as i64);
                     }
 
                     let _ = self.db.mark_failed(addr);
@@ -378,6 +873,10 @@ impl PeerManager {
                         AddressMessageKind::AddrV2 => "addrv2",
                     };
                     info!("Received {} {} addresses from peer {}", addrs.len(), kind_name, addr);
+                    if addrs.len() > MAX_ADDR_TO_SEND {
+                        self.misbehaving(addr, Misbehavior::AddrSpam).await;
+                        continue;
+                    }
                     {
                         let metrics = self.metrics.write().await;
                         match kind {
@@ -390,8 +889,77 @@ impl PeerManager {
                         discovery.handle_new_addresses(addrs).await;
                     }
                 }
+                PeerEvent::Pong { addr, rtt } => {
+                    let node_type = self.peer_node_type(addr).await;
+                    let metrics = self.metrics.read().await;
+                    metrics.observe_ping(node_type, rtt);
+                }
+                PeerEvent::Misbehaving { addr, misbehavior } => {
+                    self.misbehaving(addr, misbehavior).await;
+                }
+            }
+        }
+
+        self.shutdown(shutdown_tx, &mut event_rx).await;
+        self.log_run_summary(started_at).await;
+    }
+
+    // Stops listening and dialing, then waits for peers to disconnect. The address database
+    // needs no flush: it runs in autocommit mode, so each write is durable once it returns.
+    async fn shutdown(
+        &self,
+        shutdown_tx: watch::Sender<bool>,
+        event_rx: &mut mpsc::UnboundedReceiver<PeerEvent>,
+    ) {
+        info!("Shutting down: no longer accepting or dialing peers");
+        let _ = shutdown_tx.send(true);
+
+        let peers = { self.peers.read().await.clone() };
+        info!("Disconnecting {} peers", peers.len());
+        for peer in &peers {
+            // Record the session as a successful connection, not a failure.
+            if !dialer::is_internal_addr(peer.addr()) {
+                let _ = self.db.mark_connected(peer.addr());
+            }
+            peer.disconnect();
+        }
+
+        let drained = timeout(SHUTDOWN_GRACE, async {
+            while !self.peers.read().await.is_empty() {
+                match event_rx.recv().await {
+                    Some(PeerEvent::Disconnected { addr, .. }) => {
+                        self.peers.write().await.retain(|p| p.addr() != addr);
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
             }
+        })
+        .await;
+        if drained.is_err() {
+            warn!(
+                "{} peers did not disconnect within {:?}",
+                self.peers.read().await.len(),
+                SHUTDOWN_GRACE
+            );
         }
+        self.update_peer_counts().await;
+        self.store_block_announcements().await;
+    }
+
+    async fn log_run_summary(&self, started_at: Instant) {
+        let seen_peers = self.seen_peers.read().await.len();
+        let metrics = self.metrics.read().await;
+        info!(
+            "Run summary: uptime {}s, {} distinct peers seen over {} connections, \
+             {} txs received, {} txs relayed, {} nodes discovered",
+            started_at.elapsed().as_secs(),
+            seen_peers,
+            metrics.total_connections.get(),
+            metrics.transactions_received.get(),
+            metrics.transactions_relayed.get(),
+            metrics.nodes_discovered.get(),
+        );
     }
 
     async fn handle_message(&self, from_addr: SocketAddr, msg: Message) {
@@ -402,81 +970,259 @@ impl PeerManager {
                     metrics.inv_messages_received.inc_by(inv_list.len() as u64);
                 }
 
-                // Request tx data for unseen tx announcements.
-                let mut getdata_items = Vec::new();
+                // Track announcers of unseen txs; requests go out once each is due.
+                let peer = self.find_peer(from_addr).await;
+                let source_node_type = peer
+                    .as_ref()
+                    .map_or(NodeType::Unknown, PeerHandle::node_type);
+                let inbound = peer.as_ref().is_none_or(PeerHandle::is_inbound);
+                let request_delay = if inbound {
+                    INBOUND_PEER_TX_DELAY
+                } else {
+                    Duration::ZERO
+                };
+                let announced_blocks: Vec<BlockHash> = inv_list
+                    .iter()
+                    .filter_map(|inv| match inv {
+                        Inventory::Block(hash) | Inventory::WitnessBlock(hash) => Some(*hash),
+                        _ => None,
+                    })
+                    .collect();
+                self.record_block_announcements(from_addr, source_node_type, &announced_blocks)
+                    .await;
+                let announces_new_block = {
+                    let chain = self.headers.read().await;
+                    announced_blocks.iter().any(|hash| !chain.contains(hash))
+                };
+                let mut announcement_delays = Vec::new();
                 {
                     let mut relay_state = self.relay_state.write().await;
+                    let mut timeline = self.timeline.write().await;
                     let now = Instant::now();
 
                     for inv in inv_list {
                         let Some(key) = inventory_key(&inv) else {
                             continue;
                         };
-                        if relay_state.mark_requested(key, now) {
-                            getdata_items.push(inv);
+                        if let Some(delay) = timeline.record_announcement(
+                            key.to_byte_array(),
+                            from_addr,
+                            source_node_type,
+                        ) {
+                            announcement_delays.push(delay);
+                        }
+                        if !relay_state.is_seen(key) {
+                            relay_state.requests.received_inv(
+                                key,
+                                from_addr,
+                                !inbound,
+                                request_delay,
+                                now,
+                            );
                         }
                     }
                 }
 
-                if !getdata_items.is_empty() {
-                    self.send_to_peer(from_addr, Message::GetData(getdata_items))
-                        .await;
+                if !announcement_delays.is_empty() {
+                    let metrics = self.metrics.read().await;
+                    for delay in announcement_delays {
+                        metrics.observe_tx_propagation_delay(source_node_type, delay);
+                    }
+                }
+
+                // Peers without sendheaders announce blocks by inv; fetch the headers.
+                if announces_new_block {
+                    self.request_headers(from_addr).await;
                 }
             }
             Message::Tx(tx) => {
-                let txid = tx.compute_txid();
-                let txid_key = txid.to_byte_array();
-                let wtxid_key = tx.compute_wtxid().to_byte_array();
-                let source_node_type = self.peer_node_type(from_addr).await;
-                let is_new = {
-                    let mut relay_state = self.relay_state.write().await;
-                    relay_state.complete_request(txid_key);
-                    relay_state.complete_request(wtxid_key);
-                    let is_new = relay_state.mark_seen(txid_key);
-                    let _ = relay_state.mark_seen(wtxid_key);
-                    if is_new {
-                        relay_state.insert_tx(txid, tx.clone());
+                // Still processed: it may be new to us, but honest peers wait to be asked.
+                if !self.is_solicited(from_addr, &tx).await {
+                    self.misbehaving(from_addr, Misbehavior::UnsolicitedTx).await;
+                }
+                self.handle_tx(from_addr, tx).await;
+            }
+            Message::Headers(headers) => self.handle_headers(from_addr, headers).await,
+            Message::SendCmpct(sendcmpct) => {
+                self.blocks.write().await.received_sendcmpct(
+                    from_addr,
+                    sendcmpct.send_compact,
+                    sendcmpct.version,
+                );
+            }
+            Message::CmpctBlock(compact) => self.handle_compact_block(from_addr, compact).await,
+            Message::BlockTxn(response) => {
+                let block_hash = response.block_hash;
+                let (waiting, block) = {
+                    let mut blocks = self.blocks.write().await;
+                    let waiting = blocks.awaits(&block_hash, from_addr);
+                    (waiting, blocks.fill(from_addr, response))
+                };
+                match block {
+                    Some(block) => self.handle_block(from_addr, block, true).await,
+                    // The answer did not fit the gaps; the full block settles it.
+                    None if waiting => self.request_full_block(from_addr, block_hash).await,
+                    None => {}
+                }
+            }
+            Message::Block(block) => self.handle_block(from_addr, block, false).await,
+            Message::GetBlockTxn(request) => {
+                let response = {
+                    let blocks = self.blocks.read().await;
+                    blocks
+                        .recent_block(&request.block_hash)
+                        .and_then(|block| BlockTransactions::from_request(&request, block).ok())
+                };
+                if let Some(response) = response {
+                    self.send_to_peer(from_addr, Message::BlockTxn(response))
+                        .await;
+                }
+            }
+            Message::GetHeaders(request) => {
+                // Like Core, stay quiet while our own headers are still catching up.
+                let headers = {
+                    let chain = self.headers.read().await;
+                    if !chain.is_recent(Utc::now().timestamp() as u64) {
+                        return;
                     }
-                    is_new
+                    chain.headers_after(&request.locator_hashes, &request.stop_hash)
+                };
+                let _ = self.send_to_peer(from_addr, Message::Headers(headers)).await;
+            }
+            Message::AncPkgInfo(package) => {
+                // Only packages we asked about (an orphan's) are fetched, and only the
+                // members we have not seen yet.
+                let Some(child) = package.last() else {
+                    return;
                 };
-                if !is_new {
+                if !self.orphans.read().await.contains_wtxid(child) {
                     return;
                 }
-
+                let wanted: Vec<Wtxid> = {
+                    let relay_state = self.relay_state.read().await;
+                    package
+                        .iter()
+                        .filter(|wtxid| !relay_state.is_seen(TxKey::Wtxid(**wtxid)))
+                        .copied()
+                        .collect()
+                };
+                if !wanted.is_empty() {
+                    self.send_to_peer(from_addr, Message::GetPkgTxns(wanted))
+                        .await;
+                }
+            }
+            Message::GetPkgTxns(wtxids) => {
+                // BIP331 packages are served whole or not at all.
+                let txs = {
+                    let relay_state = self.relay_state.read().await;
+                    wtxids
+                        .iter()
+                        .map(|wtxid| relay_state.get_tx_by_wtxid(wtxid))
+                        .collect::<Option<Vec<_>>>()
+                };
+                if let Some(txs) = txs
+                    && self.send_to_peer(from_addr, Message::PkgTxns(txs)).await
                 {
-                    let metrics = self.metrics.write().await;
-                    metrics.transactions_received.inc();
-                    metrics.inc_transactions_received_from(source_node_type);
+                    let metrics = self.metrics.read().await;
+                    metrics.packages_served.inc();
+                }
+            }
+            Message::PkgTxns(txs) => {
+                {
+                    let metrics = self.metrics.read().await;
+                    metrics.packages_received.inc();
                 }
+                // Members arrive parents first, so each one completes the next.
+                for tx in txs {
+                    self.handle_tx(from_addr, tx).await;
+                }
+            }
+            Message::NotFound(items) => {
+                // A peer that announced a child but cannot serve its parent most likely
+                // saw the parent confirmed, so its orphans can go out without it.
+                let ready = {
+                    let mut relay_state = self.relay_state.write().await;
+                    let mut orphans = self.orphans.write().await;
+                    let mut ready = Vec::new();
+                    for key in items.iter().filter_map(inventory_key) {
+                        relay_state.requests.received_notfound(key, from_addr);
+                        if let TxKey::Txid(txid) = key {
+                            ready.extend(orphans.resolve_parent(&txid));
+                        }
+                    }
+                    ready
+                };
 
-                // Announce to non-Knots peers; they request via getdata.
-                self.relay_inv(from_addr, vec![Inventory::Transaction(txid)])
-                    .await;
+                self.release_orphans(ready).await;
             }
             Message::GetData(requests) => {
-                let to_send = {
+                let (responses, not_found) = {
                     let relay_state = self.relay_state.read().await;
-                    requests
-                        .iter()
-                        .filter_map(|inv| match inv {
-                            Inventory::Transaction(txid) => relay_state.get_tx(txid),
-                            Inventory::WitnessTransaction(txid) => relay_state.get_tx(txid),
-                            Inventory::WTx(wtxid) => relay_state.get_tx_by_wtxid(wtxid),
-                            _ => None,
-                        })
-                        .collect::<Vec<_>>()
+                    let blocks = self.blocks.read().await;
+                    let mut responses = Vec::new();
+                    let mut not_found = Vec::new();
+                    for inv in requests {
+                        let response = match inv {
+                            // Plain MSG_TX asks for the legacy, witness-free serialization.
+                            Inventory::Transaction(txid) => relay_state
+                                .get_tx(&txid)
+                                .map(|tx| Message::Tx(strip_witness(tx))),
+                            Inventory::WitnessTransaction(txid) => {
+                                relay_state.get_tx(&txid).map(Message::Tx)
+                            }
+                            Inventory::WTx(wtxid) => {
+                                relay_state.get_tx_by_wtxid(&wtxid).map(Message::Tx)
+                            }
+                            Inventory::Unknown { inv_type, hash } if inv_type == MSG_ANCPKGINFO => {
+                                relay_state
+                                    .ancestor_package(&Wtxid::from_byte_array(hash))
+                                    .map(Message::AncPkgInfo)
+                            }
+                            // Only the few blocks we reconstructed; others are not ours to serve.
+                            Inventory::Block(hash)
+                            | Inventory::WitnessBlock(hash)
+                            | Inventory::CompactBlock(hash) => {
+                                let Some(block) = blocks.recent_block(&hash) else {
+                                    continue;
+                                };
+                                Some(block_response(&inv, block))
+                            }
+                            _ => continue,
+                        };
+                        match response {
+                            Some(response) => responses.push(response),
+                            None => not_found.push(inv),
+                        }
+                    }
+                    (responses, not_found)
                 };
 
                 let mut sent = 0u64;
-                for tx in to_send {
-                    if self.send_to_peer(from_addr, Message::Tx(tx)).await {
+                for response in responses {
+                    let is_tx = matches!(response, Message::Tx(_));
+                    if self.send_to_peer(from_addr, response).await && is_tx {
                         sent += 1;
                     }
                 }
 
-                if sent > 0 {
-                    let metrics = self.metrics.write().await;
-                    metrics.transactions_relayed.inc_by(sent);
+                // Tell the peer right away so it can ask someone else instead of timing out.
+                let not_found_count = not_found.len() as u64;
+                if !not_found.is_empty() {
+                    self.send_to_peer(from_addr, Message::NotFound(not_found))
+                        .await;
+                }
+
+                let metrics = self.metrics.write().await;
+                metrics.transactions_relayed.inc_by(sent);
+                metrics.notfound_items_sent.inc_by(not_found_count);
+            }
+            Message::MemPool => {
+                {
+                    let metrics = self.metrics.read().await;
+                    metrics.mempool_requests_received.inc();
+                }
+                if self.serve_mempool {
+                    self.answer_mempool(from_addr).await;
                 }
             }
             Message::GetAddr => {
@@ -502,7 +1248,7 @@ impl PeerManager {
                         .into_iter()
                         .map(|addr| AddressEntry {
                             services: ServiceFlags::NONE,
-                            addr,
+                            addr: NetAddr::Ip(addr),
                             timestamp,
                         })
                         .collect::<Vec<_>>()
@@ -513,9 +1259,17 @@ impl PeerManager {
                 }
             }
             Message::FeeFilter(feerate) => {
+                let feerate = feerate.max(0) as u64;
+                {
+                    let peers = self.peers.read().await;
+                    if let Some(peer) = peers.iter().find(|p| p.addr() == from_addr) {
+                        peer.set_fee_filter(feerate);
+                    }
+                }
+
                 let metrics = self.metrics.write().await;
                 metrics.feefilter_messages_received.inc();
-                metrics.feefilter_sat_per_kvb.observe((feerate.max(0)) as f64);
+                metrics.feefilter_sat_per_kvb.observe(feerate as f64);
             }
             Message::WtxidRelay => {
                 let metrics = self.metrics.write().await;
@@ -529,30 +1283,1081 @@ impl PeerManager {
         }
     }
 
-    async fn relay_inv(&self, from_addr: SocketAddr, inv_list: Vec<Inventory>) {
-        let msg = Message::Inv(inv_list);
-        let peers = { self.peers.read().await.clone() };
-        let mut stale = Vec::new();
+    async fn handle_headers(&self, from_addr: SocketAddr, headers: Vec<Header>) {
+        // Larger batches answer our getheaders rather than announce anything.
+        if headers.len() <= MAX_BLOCKS_TO_ANNOUNCE {
+            let hashes: Vec<BlockHash> = headers.iter().map(Header::block_hash).collect();
+            let node_type = self.peer_node_type(from_addr).await;
+            self.record_block_announcements(from_addr, node_type, &hashes)
+                .await;
+        }
+        let full = headers.len() >= MAX_HEADERS_RESULTS;
+        let result = self.connect_headers(from_addr, &headers).await;
+
+        // A full batch means the peer has more, which we ask for from where it left off, even
+        // on a branch that has not overtaken ours yet. Unconnecting ones mean we missed some.
+        let more = full || matches!(result, Err(HeaderError::Unconnected));
+        self.header_sync.write().await.received(from_addr, more);
+        if full && result.is_ok() {
+            self.request_headers_from(from_addr, headers.last().map(Header::block_hash))
+                .await;
+        } else if more {
+            self.request_headers(from_addr).await;
+        } else if let Ok(Some(connected)) = result {
+            self.request_compact_block(from_addr, &connected).await;
+        }
+    }
 
-        for peer in peers {
-            // Don't relay back to sender
-            if peer.addr() == from_addr {
-                continue;
+    // Adds headers to our chain and stores what is new. Invalid headers cost the sender its
+    // connection.
+    async fn connect_headers(
+        &self,
+        from_addr: SocketAddr,
+        headers: &[Header],
+    ) -> Result<Option<Connected>, HeaderError> {
+        let now = Utc::now().timestamp();
+        let (result, tip_height, tip_age) = {
+            let mut chain = self.headers.write().await;
+            let result = chain.connect(headers, now as u32);
+            if let Ok(Some(connected)) = &result
+                && connected.reorged > 0
+            {
+                info!(
+                    "Header reorg at height {}: {} headers replaced by {}",
+                    connected.from_height, connected.reorged, connected.added
+                );
+                if let Err(e) = self.db.delete_mined_blocks_from(connected.from_height) {
+                    warn!("Failed to drop mined txs of reorged blocks: {}", e);
+                }
             }
-
+            // Headers before an invalid one may still have moved the tip.
+            if let Some((from_height, new_headers)) = chain.take_unstored()
+                && let Err(e) = self.db.store_headers(from_height, &new_headers)
+            {
+                warn!("Failed to store headers: {}", e);
+            }
+            (result, chain.height(), chain.tip_age(now as u64))
+        };
+
+        {
+            let metrics = self.metrics.read().await;
+            metrics.header_tip_height.set(tip_height as i64);
+            metrics.header_tip_age_seconds.set(tip_age as i64);
+        }
+        self.observe_block_announcements().await;
+        match &result {
+            Ok(Some(connected)) => {
+                debug!(
+                    "{} new headers from {}, tip height {}",
+                    connected.added, from_addr, tip_height
+                );
+            }
+            Ok(None) => {}
+            Err(e) => {
+                let metrics = self.metrics.read().await;
+                metrics
+                    .headers_rejected
+                    .with_label_values(&[e.as_str()])
+                    .inc();
+                if e.is_invalid() {
+                    warn!("Invalid headers from {}: {}", from_addr, e.as_str());
+                    drop(metrics);
+                    self.misbehaving(from_addr, Misbehavior::InvalidHeaders).await;
+                }
+            }
+        }
+        result
+    }
+
+    // Notes each peer announcing a new block, in order. Only once our headers are caught up,
+    // so blocks we merely had not synced yet are not mistaken for new ones.
+    async fn record_block_announcements(
+        &self,
+        from_addr: SocketAddr,
+        node_type: NodeType,
+        hashes: &[BlockHash],
+    ) {
+        if hashes.is_empty() {
+            return;
+        }
+        {
+            let chain = self.headers.read().await;
+            if !chain.is_recent(Utc::now().timestamp() as u64) {
+                return;
+            }
+            let mut block_timeline = self.block_timeline.write().await;
+            for hash in hashes {
+                // Blocks we had before anyone announced them are not new.
+                if chain.contains(hash) && !block_timeline.contains(hash) {
+                    continue;
+                }
+                block_timeline.record_announcement(*hash, from_addr, node_type);
+            }
+        }
+        self.observe_block_announcements().await;
+    }
+
+    // Counts announcements of blocks whose headers are now on our chain; until then, an
+    // announced hash may be made up.
+    async fn observe_block_announcements(&self) {
+        let observed = {
+            let chain = self.headers.read().await;
+            self.block_timeline
+                .write()
+                .await
+                .take_validated(|hash| chain.contains(hash))
+        };
+        if observed.is_empty() {
+            return;
+        }
+        let metrics = self.metrics.read().await;
+        for (node_type, position, delay) in observed {
+            metrics.observe_block_announcement(node_type, position, delay);
+        }
+    }
+
+    // Writes out announcements of blocks whose headers have validated since the last call.
+    async fn store_block_announcements(&self) {
+        let announcements = {
+            let chain = self.headers.read().await;
+            self.block_timeline
+                .write()
+                .await
+                .take_unpersisted(|hash| chain.contains(hash))
+        };
+        if announcements.is_empty() {
+            return;
+        }
+        if let Err(e) = self.db.store_block_announcements(&announcements) {
+            warn!("Failed to store block announcements: {}", e);
+        }
+    }
+
+    // Fetches a freshly announced tip as a compact block, once we are caught up.
+    async fn request_compact_block(&self, from_addr: SocketAddr, connected: &Connected) {
+        let tip = {
+            let chain = self.headers.read().await;
+            if connected.added > MAX_BLOCKS_TO_FETCH
+                || !chain.is_recent(Utc::now().timestamp() as u64)
+            {
+                return;
+            }
+            chain.tip_hash()
+        };
+        {
+            let mut blocks = self.blocks.write().await;
+            if !blocks.supports(from_addr) || !blocks.request(tip, Instant::now()) {
+                return;
+            }
+        }
+        self.send_to_peer(from_addr, Message::GetData(vec![Inventory::CompactBlock(tip)]))
+            .await;
+    }
+
+    async fn handle_compact_block(&self, from_addr: SocketAddr, compact: HeaderAndShortIds) {
+        let block_hash = compact.header.block_hash();
+        let node_type = self.peer_node_type(from_addr).await;
+        self.record_block_announcements(from_addr, node_type, &[block_hash])
+            .await;
+        if self.blocks.read().await.knows(&block_hash, Instant::now()) {
+            return;
+        }
+        match self.connect_headers(from_addr, &[compact.header]).await {
+            Ok(_) => {}
+            Err(HeaderError::Unconnected) => {
+                self.request_headers(from_addr).await;
+                return;
+            }
+            Err(_) => return,
+        }
+
+        let result = {
+            let relay_state = self.relay_state.read().await;
+            let orphans = self.orphans.read().await;
+            let candidates = relay_state
+                .cached_txs_by_wtxid()
+                .chain(orphans.txs_by_wtxid());
+            self.blocks
+                .write()
+                .await
+                .reconstruct(from_addr, &compact, candidates)
+        };
+
+        let reconstruction = match result {
+            Ok((reconstruction, stats)) => {
+                info!(
+                    "Compact block {} from {}: {}/{} txs cached",
+                    block_hash, from_addr, stats.cached, stats.short_ids
+                );
+                let metrics = self.metrics.read().await;
+                metrics.block_txs_cached.inc_by(stats.cached as u64);
+                metrics
+                    .block_txs_missing
+                    .inc_by((stats.short_ids - stats.cached) as u64);
+                if stats.short_ids > 0 {
+                    metrics
+                        .compact_block_cache_hit_ratio
+                        .observe(stats.cached as f64 / stats.short_ids as f64);
+                }
+                reconstruction
+            }
+            Err(CompactBlockError::ShortIdCollision) => {
+                self.request_full_block(from_addr, block_hash).await;
+                return;
+            }
+            Err(CompactBlockError::Malformed) => {
+                warn!("Malformed compact block {} from {}", block_hash, from_addr);
+                self.misbehaving(from_addr, Misbehavior::InvalidCompactBlock).await;
+                return;
+            }
+        };
+
+        match reconstruction {
+            Reconstruction::Complete(block) => self.handle_block(from_addr, block, true).await,
+            Reconstruction::Missing(indexes) => {
+                let request = BlockTransactionsRequest {
+                    block_hash,
+                    indexes,
+                };
+                self.send_to_peer(from_addr, Message::GetBlockTxn(request))
+                    .await;
+            }
+        }
+    }
+
+    // What the relay log knows about each tx of a block on our header chain.
+    async fn match_mined_txs(&self, block: &Block) -> Option<(u32, Vec<MinedTx>)> {
+        let height = self.headers.read().await.height_of(&block.block_hash())?;
+        let relay_state = self.relay_state.read().await;
+        let timeline = self.timeline.read().await;
+        let mined = block
+            .txdata
+            .iter()
+            .skip(1)
+            .map(|tx| {
+                let txid = tx.compute_txid();
+                let record = timeline
+                    .get(&txid.to_byte_array())
+                    .or_else(|| timeline.get(&tx.compute_wtxid().to_byte_array()));
+                let arrival = record.and_then(|record| record.arrival.as_ref());
+                MinedTx {
+                    txid,
+                    received: arrival.is_some() || relay_state.seen_txids.contains(&txid),
+                    relayed: record.is_some_and(|record| record.relay.is_some())
+                        || relay_state.tx_cache.contains_key(&txid),
+                    first_sender: arrival.map(|arrival| arrival.node_type),
+                    knots_announced: record.map(|record| record.knots_announced),
+                    categories: classify::classify(tx),
+                }
+            })
+            .collect();
+        Some((height, mined))
+    }
+
+    async fn record_mined_txs(&self, block_hash: BlockHash, height: u32, mined: Vec<MinedTx>) {
+        let received = mined.iter().filter(|tx| tx.received).count();
+        let without_knots = mined
+            .iter()
+            .filter(|tx| tx.knots_announced == Some(false))
+            .count();
+        info!(
+            "Block {} at height {}: {}/{} txs passed through us, {} never announced by Knots",
+            block_hash,
+            height,
+            received,
+            mined.len(),
+            without_knots
+        );
+
+        if let Err(e) = self.db.store_mined_block(block_hash, height, &mined) {
+            warn!("Failed to store mined txs: {}", e);
+        }
+        let metrics = self.metrics.read().await;
+        for tx in &mined {
+            metrics.observe_mined_tx(tx);
+        }
+    }
+
+    // Asks for blocks whose missing txs never came in full, from another peer that announced
+    // them if there is one.
+    async fn retry_stalled_blocks(&self) {
+        let now = Instant::now();
+        let stalled = self.blocks.write().await.take_stalled(now);
+        for (block_hash, stalled_peer) in stalled {
+            let announcers = self.block_timeline.read().await.announcers(&block_hash);
+            let mut peer = stalled_peer;
+            for announcer in announcers {
+                if announcer != stalled_peer && self.find_peer(announcer).await.is_some() {
+                    peer = announcer;
+                    break;
+                }
+            }
+            debug!(
+                "Missing txs of block {} timed out at {}, asking {} for the block",
+                block_hash, stalled_peer, peer
+            );
+            self.blocks.write().await.request(block_hash, now);
+            self.request_full_block(peer, block_hash).await;
+        }
+    }
+
+    async fn request_full_block(&self, from_addr: SocketAddr, block_hash: BlockHash) {
+        self.send_to_peer(
+            from_addr,
+            Message::GetData(vec![Inventory::WitnessBlock(block_hash)]),
+        )
+        .await;
+    }
+
+    // A complete block: kept for serving, pushed to high-bandwidth peers if it is the new tip,
+    // and, if it is on our chain, its txs are dropped from the tx cache and announcement
+    // queues since they no longer need relaying. `reconstructed` blocks came from a compact
+    // block; the others arrived whole.
+    async fn handle_block(&self, from_addr: SocketAddr, block: Block, reconstructed: bool) {
+        let block_hash = block.block_hash();
+        if !reconstructed {
+            match self.connect_headers(from_addr, &[block.header]).await {
+                Ok(_) => {}
+                Err(HeaderError::Unconnected) => {
+                    self.request_headers(from_addr).await;
+                    return;
+                }
+                Err(_) => return,
+            }
+        }
+
+        let compact = HeaderAndShortIds::from_block(
+            &block,
+            rand::random(),
+            COMPACT_BLOCK_VERSION as u32,
+            &[],
+        );
+        let keys: Vec<(Txid, Wtxid)> = block
+            .txdata
+            .iter()
+            .skip(1)
+            .map(|tx| (tx.compute_txid(), tx.compute_wtxid()))
+            .collect();
+        let is_new = self.blocks.read().await.recent_block(&block_hash).is_none();
+        // Matched against the relay log before the block's txs are marked seen below.
+        let mined = if is_new {
+            self.match_mined_txs(&block).await
+        } else {
+            None
+        };
+
+        let (added, promotions) = {
+            let mut blocks = self.blocks.write().await;
+            if blocks.add_block(block) {
+                (true, blocks.promote(from_addr))
+            } else {
+                (false, Vec::new())
+            }
+        };
+        if !added {
+            // A short id collision can yield the wrong txs; a full block has no excuse.
+            if reconstructed {
+                self.request_full_block(from_addr, block_hash).await;
+            } else {
+                warn!("Block {} from {} does not match its header", block_hash, from_addr);
+                self.misbehaving(from_addr, Misbehavior::InvalidBlock).await;
+            }
+            return;
+        }
+        info!("Block {} from {} ({} txs)", block_hash, from_addr, keys.len() + 1);
+        if reconstructed {
+            let metrics = self.metrics.read().await;
+            metrics.compact_blocks_reconstructed.inc();
+        }
+        if let Some((height, mined)) = mined {
+            self.record_mined_txs(block_hash, height, mined).await;
+        }
+        if is_new && let Some(peer) = self.find_peer(from_addr).await {
+            peer.mark_block();
+        }
+
+        for (peer, announce) in promotions {
+            let sendcmpct = SendCmpct {
+                send_compact: announce,
+                version: COMPACT_BLOCK_VERSION,
+            };
+            self.send_to_peer(peer, Message::SendCmpct(sendcmpct)).await;
+        }
+
+        // Confirmed txs need no fetching, and orphans spending them can go out.
+        let confirmed: HashSet<Txid> = keys.iter().map(|(txid, _)| *txid).collect();
+        let ready = {
+            let mut relay_state = self.relay_state.write().await;
+            let mut orphans = self.orphans.write().await;
+            let mut ready = Vec::new();
+            for (txid, wtxid) in keys {
+                relay_state.requests.received_response(TxKey::Txid(txid));
+                relay_state.requests.received_response(TxKey::Wtxid(wtxid));
+                relay_state.mark_seen(txid, wtxid);
+                ready.extend(orphans.resolve_parent(&txid));
+            }
+            ready
+        };
+        // A block off our chain may still be reorged out; its txs stay relayable until then.
+        if self.headers.read().await.contains(&block_hash) {
+            self.relay_state.write().await.remove_confirmed(&confirmed);
+            self.trickle.write().await.remove_confirmed(&confirmed);
+        }
+        if !ready.is_empty() {
+            let metrics = self.metrics.read().await;
+            metrics.orphans_released.inc_by(ready.len() as u64);
+        }
+        for orphan in ready {
+            self.relay_with_descendants(orphan.from, orphan.tx, orphan.txid, orphan.wtxid)
+                .await;
+        }
+
+        let is_tip = self.headers.read().await.tip_hash() == block_hash;
+        let Ok(compact) = compact else {
+            return;
+        };
+        if !is_tip {
+            return;
+        }
+        let targets: Vec<PeerHandle> = {
+            let peers = self.peers.read().await;
+            let blocks = self.blocks.read().await;
+            peers
+                .iter()
+                .filter(|peer| peer.addr() != from_addr)
+                .filter(|peer| peer.node_type() != NodeType::Knots)
+                .filter(|peer| blocks.wants_high_bandwidth(peer.addr()))
+                .cloned()
+                .collect()
+        };
+        let mut forwarded = 0u64;
+        for peer in targets {
+            if self.send_to_peer_handle(&peer, Message::CmpctBlock(compact.clone())) {
+                forwarded += 1;
+            }
+        }
+
+        let metrics = self.metrics.read().await;
+        metrics.compact_blocks_forwarded.inc_by(forwarded);
+    }
+
+    // Sends getheaders from our tip unless one is already outstanding at that peer.
+    async fn request_headers(&self, addr: SocketAddr) {
+        self.request_headers_from(addr, None).await;
+    }
+
+    // Sends getheaders from `from` (a header we have), or else our tip.
+    async fn request_headers_from(&self, addr: SocketAddr, from: Option<BlockHash>) {
+        if !self.header_sync.write().await.request(addr, Instant::now()) {
+            return;
+        }
+        let locator_hashes = {
+            let chain = self.headers.read().await;
+            from.map_or_else(|| chain.locator(), |hash| chain.locator_from(hash))
+        };
+        let request = GetHeadersMessage {
+            version: ADVERTISED_PROTOCOL_VERSION,
+            locator_hashes,
+            stop_hash: BlockHash::all_zeros(),
+        };
+        self.send_to_peer(addr, Message::GetHeaders(request)).await;
+    }
+
+    // Starts header sync with newly connected peers, outbound ones first.
+    async fn sync_headers(&self) {
+        let now = Utc::now().timestamp() as u64;
+        let (tip_recent, tip_height, tip_age) = {
+            let chain = self.headers.read().await;
+            (chain.is_recent(now), chain.height(), chain.tip_age(now))
+        };
+        {
+            let metrics = self.metrics.read().await;
+            metrics.header_tip_height.set(tip_height as i64);
+            metrics.header_tip_age_seconds.set(tip_age as i64);
+        }
+
+        let candidates: Vec<SocketAddr> = {
+            let peers = self.peers.read().await;
+            let (inbound, outbound): (Vec<&PeerHandle>, Vec<&PeerHandle>) =
+                peers.iter().partition(|peer| peer.is_inbound());
+            outbound.into_iter().chain(inbound).map(PeerHandle::addr).collect()
+        };
+        let chosen =
+            self.header_sync
+                .write()
+                .await
+                .peers_to_start(&candidates, tip_recent, Instant::now());
+        for addr in chosen {
+            self.request_headers(addr).await;
+        }
+    }
+
+    async fn handle_tx(&self, from_addr: SocketAddr, tx: Transaction) {
+        let txid = tx.compute_txid();
+        let wtxid = tx.compute_wtxid();
+        let txid_key = txid.to_byte_array();
+        let wtxid_key = wtxid.to_byte_array();
+        let peer = self.find_peer(from_addr).await;
+        let source_node_type = peer
+            .as_ref()
+            .map_or(NodeType::Unknown, PeerHandle::node_type);
+
+        if let Err(reason) = validation::check_sanity(&tx) {
+            self.reject_invalid_tx(from_addr, &tx, txid, wtxid, reason).await;
+            return;
+        }
+
+        // We always ask for witness data, but a stripped copy must not be cached and
+        // re-served as the real tx. Failing this peer's request lets another announcer
+        // deliver it.
+        if is_witness_stripped(&tx) {
+            debug!("Rejecting witness-stripped tx {} from {}", txid, from_addr);
+            {
+                let mut relay_state = self.relay_state.write().await;
+                relay_state
+                    .requests
+                    .received_notfound(TxKey::Txid(txid), from_addr);
+                relay_state
+                    .requests
+                    .received_notfound(TxKey::Wtxid(wtxid), from_addr);
+            }
+            let metrics = self.metrics.read().await;
+            metrics.stripped_txs_rejected.inc();
+            return;
+        }
+
+        let (new_txid, new_wtxid) = {
+            let mut relay_state = self.relay_state.write().await;
+            relay_state.requests.received_response(TxKey::Txid(txid));
+            relay_state.requests.received_response(TxKey::Wtxid(wtxid));
+            relay_state.mark_seen(txid, wtxid)
+        };
+        if !new_txid {
+            // Same txid with a different witness: the first variant stays the one we
+            // serve and announce; remembering the wtxid stops us refetching this one.
+            if new_wtxid {
+                debug!("Ignoring witness-malleated variant {} of {}", wtxid, txid);
+                let metrics = self.metrics.read().await;
+                metrics.witness_malleated_txs.inc();
+            }
+            return;
+        }
+
+        self.timeline.write().await.record_arrival(
+            txid_key,
+            wtxid_key,
+            from_addr,
+            source_node_type,
+        );
+        // Peers that bring us new txs are spared from inbound eviction.
+        if let Some(peer) = &peer {
+            peer.mark_tx();
+        }
+
+        let categories = classify::classify(&tx);
+        if !categories.is_empty() {
+            debug!("Tx {} from {} classified as {:?}", txid, from_addr, categories);
+        }
+        {
+            let metrics = self.metrics.write().await;
+            metrics.transactions_received.inc();
+            metrics.inc_transactions_received_from(source_node_type);
+            metrics.inc_transaction_categories(&categories, source_node_type);
+        }
+
+        // Already marked seen, so a non-standard tx is neither relayed nor fetched again.
+        if self.policy_mode == PolicyMode::Standard
+            && let Err(reason) = validation::check_standard(&tx)
+        {
+            debug!("Not relaying non-standard tx {} ({})", txid, reason.as_str());
+            let metrics = self.metrics.read().await;
+            metrics
+                .txs_rejected
+                .with_label_values(&[reason.as_str()])
+                .inc();
+            return;
+        }
+
+        self.accept_tx(from_addr, tx, txid, wtxid).await;
+    }
+
+    // A tx that can never be valid: remember it so nobody is asked for it again, and ban
+    // the peer that sent it.
+    async fn reject_invalid_tx(
+        &self,
+        from_addr: SocketAddr,
+        tx: &Transaction,
+        txid: Txid,
+        wtxid: Wtxid,
+        reason: Rejection,
+    ) {
+        warn!("Invalid tx {} from {}: {}", txid, from_addr, reason.as_str());
+        {
+            let mut relay_state = self.relay_state.write().await;
+            relay_state.requests.received_response(TxKey::Wtxid(wtxid));
+            relay_state.reject(TxKey::Wtxid(wtxid));
+            // With a witness, another copy of the same txid could still be valid.
+            if tx.input.iter().all(|input| input.witness.is_empty()) {
+                relay_state.requests.received_response(TxKey::Txid(txid));
+                relay_state.reject(TxKey::Txid(txid));
+            } else {
+                relay_state
+                    .requests
+                    .received_notfound(TxKey::Txid(txid), from_addr);
+            }
+        }
+
+        {
+            let metrics = self.metrics.read().await;
+            metrics
+                .txs_rejected
+                .with_label_values(&[reason.as_str()])
+                .inc();
+        }
+        if self.misbehaving(from_addr, Misbehavior::InvalidTx).await {
+            let metrics = self.metrics.read().await;
+            metrics.invalid_tx_disconnects.inc();
+        }
+    }
+
+    // Whether we asked `from_addr` for the tx (orphan parents included), or already have it
+    // and the peer merely answered late.
+    async fn is_solicited(&self, from_addr: SocketAddr, tx: &Transaction) -> bool {
+        let txid = tx.compute_txid();
+        let wtxid = tx.compute_wtxid();
+        let relay_state = self.relay_state.read().await;
+        relay_state.is_seen(TxKey::Txid(txid))
+            || relay_state.is_seen(TxKey::Wtxid(wtxid))
+            || relay_state.requests.requested_from(TxKey::Txid(txid), from_addr)
+            || relay_state.requests.requested_from(TxKey::Wtxid(wtxid), from_addr)
+    }
+
+    // Adds to the peer's misbehavior score. Once it reaches the threshold the peer is
+    // disconnected and its IP banned, unless it is one we were told to connect to or a local
+    // address. Returns whether the peer was disconnected.
+    async fn misbehaving(&self, addr: SocketAddr, misbehavior: Misbehavior) -> bool {
+        let Some(peer) = self.find_peer(addr).await else {
+            return false;
+        };
+        debug!("Peer {} misbehaving: {}", addr, misbehavior.as_str());
+        {
+            let metrics = self.metrics.read().await;
+            metrics
+                .misbehavior
+                .with_label_values(&[misbehavior.as_str()])
+                .inc();
+        }
+        if !self.misbehavior.write().await.add(addr, misbehavior) {
+            return false;
+        }
+
+        peer.disconnect();
+        let manual = self
+            .manual_peers
+            .read()
+            .await
+            .iter()
+            .any(|target| target.peer_addr() == addr);
+        if manual || dialer::is_internal_addr(addr) || ban::is_local(addr.ip()) {
+            warn!("Disconnecting misbehaving peer {} ({})", addr, misbehavior.as_str());
+            return true;
+        }
+        warn!("Banning misbehaving peer {} ({})", addr, misbehavior.as_str());
+        if let Err(e) = self
+            .db
+            .ban(addr.ip(), misbehavior.as_str(), Utc::now() + BAN_DURATION)
+        {
+            warn!("Failed to ban {}: {}", addr, e);
+            return true;
+        }
+        let metrics = self.metrics.read().await;
+        metrics.peers_banned.inc();
+        true
+    }
+
+    // Inputs missing from the tx cache are first looked up on the prevout source, off the
+    // event loop; `prevouts_fetched` picks the tx up again. A tx whose parent is still being
+    // looked up queues behind it, so parents are always announced first.
+    async fn accept_tx(&self, from_addr: SocketAddr, tx: Transaction, txid: Txid, wtxid: Wtxid) {
+        if let Some(prevouts) = &self.prevouts
+            && !tx.is_coinbase()
+        {
+            let (unknown, behind_parent) = {
+                let relay_state = self.relay_state.read().await;
+                let orphans = self.orphans.read().await;
+                let pending = self.pending_lookups.read().await;
+                let unknown: Vec<OutPoint> = tx
+                    .input
+                    .iter()
+                    .map(|input| input.previous_output)
+                    .filter(|outpoint| {
+                        !orphans.contains(&outpoint.txid)
+                            && !relay_state.is_seen(TxKey::Txid(outpoint.txid))
+                            && prevouts.cached(outpoint).is_none()
+                    })
+                    .collect();
+                let behind_parent = tx
+                    .input
+                    .iter()
+                    .any(|input| pending.contains(&input.previous_output.txid));
+                (unknown, behind_parent)
+            };
+            if !unknown.is_empty() || behind_parent {
+                self.pending_lookups.write().await.insert(txid);
+                let pending = PendingTx {
+                    from: from_addr,
+                    tx,
+                    txid,
+                    wtxid,
+                };
+                prevouts.lookup(pending, unknown);
+                return;
+            }
+        }
+        self.accept_looked_up(from_addr, tx, txid, wtxid, &[], None)
+            .await;
+    }
+
+    async fn prevouts_fetched(&self, lookup: Lookup<PendingTx>) {
+        let PendingTx {
+            from,
+            tx,
+            txid,
+            wtxid,
+        } = lookup.context;
+        self.pending_lookups.write().await.remove(&txid);
+        self.accept_looked_up(from, tx, txid, wtxid, &lookup.outpoints, lookup.values.as_deref())
+            .await;
+    }
+
+    // Relays `tx` once every parent it spends is known. Otherwise it waits in the orphan
+    // pool while the missing parents are requested from the peer that sent it.
+    async fn accept_looked_up(
+        &self,
+        from_addr: SocketAddr,
+        tx: Transaction,
+        txid: Txid,
+        wtxid: Wtxid,
+        looked_up: &[OutPoint],
+        values: Option<&[Option<Amount>]>,
+    ) {
+        let missing = self.missing_parents(&tx, looked_up, values).await;
+        if missing.is_empty() {
+            self.relay_with_descendants(from_addr, tx, txid, wtxid).await;
+            return;
+        }
+
+        // Parents are tracked as if the sender had announced them, so other announcers of
+        // the same parent remain fallbacks. Seen parents, orphans included, are skipped.
+        let package_relay = self
+            .find_peer(from_addr)
+            .await
+            .is_some_and(|peer| peer.package_relay());
+        let requested_parents = {
+            let mut relay_state = self.relay_state.write().await;
+            let now = Instant::now();
+            let delay = if package_relay {
+                PACKAGE_REQUEST_GRACE
+            } else {
+                Duration::ZERO
+            };
+            let mut requested = 0u64;
+            for parent in &missing {
+                let key = TxKey::Txid(*parent);
+                if !relay_state.is_seen(key) {
+                    relay_state
+                        .requests
+                        .received_inv(key, from_addr, true, delay, now);
+                    requested += 1;
+                }
+            }
+            requested
+        };
+
+        debug!(
+            "Holding orphan {} from {} until {} parents arrive",
+            txid,
+            from_addr,
+            missing.len()
+        );
+        let (dropped, orphan_pool_size) = {
+            let mut orphans = self.orphans.write().await;
+            let dropped = orphans.add(tx, from_addr, missing, Instant::now());
+            (dropped, orphans.len())
+        };
+
+        {
+            let metrics = self.metrics.read().await;
+            metrics.orphan_parent_requests.inc_by(requested_parents);
+            metrics.orphan_pool_size.set(orphan_pool_size as i64);
+            for reason in dropped {
+                metrics
+                    .orphans_dropped
+                    .with_label_values(&[reason.as_str()])
+                    .inc();
+            }
+        }
+
+        if requested_parents == 0 {
+            return;
+        }
+        if package_relay {
+            // BIP331 peers can hand over the whole ancestor package in one round trip.
+            {
+                let metrics = self.metrics.read().await;
+                metrics.packages_requested.inc();
+            }
+            let request = Inventory::Unknown {
+                inv_type: MSG_ANCPKGINFO,
+                hash: wtxid.to_byte_array(),
+            };
+            self.send_to_peer(from_addr, Message::GetData(vec![request]))
+                .await;
+        }
+    }
+
+    // Relays orphans whose missing parent nobody could deliver.
+    async fn release_orphans(&self, ready: Vec<Orphan>) {
+        if !ready.is_empty() {
+            let metrics = self.metrics.read().await;
+            metrics.orphans_released.inc_by(ready.len() as u64);
+        }
+        for orphan in ready {
+            self.relay_with_descendants(orphan.from, orphan.tx, orphan.txid, orphan.wtxid)
+                .await;
+        }
+    }
+
+    // Sends one getdata per peer for every tracked tx whose next announcer is due. Runs on
+    // the TX_REQUEST_TICK only, as it scans every tracked tx.
+    async fn send_due_tx_requests(&self) {
+        let (due, in_flight, tracked) = {
+            let mut relay_state = self.relay_state.write().await;
+            let due = relay_state.requests.requests_due(Instant::now());
+            (
+                due,
+                relay_state.requests.in_flight(),
+                relay_state.requests.tracked(),
+            )
+        };
+
+        let mut sent = 0u64;
+        if !due.requests.is_empty() {
+            let mut timeline = self.timeline.write().await;
+            for (peer, keys) in &due.requests {
+                for key in keys {
+                    timeline.record_request(key.to_byte_array(), *peer);
+                }
+            }
+        }
+        for (peer, keys) in due.requests {
+            sent += keys.len() as u64;
+            let items = keys.into_iter().map(witness_request).collect();
+            self.send_to_peer(peer, Message::GetData(items)).await;
+        }
+
+        {
+            let metrics = self.metrics.read().await;
+            metrics.tx_requests_sent.inc_by(sent);
+            metrics.tx_request_timeouts.inc_by(due.timed_out as u64);
+            metrics.tx_request_fallbacks.inc_by(due.fallbacks as u64);
+            metrics.tx_requests_in_flight.set(in_flight as i64);
+            metrics.tx_requests_tracked.set(tracked as i64);
+        }
+
+        // As with notfound: a parent no announcer delivered was most likely confirmed.
+        let ready = {
+            let mut orphans = self.orphans.write().await;
+            let mut ready = Vec::new();
+            for key in &due.given_up {
+                if let TxKey::Txid(txid) = key {
+                    ready.extend(orphans.resolve_parent(txid));
+                }
+            }
+            ready
+        };
+        self.release_orphans(ready).await;
+    }
+
+    // Relays `tx`, then every orphan it completes, so parents are always queued before
+    // their children.
+    async fn relay_with_descendants(
+        &self,
+        from_addr: SocketAddr,
+        tx: Transaction,
+        txid: Txid,
+        wtxid: Wtxid,
+    ) {
+        let mut ready = VecDeque::from([(from_addr, tx, txid, wtxid)]);
+        let mut released = 0u64;
+
+        while let Some((from_addr, tx, txid, wtxid)) = ready.pop_front() {
+            // Announce to non-Knots peers; they request via getdata.
+            let feerate = self.tx_feerate(&tx).await;
+            self.relay_state
+                .write()
+                .await
+                .insert_tx(txid, tx.clone(), feerate);
+            let announced_to = self.relay_tx(from_addr, txid, wtxid, feerate).await;
+            self.timeline
+                .write()
+                .await
+                .record_relay(txid.to_byte_array(), announced_to);
+
+            let children = self.orphans.write().await.resolve_parent(&txid);
+            released += children.len() as u64;
+            ready.extend(
+                children
+                    .into_iter()
+                    .map(|orphan| (orphan.from, orphan.tx, orphan.txid, orphan.wtxid)),
+            );
+        }
+
+        if released > 0 {
+            let orphan_pool_size = self.orphans.read().await.len();
+            let metrics = self.metrics.read().await;
+            metrics.orphans_released.inc_by(released);
+            metrics.orphan_pool_size.set(orphan_pool_size as i64);
+        }
+    }
+
+    // BIP35: every cached tx the peer's feefilter admits, queued for the peer's next trickle
+    // flush like any other announcement. Answered once per connection.
+    async fn answer_mempool(&self, from_addr: SocketAddr) {
+        let Some(peer) = self.find_peer(from_addr).await else {
+            return;
+        };
+        if !self.mempool_answered.write().await.insert(from_addr) {
+            debug!("Ignoring repeated mempool request from {}", from_addr);
+            return;
+        }
+
+        let announcements = {
+            let relay_state = self.relay_state.read().await;
+            relay_state.cached_announcements(peer.fee_filter())
+        };
+        debug!(
+            "Answering mempool request from {} with {} txs",
+            from_addr,
+            announcements.len()
+        );
+        let mut trickle = self.trickle.write().await;
+        for entry in announcements {
+            trickle.push(from_addr, entry);
+        }
+    }
+
+    // Parents of `tx` that we have not relayed and that the prevout source reported absent
+    // (`values` answers the lookup of `looked_up`). Without a source every parent we have
+    // not relayed counts as missing, as there is nothing else to go on; if the lookup
+    // failed, nothing is assumed missing. Orphaned parents count as missing.
+    async fn missing_parents(
+        &self,
+        tx: &Transaction,
+        looked_up: &[OutPoint],
+        values: Option<&[Option<Amount>]>,
+    ) -> HashSet<Txid> {
+        let mut missing = HashSet::new();
+        if tx.is_coinbase() {
+            return missing;
+        }
+        let absent: HashSet<OutPoint> = looked_up
+            .iter()
+            .zip(values.unwrap_or_default())
+            .filter(|(_, value)| value.is_none())
+            .map(|(outpoint, _)| *outpoint)
+            .collect();
+
+        let relay_state = self.relay_state.read().await;
+        let orphans = self.orphans.read().await;
+        for input in &tx.input {
+            let parent = input.previous_output.txid;
+            let unseen_and_absent = (self.prevouts.is_none()
+                || absent.contains(&input.previous_output))
+                && !relay_state.is_seen(TxKey::Txid(parent));
+            if orphans.contains(&parent) || unseen_and_absent {
+                missing.insert(parent);
+            }
+        }
+        missing
+    }
+
+    // Queues the announcement on each eligible peer's trickle queue; the flush picks wtxid
+    // or txid per peer. Returns the number of peers it was queued for. Peers whose feefilter
+    // is above `feerate` (sat/kvB) are skipped; an unknown feerate is announced to everyone.
+    async fn relay_tx(
+        &self,
+        from_addr: SocketAddr,
+        txid: Txid,
+        wtxid: Wtxid,
+        feerate: Option<u64>,
+    ) -> usize {
+        let peers = { self.peers.read().await.clone() };
+        let entry = QueuedInv {
+            txid,
+            wtxid,
+            feerate,
+            depth: self.relay_state.read().await.tx_depth(&txid),
+        };
+        let mut trickle = self.trickle.write().await;
+        let mut announced = 0;
+        let mut suppressed = 0;
+
+        for peer in peers {
+            // Don't relay back to sender
+            if peer.addr() == from_addr {
+                continue;
+            }
+
             // Don't relay to Knots
             if peer.node_type() == NodeType::Knots {
                 continue;
             }
 
-            if !self.send_to_peer_handle(&peer, msg.clone()) {
-                stale.push(peer.addr());
+            if let Some(feerate) = feerate
+                && feerate < peer.fee_filter()
+            {
+                suppressed += 1;
+                continue;
             }
+
+            trickle.push(peer.addr(), entry);
+            announced += 1;
         }
+        drop(trickle);
 
-        if !stale.is_empty() {
-            self.prune_stale_peers(stale).await;
+        if suppressed > 0 {
+            let metrics = self.metrics.read().await;
+            metrics.feefilter_suppressed_announcements.inc_by(suppressed);
         }
+        announced
+    }
+
+    // Fee per 1000 vbytes, from cached parents and values the prevout source already found.
+    async fn tx_feerate(&self, tx: &Transaction) -> Option<u64> {
+        if tx.is_coinbase() {
+            return None;
+        }
+
+        let input_total = {
+            let relay_state = self.relay_state.read().await;
+            tx.input.iter().try_fold(Amount::ZERO, |total, input| {
+                let outpoint = &input.previous_output;
+                let value = relay_state.get_output_value(outpoint).or_else(|| {
+                    self.prevouts
+                        .as_ref()
+                        .and_then(|prevouts| prevouts.cached(outpoint))
+                })?;
+                total.checked_add(value)
+            })?
+        };
+        let output_total = tx
+            .output
+            .iter()
+            .try_fold(Amount::ZERO, |total, output| total.checked_add(output.value))?;
+        let fee = input_total.checked_sub(output_total)?;
+        let vsize = tx.vsize().max(1) as u64;
+        Some(fee.to_sat().saturating_mul(1000) / vsize)
     }
 
     async fn send_to_peer(&self, addr: SocketAddr, msg: Message) -> bool {
@@ -571,6 +2376,11 @@ impl PeerManager {
         false
     }
 
+    async fn find_peer(&self, addr: SocketAddr) -> Option<PeerHandle> {
+        let peers = self.peers.read().await;
+        peers.iter().find(|peer| peer.addr() == addr).cloned()
+    }
+
     async fn peer_node_type(&self, addr: SocketAddr) -> NodeType {
         let peers = self.peers.read().await;
         peers
@@ -631,17 +2441,209 @@ impl PeerManager {
             }
         }
 
+        let (ipv4, ipv6) = count_by_family(peers.iter().map(PeerHandle::addr));
+        let inbound = peers.iter().filter(|peer| peer.is_inbound()).count();
+
         let metrics = self.metrics.read().await;
+        metrics.update_direction_counts(inbound as i64, (peers.len() - inbound) as i64);
         metrics.update_peer_counts(knots, core, libre, other);
+        metrics.update_family_counts(ipv4 as i64, ipv6 as i64);
         metrics.update_unclassified_agent_peers(&unclassified_agents);
     }
 }
 
-fn inventory_key(inv: &Inventory) -> Option<[u8; 32]> {
+// Runtime controls over a running `PeerManager`, used by the admin API.
+#[derive(Clone)]
+pub struct ManagerHandle {
+    db: Arc<AddressDb>,
+    peers: Arc<RwLock<Vec<PeerHandle>>>,
+    manual_peers: Arc<RwLock<Vec<DialTarget>>>,
+    target_peers: Arc<AtomicUsize>,
+    timeline: Arc<RwLock<TxTimeline>>,
+    network: Network,
+}
+
+impl ManagerHandle {
+    pub fn db(&self) -> &AddressDb {
+        &self.db
+    }
+
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
+    pub async fn peers(&self) -> Vec<PeerHandle> {
+        self.peers.read().await.clone()
+    }
+
+    // Looks up by txid or wtxid.
+    pub async fn tx_record(&self, key: &[u8; 32]) -> Option<TxRecord> {
+        self.timeline.read().await.get(key).cloned()
+    }
+
+    // Also forgets a matching manual peer, otherwise the next refill would redial it.
+    pub async fn disconnect_peer(&self, addr: SocketAddr) -> bool {
+        self.manual_peers
+            .write()
+            .await
+            .retain(|target| target.peer_addr() != addr);
+
+        let peers = self.peers.read().await;
+        match peers.iter().find(|peer| peer.addr() == addr) {
+            Some(peer) => {
+                peer.disconnect();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub async fn add_manual_peer(&self, target: DialTarget) {
+        let mut manual = self.manual_peers.write().await;
+        if !manual.contains(&target) {
+            manual.push(target);
+        }
+    }
+
+    pub fn target_peers(&self) -> usize {
+        self.target_peers.load(Ordering::Relaxed)
+    }
+
+    pub fn set_target_peers(&self, target_peers: usize) {
+        self.target_peers.store(target_peers, Ordering::Relaxed);
+    }
+}
+
+// Next prevout lookup answer; never resolves without a prevout source.
+async fn next_lookup(
+    results: &mut Option<mpsc::UnboundedReceiver<Lookup<PendingTx>>>,
+) -> Option<Lookup<PendingTx>> {
+    match results {
+        Some(results) => results.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn inventory_key(inv: &Inventory) -> Option<TxKey> {
     match inv {
-        Inventory::Transaction(txid) => Some(txid.to_byte_array()),
-        Inventory::WTx(wtxid) => Some(wtxid.to_byte_array()),
-        Inventory::WitnessTransaction(wtxid) => Some(wtxid.to_byte_array()),
+        Inventory::Transaction(txid) => Some(TxKey::Txid(*txid)),
+        Inventory::WitnessTransaction(txid) => Some(TxKey::Txid(*txid)),
+        Inventory::WTx(wtxid) => Some(TxKey::Wtxid(*wtxid)),
         _ => None,
     }
 }
+
+// A recent block in the form a getdata asked for.
+fn block_response(inv: &Inventory, block: &Block) -> Message {
+    match inv {
+        Inventory::Block(_) => Message::Block(Block {
+            header: block.header,
+            txdata: block.txdata.iter().cloned().map(strip_witness).collect(),
+        }),
+        Inventory::CompactBlock(_) => HeaderAndShortIds::from_block(
+            block,
+            rand::random(),
+            COMPACT_BLOCK_VERSION as u32,
+            &[],
+        )
+        .map_or_else(|_| Message::Block(block.clone()), Message::CmpctBlock),
+        _ => Message::Block(block.clone()),
+    }
+}
+
+// MSG_TX announcements are fetched as MSG_WITNESS_TX; MSG_WTX already implies witness data.
+fn witness_request(key: TxKey) -> Inventory {
+    match key {
+        TxKey::Txid(txid) => Inventory::WitnessTransaction(txid),
+        TxKey::Wtxid(wtxid) => Inventory::WTx(wtxid),
+    }
+}
+
+// Without any witness, an input with an empty scriptSig or a scriptSig that only pushes a
+// witness program (P2SH-wrapped segwit) can only be a segwit spend whose witness was dropped.
+fn is_witness_stripped(tx: &Transaction) -> bool {
+    if tx.input.iter().any(|input| !input.witness.is_empty()) {
+        return false;
+    }
+    tx.input.iter().any(|input| {
+        if input.script_sig.is_empty() {
+            return true;
+        }
+        let mut instructions = input.script_sig.instructions();
+        match (instructions.next(), instructions.next()) {
+            (Some(Ok(Instruction::PushBytes(push))), None) => {
+                ScriptBuf::from_bytes(push.as_bytes().to_vec()).is_witness_program()
+            }
+            _ => false,
+        }
+    })
+}
+
+fn strip_witness(mut tx: Transaction) -> Transaction {
+    for input in &mut tx.input {
+        input.witness.clear();
+    }
+    tx
+}
+
+// Makes room for a new inbound connection if the inbound slots are full, returning the peer
+// evicted for it, or the reason it is turned away. Handshakes in progress hold a slot;
+// `evicting` remembers peers already told to go until they are gone.
+async fn admit_inbound(
+    peers: &RwLock<Vec<PeerHandle>>,
+    pending: usize,
+    max_inbound: usize,
+    evicting: &mut HashSet<SocketAddr>,
+    key: &RandomState,
+) -> Result<Option<SocketAddr>, &'static str> {
+    if pending >= max_inbound {
+        return Err("pending_handshakes");
+    }
+    let peers = peers.read().await;
+    evicting.retain(|addr| peers.iter().any(|peer| peer.addr() == *addr));
+    let inbound: Vec<&PeerHandle> = peers
+        .iter()
+        .filter(|peer| peer.is_inbound() && !evicting.contains(&peer.addr()))
+        .collect();
+    if inbound.len() + pending < max_inbound {
+        return Ok(None);
+    }
+
+    let candidates = inbound.iter().map(|peer| EvictionCandidate::from(*peer)).collect();
+    let Some(evicted) = eviction::select_peer_to_evict(candidates, key) else {
+        return Err("all_protected");
+    };
+    if let Some(peer) = inbound.iter().find(|peer| peer.addr() == evicted) {
+        peer.disconnect();
+    }
+    evicting.insert(evicted);
+    Ok(Some(evicted))
+}
+
+// Hostname peers live in the internal range and belong to neither family.
+fn count_by_family(addrs: impl Iterator<Item = SocketAddr>) -> (usize, usize) {
+    let mut ipv4 = 0;
+    let mut ipv6 = 0;
+    for addr in addrs {
+        if dialer::is_internal_addr(addr) {
+            continue;
+        }
+        match addr.ip().to_canonical() {
+            IpAddr::V4(_) => ipv4 += 1,
+            IpAddr::V6(_) => ipv6 += 1,
+        }
+    }
+    (ipv4, ipv6)
+}
+
+fn bind_listener(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
@@ -1,20 +1,34 @@
-use crate::db::NodeType;
+use crate::api;
+use crate::classify::TxCategory;
+use crate::db::{AddressDb, NodeType};
+use crate::manager::ManagerHandle;
+use crate::p2p::address::AddrNetwork;
+use crate::report::{self, MinedTx};
 use axum::{Router, routing::get};
 use prometheus::{
-    Encoder, Histogram, IntCounter, IntGauge, IntGaugeVec, TextEncoder, register_histogram,
-    register_int_counter, register_int_gauge, register_int_gauge_vec,
+    Encoder, Histogram, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    TextEncoder, register_histogram, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
 };
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::RwLock;
 use tracing::error;
 
 #[derive(Clone)]
 pub struct Metrics {
     pub connected_peers: IntGauge,
+    pub inbound_peers: IntGauge,
+    pub outbound_peers: IntGauge,
+    pub inbound_evictions: IntCounterVec,
+    pub misbehavior: IntCounterVec,
+    pub peers_banned: IntCounter,
+    pub banned_inbound_refused: IntCounter,
     pub total_connections: IntCounter,
     pub total_disconnections: IntCounter,
+    pub v2_transport_connections: IntCounter,
     pub transactions_relayed: IntCounter,
     pub transactions_received: IntCounter,
     pub transactions_received_from_knots: IntCounter,
@@ -22,6 +36,8 @@ pub struct Metrics {
     pub transactions_received_from_libre: IntCounter,
     pub transactions_received_from_other: IntCounter,
     pub transactions_received_from_unknown: IntCounter,
+    pub transactions_by_category: IntCounterVec,
+    pub transactions_by_category_and_source: IntCounterVec,
     pub unclassified_agent_peers: IntGaugeVec,
     pub inv_messages_received: IntCounter,
     pub addr_messages_received: IntCounter,
@@ -29,25 +45,100 @@ pub struct Metrics {
     pub getaddr_messages_received: IntCounter,
     pub sendaddrv2_messages_received: IntCounter,
     pub wtxidrelay_messages_received: IntCounter,
+    pub witness_malleated_txs: IntCounter,
+    pub stripped_txs_rejected: IntCounter,
+    pub txs_rejected: IntCounterVec,
+    pub invalid_tx_disconnects: IntCounter,
     pub feefilter_messages_received: IntCounter,
+    pub mempool_requests_received: IntCounter,
+    pub notfound_items_sent: IntCounter,
     pub feefilter_sat_per_kvb: Histogram,
+    pub feefilter_suppressed_announcements: IntCounter,
+    pub inv_queue_depth: IntGauge,
+    pub tx_requests_sent: IntCounter,
+    pub tx_request_timeouts: IntCounter,
+    pub tx_request_fallbacks: IntCounter,
+    pub tx_requests_in_flight: IntGauge,
+    pub tx_requests_tracked: IntGauge,
+    pub header_tip_height: IntGauge,
+    pub header_tip_age_seconds: IntGauge,
+    pub headers_rejected: IntCounterVec,
+    pub compact_blocks_reconstructed: IntCounter,
+    pub block_txs_cached: IntCounter,
+    pub block_txs_missing: IntCounter,
+    pub compact_block_cache_hit_ratio: Histogram,
+    pub compact_blocks_forwarded: IntCounter,
+    pub orphan_pool_size: IntGauge,
+    pub orphan_parent_requests: IntCounter,
+    pub orphans_released: IntCounter,
+    pub orphans_dropped: IntCounterVec,
+    pub packages_requested: IntCounter,
+    pub packages_received: IntCounter,
+    pub packages_served: IntCounter,
+    pub inv_batch_size: Histogram,
     pub knots_peers: IntGauge,
     pub core_peers: IntGauge,
     pub libre_peers: IntGauge,
     pub other_peers: IntGauge,
+    pub ipv4_peers: IntGauge,
+    pub ipv6_peers: IntGauge,
     pub discovery_runs: IntCounter,
     pub nodes_discovered: IntCounter,
     pub nodes_pruned: IntCounter,
+    pub known_nodes: IntGaugeVec,
+    pub tx_propagation_delay_seconds: HistogramVec,
+    pub block_announcement_delay_seconds: HistogramVec,
+    pub block_first_announcements: IntCounterVec,
+    pub ping_rtt_seconds: HistogramVec,
+    pub mined_txs: IntCounter,
+    pub mined_txs_received: IntCounter,
+    pub mined_txs_relayed: IntCounter,
+    pub mined_txs_by_first_sender: IntCounterVec,
+    pub mined_txs_never_announced_by_knots: IntCounter,
+    pub mined_txs_by_category: IntCounterVec,
+    pub mined_category_txs_never_announced_by_knots: IntCounterVec,
 }
 
 impl Metrics {
     pub fn new() -> Self {
-        Self {
+        let metrics = Self {
             connected_peers: register_int_gauge!(
                 "crab_router_connected_peers",
                 "Number of currently connected peers"
             )
             .unwrap(),
+            inbound_peers: register_int_gauge!(
+                "crab_router_inbound_peers",
+                "Connected peers that dialed us"
+            )
+            .unwrap(),
+            outbound_peers: register_int_gauge!(
+                "crab_router_outbound_peers",
+                "Connected peers we dialed"
+            )
+            .unwrap(),
+            inbound_evictions: register_int_counter_vec!(
+                "crab_router_inbound_evictions",
+                "Inbound connections dropped because inbound slots were full, by reason",
+                &["reason"]
+            )
+            .unwrap(),
+            misbehavior: register_int_counter_vec!(
+                "crab_router_misbehavior",
+                "Peer misbehavior counted towards a ban, by reason",
+                &["reason"]
+            )
+            .unwrap(),
+            peers_banned: register_int_counter!(
+                "crab_router_peers_banned",
+                "Peers disconnected and banned for misbehaving"
+            )
+            .unwrap(),
+            banned_inbound_refused: register_int_counter!(
+                "crab_router_banned_inbound_refused",
+                "Inbound connections refused because the IP is banned"
+            )
+            .unwrap(),
             total_connections: register_int_counter!(
                 "crab_router_total_connections",
                 "Total number of peer connections made"
@@ -58,6 +149,11 @@ impl Metrics {
                 "Total number of peer disconnections"
             )
             .unwrap(),
+            v2_transport_connections: register_int_counter!(
+                "crab_router_v2_transport_connections",
+                "Total number of peer connections using BIP324 v2 transport"
+            )
+            .unwrap(),
             transactions_relayed: register_int_counter!(
                 "crab_router_transactions_relayed",
                 "Total number of transactions relayed to peers"
@@ -93,6 +189,18 @@ impl Metrics {
                 "Total number of transactions received from unknown peers"
             )
             .unwrap(),
+            transactions_by_category: register_int_counter_vec!(
+                "crab_router_transactions_by_category",
+                "Transactions received by content category",
+                &["category"]
+            )
+            .unwrap(),
+            transactions_by_category_and_source: register_int_counter_vec!(
+                "crab_router_transactions_by_category_and_source",
+                "Transactions received by content category and source node type",
+                &["category", "node_type"]
+            )
+            .unwrap(),
             unclassified_agent_peers: register_int_gauge_vec!(
                 "crab_router_unclassified_agent_peers",
                 "Number of currently connected peers by unclassified user agent",
@@ -129,11 +237,42 @@ impl Metrics {
                 "Total number of wtxidrelay messages received"
             )
             .unwrap(),
+            witness_malleated_txs: register_int_counter!(
+                "crab_router_witness_malleated_txs",
+                "Transactions received with a known txid but a new wtxid (not relayed)"
+            )
+            .unwrap(),
+            stripped_txs_rejected: register_int_counter!(
+                "crab_router_stripped_txs_rejected",
+                "Segwit transactions received without their witness data"
+            )
+            .unwrap(),
+            txs_rejected: register_int_counter_vec!(
+                "crab_router_txs_rejected",
+                "Transactions rejected by sanity or policy checks, by reason",
+                &["reason"]
+            )
+            .unwrap(),
+            invalid_tx_disconnects: register_int_counter!(
+                "crab_router_invalid_tx_disconnects",
+                "Peers disconnected for sending consensus-invalid transactions"
+            )
+            .unwrap(),
             feefilter_messages_received: register_int_counter!(
                 "crab_router_feefilter_messages_received",
                 "Total number of feefilter messages received"
             )
             .unwrap(),
+            mempool_requests_received: register_int_counter!(
+                "crab_router_mempool_requests_received",
+                "Total number of BIP35 mempool messages received"
+            )
+            .unwrap(),
+            notfound_items_sent: register_int_counter!(
+                "crab_router_notfound_items_sent",
+                "Requested txs we did not have, reported back in notfound messages"
+            )
+            .unwrap(),
             feefilter_sat_per_kvb: register_histogram!(
                 "crab_router_feefilter_sat_per_kvb",
                 "Observed feefilter values (satoshis per 1000 bytes)",
@@ -156,6 +295,127 @@ impl Metrics {
                 ]
             )
             .unwrap(),
+            feefilter_suppressed_announcements: register_int_counter!(
+                "crab_router_feefilter_suppressed_announcements",
+                "Tx announcements skipped because the tx feerate was below the peer's feefilter"
+            )
+            .unwrap(),
+            inv_queue_depth: register_int_gauge!(
+                "crab_router_inv_queue_depth",
+                "Tx announcements waiting in per-peer trickle queues"
+            )
+            .unwrap(),
+            inv_batch_size: register_histogram!(
+                "crab_router_inv_batch_size",
+                "Number of tx announcements per trickled inv message",
+                vec![
+                    1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1_000.0, 5_000.0
+                ]
+            )
+            .unwrap(),
+            tx_requests_sent: register_int_counter!(
+                "crab_router_tx_requests_sent",
+                "Txs requested via getdata from an announcing peer"
+            )
+            .unwrap(),
+            tx_request_timeouts: register_int_counter!(
+                "crab_router_tx_request_timeouts",
+                "Tx requests the peer did not answer in time"
+            )
+            .unwrap(),
+            tx_request_fallbacks: register_int_counter!(
+                "crab_router_tx_request_fallbacks",
+                "Tx requests sent to another announcer after an earlier one failed"
+            )
+            .unwrap(),
+            tx_requests_in_flight: register_int_gauge!(
+                "crab_router_tx_requests_in_flight",
+                "Tx requests currently awaiting an answer"
+            )
+            .unwrap(),
+            tx_requests_tracked: register_int_gauge!(
+                "crab_router_tx_requests_tracked",
+                "Announced txs we still want, with at least one announcer left to ask"
+            )
+            .unwrap(),
+            header_tip_height: register_int_gauge!(
+                "crab_router_header_tip_height",
+                "Height of the best validated header chain"
+            )
+            .unwrap(),
+            header_tip_age_seconds: register_int_gauge!(
+                "crab_router_header_tip_age_seconds",
+                "Seconds since the timestamp of the best header"
+            )
+            .unwrap(),
+            headers_rejected: register_int_counter_vec!(
+                "crab_router_headers_rejected",
+                "Headers messages that failed validation, by reason",
+                &["reason"]
+            )
+            .unwrap(),
+            compact_blocks_reconstructed: register_int_counter!(
+                "crab_router_compact_blocks_reconstructed",
+                "Blocks rebuilt from a compact block and our relayed txs"
+            )
+            .unwrap(),
+            block_txs_cached: register_int_counter!(
+                "crab_router_block_txs_cached",
+                "Compact block txs we already had from relay"
+            )
+            .unwrap(),
+            block_txs_missing: register_int_counter!(
+                "crab_router_block_txs_missing",
+                "Compact block txs we had to request with getblocktxn"
+            )
+            .unwrap(),
+            compact_block_cache_hit_ratio: register_histogram!(
+                "crab_router_compact_block_cache_hit_ratio",
+                "Share of each compact block's txs found in our relay cache",
+                vec![0.0, 0.5, 0.75, 0.9, 0.95, 0.99, 1.0]
+            )
+            .unwrap(),
+            compact_blocks_forwarded: register_int_counter!(
+                "crab_router_compact_blocks_forwarded",
+                "Compact blocks pushed to high-bandwidth peers"
+            )
+            .unwrap(),
+            orphan_pool_size: register_int_gauge!(
+                "crab_router_orphan_pool_size",
+                "Transactions held back until their parents arrive"
+            )
+            .unwrap(),
+            orphan_parent_requests: register_int_counter!(
+                "crab_router_orphan_parent_requests",
+                "Missing parents requested from the peer that sent an orphan"
+            )
+            .unwrap(),
+            orphans_released: register_int_counter!(
+                "crab_router_orphans_released",
+                "Orphans relayed once their parents arrived or were reported notfound"
+            )
+            .unwrap(),
+            orphans_dropped: register_int_counter_vec!(
+                "crab_router_orphans_dropped",
+                "Orphans dropped without being relayed, by reason",
+                &["reason"]
+            )
+            .unwrap(),
+            packages_requested: register_int_counter!(
+                "crab_router_packages_requested",
+                "BIP331 ancestor package infos requested for orphans"
+            )
+            .unwrap(),
+            packages_received: register_int_counter!(
+                "crab_router_packages_received",
+                "BIP331 pkgtxns messages received"
+            )
+            .unwrap(),
+            packages_served: register_int_counter!(
+                "crab_router_packages_served",
+                "BIP331 pkgtxns messages sent in reply to getpkgtxns"
+            )
+            .unwrap(),
             knots_peers: register_int_gauge!(
                 "crab_router_knots_peers",
                 "Number of Knots peers currently connected"
@@ -176,6 +436,16 @@ impl Metrics {
                 "Number of other peers currently connected"
             )
             .unwrap(),
+            ipv4_peers: register_int_gauge!(
+                "crab_router_ipv4_peers",
+                "Number of IPv4 peers currently connected"
+            )
+            .unwrap(),
+            ipv6_peers: register_int_gauge!(
+                "crab_router_ipv6_peers",
+                "Number of IPv6 peers currently connected"
+            )
+            .unwrap(),
             discovery_runs: register_int_counter!(
                 "crab_router_discovery_runs",
                 "Number of discovery cycles run"
@@ -191,7 +461,94 @@ impl Metrics {
                 "Total number of nodes pruned from database"
             )
             .unwrap(),
+            known_nodes: register_int_gauge_vec!(
+                "crab_router_known_nodes",
+                "Number of nodes in the address database by network",
+                &["network"]
+            )
+            .unwrap(),
+            tx_propagation_delay_seconds: register_histogram_vec!(
+                "crab_router_tx_propagation_delay_seconds",
+                "Delay between the first announcement of a tx and each peer's announcement",
+                &["node_type"],
+                vec![
+                    0.0, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 30.0, 60.0, 120.0
+                ]
+            )
+            .unwrap(),
+            block_announcement_delay_seconds: register_histogram_vec!(
+                "crab_router_block_announcement_delay_seconds",
+                "Delay between the first announcement of a block and each peer's announcement",
+                &["node_type"],
+                vec![0.0, 0.05, 0.1, 0.25, 0.5, 1.0, 2.0, 5.0, 10.0, 30.0, 60.0]
+            )
+            .unwrap(),
+            block_first_announcements: register_int_counter_vec!(
+                "crab_router_block_first_announcements",
+                "Blocks a peer of each type announced to us before anyone else",
+                &["node_type"]
+            )
+            .unwrap(),
+            ping_rtt_seconds: register_histogram_vec!(
+                "crab_router_ping_rtt_seconds",
+                "Round trip of keepalive pings, by peer type",
+                &["node_type"],
+                vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+            )
+            .unwrap(),
+            mined_txs: register_int_counter!(
+                "crab_router_mined_txs",
+                "Confirmed txs (coinbases aside) matched against our relay log"
+            )
+            .unwrap(),
+            mined_txs_received: register_int_counter!(
+                "crab_router_mined_txs_received",
+                "Confirmed txs that reached us before their block"
+            )
+            .unwrap(),
+            mined_txs_relayed: register_int_counter!(
+                "crab_router_mined_txs_relayed",
+                "Confirmed txs we relayed before their block"
+            )
+            .unwrap(),
+            mined_txs_by_first_sender: register_int_counter_vec!(
+                "crab_router_mined_txs_by_first_sender",
+                "Confirmed txs by the type of peer that first sent them to us",
+                &["node_type"]
+            )
+            .unwrap(),
+            mined_txs_never_announced_by_knots: register_int_counter!(
+                "crab_router_mined_txs_never_announced_by_knots",
+                "Confirmed txs still in the relay log that no Knots peer announced to us"
+            )
+            .unwrap(),
+            mined_txs_by_category: register_int_counter_vec!(
+                "crab_router_mined_txs_by_category",
+                "Confirmed txs by content category",
+                &["category"]
+            )
+            .unwrap(),
+            mined_category_txs_never_announced_by_knots: register_int_counter_vec!(
+                "crab_router_mined_category_txs_never_announced_by_knots",
+                "Tracked confirmed txs of each content category no Knots peer announced to us",
+                &["category"]
+            )
+            .unwrap(),
+        };
+
+        // Export every category from the start so rate() works before the first match.
+        for category in TxCategory::ALL {
+            metrics
+                .transactions_by_category
+                .with_label_values(&[category.as_str()]);
+            metrics
+                .mined_txs_by_category
+                .with_label_values(&[category.as_str()]);
+            metrics
+                .mined_category_txs_never_announced_by_knots
+                .with_label_values(&[category.as_str()]);
         }
+        metrics
     }
 
     pub fn update_peer_counts(&self, knots: i64, core: i64, libre: i64, other: i64) {
@@ -202,6 +559,28 @@ impl Metrics {
         self.connected_peers.set(knots + core + libre + other);
     }
 
+    pub fn update_direction_counts(&self, inbound: i64, outbound: i64) {
+        self.inbound_peers.set(inbound);
+        self.outbound_peers.set(outbound);
+    }
+
+    pub fn update_family_counts(&self, ipv4: i64, ipv6: i64) {
+        self.ipv4_peers.set(ipv4);
+        self.ipv6_peers.set(ipv6);
+    }
+
+    pub fn update_known_nodes(&self, counts: &[(AddrNetwork, i64)]) {
+        for network in AddrNetwork::ALL {
+            let count = counts
+                .iter()
+                .find(|(n, _)| *n == network)
+                .map_or(0, |(_, count)| *count);
+            self.known_nodes
+                .with_label_values(&[network.as_str()])
+                .set(count);
+        }
+    }
+
     pub fn inc_transactions_received_from(&self, node_type: NodeType) {
         match node_type {
             NodeType::Knots => self.transactions_received_from_knots.inc(),
@@ -212,6 +591,74 @@ impl Metrics {
         }
     }
 
+    pub fn inc_transaction_categories(&self, categories: &[TxCategory], node_type: NodeType) {
+        for category in categories {
+            self.transactions_by_category
+                .with_label_values(&[category.as_str()])
+                .inc();
+            self.transactions_by_category_and_source
+                .with_label_values(&[category.as_str(), node_type.as_str()])
+                .inc();
+        }
+    }
+
+    pub fn observe_tx_propagation_delay(&self, node_type: NodeType, delay: Duration) {
+        self.tx_propagation_delay_seconds
+            .with_label_values(&[node_type.as_str()])
+            .observe(delay.as_secs_f64());
+    }
+
+    // `position` is 0 for the first announcer of the block.
+    pub fn observe_block_announcement(
+        &self,
+        node_type: NodeType,
+        position: usize,
+        delay: Duration,
+    ) {
+        self.block_announcement_delay_seconds
+            .with_label_values(&[node_type.as_str()])
+            .observe(delay.as_secs_f64());
+        if position == 0 {
+            self.block_first_announcements
+                .with_label_values(&[node_type.as_str()])
+                .inc();
+        }
+    }
+
+    pub fn observe_ping(&self, node_type: NodeType, rtt: Duration) {
+        self.ping_rtt_seconds
+            .with_label_values(&[node_type.as_str()])
+            .observe(rtt.as_secs_f64());
+    }
+
+    pub fn observe_mined_tx(&self, tx: &MinedTx) {
+        self.mined_txs.inc();
+        if tx.received {
+            self.mined_txs_received.inc();
+        }
+        if tx.relayed {
+            self.mined_txs_relayed.inc();
+        }
+        if let Some(node_type) = tx.first_sender {
+            self.mined_txs_by_first_sender
+                .with_label_values(&[node_type.as_str()])
+                .inc();
+        }
+        if tx.knots_announced == Some(false) {
+            self.mined_txs_never_announced_by_knots.inc();
+        }
+        for category in &tx.categories {
+            self.mined_txs_by_category
+                .with_label_values(&[category.as_str()])
+                .inc();
+            if tx.knots_announced == Some(false) {
+                self.mined_category_txs_never_announced_by_knots
+                    .with_label_values(&[category.as_str()])
+                    .inc();
+            }
+        }
+    }
+
     pub fn update_unclassified_agent_peers(&self, counts: &HashMap<String, i64>) {
         self.unclassified_agent_peers.reset();
         for (agent, count) in counts {
@@ -222,8 +669,16 @@ impl Metrics {
     }
 }
 
-pub async fn serve_metrics(addr: SocketAddr, _metrics: Arc<RwLock<Metrics>>) {
-    let app = Router::new().route("/metrics", get(metrics_handler));
+pub async fn serve_metrics(
+    addr: SocketAddr,
+    _metrics: Arc<RwLock<Metrics>>,
+    db: Arc<AddressDb>,
+    admin: Option<ManagerHandle>,
+) {
+    let mut app = Router::new().route("/metrics", get(metrics_handler));
+    if let Some(manager) = admin {
+        app = app.merge(api::router(manager)).merge(report::router(db));
+    }
 
     match tokio::net::TcpListener::bind(addr).await {
         Ok(listener) => {
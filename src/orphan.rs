@@ -0,0 +1,162 @@
+use bitcoin::{Transaction, Txid, Wtxid};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Bitcoin Core's DEFAULT_MAX_ORPHAN_TRANSACTIONS and ORPHAN_TX_EXPIRE_TIME.
+const MAX_ORPHAN_TXS: usize = 100;
+const ORPHAN_TX_EXPIRE: Duration = Duration::from_secs(20 * 60);
+// Core refuses to keep orphans above MAX_STANDARD_TX_WEIGHT.
+const MAX_ORPHAN_TX_WEIGHT: u64 = 400_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanDrop {
+    Oversize,
+    Expired,
+    Evicted,
+    PeerDisconnected,
+}
+
+impl OrphanDrop {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OrphanDrop::Oversize => "oversize",
+            OrphanDrop::Expired => "expired",
+            OrphanDrop::Evicted => "evicted",
+            OrphanDrop::PeerDisconnected => "peer_disconnected",
+        }
+    }
+}
+
+pub struct Orphan {
+    pub tx: Transaction,
+    pub txid: Txid,
+    pub wtxid: Wtxid,
+    // Peer that sent the orphan; its parents are requested from there.
+    pub from: SocketAddr,
+    missing: HashSet<Txid>,
+    added: Instant,
+}
+
+// Transactions held back until the parents they spend have been relayed.
+#[derive(Default)]
+pub struct OrphanPool {
+    orphans: HashMap<Txid, Orphan>,
+    by_parent: HashMap<Txid, HashSet<Txid>>,
+    order: VecDeque<Txid>,
+}
+
+impl OrphanPool {
+    pub fn contains(&self, txid: &Txid) -> bool {
+        self.orphans.contains_key(txid)
+    }
+
+    pub fn contains_wtxid(&self, wtxid: &Wtxid) -> bool {
+        self.orphans.values().any(|orphan| orphan.wtxid == *wtxid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn txs_by_wtxid(&self) -> impl Iterator<Item = (Wtxid, &Transaction)> {
+        self.orphans.values().map(|orphan| (orphan.wtxid, &orphan.tx))
+    }
+
+    // Holds `tx` until every txid in `missing` is resolved. Returns why any txs (possibly
+    // `tx` itself) were dropped instead.
+    pub fn add(
+        &mut self,
+        tx: Transaction,
+        from: SocketAddr,
+        missing: HashSet<Txid>,
+        now: Instant,
+    ) -> Vec<OrphanDrop> {
+        if tx.weight().to_wu() > MAX_ORPHAN_TX_WEIGHT {
+            return vec![OrphanDrop::Oversize];
+        }
+
+        let mut dropped = Vec::new();
+        // `order` is oldest first, so expired orphans are always at the front.
+        while let Some(&oldest) = self.order.front()
+            && now.duration_since(self.orphans[&oldest].added) >= ORPHAN_TX_EXPIRE
+        {
+            self.remove(&oldest);
+            dropped.push(OrphanDrop::Expired);
+        }
+        while self.orphans.len() >= MAX_ORPHAN_TXS
+            && let Some(&oldest) = self.order.front()
+        {
+            self.remove(&oldest);
+            dropped.push(OrphanDrop::Evicted);
+        }
+
+        let txid = tx.compute_txid();
+        for parent in &missing {
+            self.by_parent.entry(*parent).or_default().insert(txid);
+        }
+        self.order.push_back(txid);
+        self.orphans.insert(
+            txid,
+            Orphan {
+                wtxid: tx.compute_wtxid(),
+                tx,
+                txid,
+                from,
+                missing,
+                added: now,
+            },
+        );
+        dropped
+    }
+
+    // Marks `parent` as no longer missing, either because it was relayed or because the
+    // announcer could not serve it. Returns the orphans that now have every parent.
+    pub fn resolve_parent(&mut self, parent: &Txid) -> Vec<Orphan> {
+        let Some(children) = self.by_parent.remove(parent) else {
+            return Vec::new();
+        };
+
+        let mut ready = Vec::new();
+        for child in children {
+            let Some(orphan) = self.orphans.get_mut(&child) else {
+                continue;
+            };
+            orphan.missing.remove(parent);
+            if orphan.missing.is_empty()
+                && let Some(orphan) = self.remove(&child)
+            {
+                ready.push(orphan);
+            }
+        }
+        ready
+    }
+
+    // Forgets orphans from a disconnected peer; their parent requests can no longer succeed.
+    pub fn remove_peer(&mut self, peer: SocketAddr) -> usize {
+        let txids: Vec<Txid> = self
+            .orphans
+            .values()
+            .filter(|orphan| orphan.from == peer)
+            .map(|orphan| orphan.txid)
+            .collect();
+        for txid in &txids {
+            self.remove(txid);
+        }
+        txids.len()
+    }
+
+    fn remove(&mut self, txid: &Txid) -> Option<Orphan> {
+        let orphan = self.orphans.remove(txid)?;
+        self.order.retain(|queued| queued != txid);
+        for parent in &orphan.missing {
+            if let Some(children) = self.by_parent.get_mut(parent) {
+                children.remove(txid);
+                if children.is_empty() {
+                    self.by_parent.remove(parent);
+                }
+            }
+        }
+        Some(orphan)
+    }
+}
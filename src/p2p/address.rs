@@ -0,0 +1,176 @@
+use bitcoin::p2p::address::AddrV2;
+use data_encoding::BASE32_NOPAD;
+use sha3::{Digest, Sha3_256};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+const TORV3_VERSION: u8 = 3;
+
+// Any address kind a peer can gossip via BIP155 addrv2 that we keep track of.
+// Tor v2 and unknown network IDs are dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NetAddr {
+    Ip(SocketAddr),
+    TorV3([u8; 32], u16),
+    I2p([u8; 32], u16),
+    Cjdns(Ipv6Addr, u16),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddrNetwork {
+    Ipv4,
+    Ipv6,
+    Onion,
+    I2p,
+    Cjdns,
+}
+
+impl AddrNetwork {
+    pub const ALL: [AddrNetwork; 5] = [
+        AddrNetwork::Ipv4,
+        AddrNetwork::Ipv6,
+        AddrNetwork::Onion,
+        AddrNetwork::I2p,
+        AddrNetwork::Cjdns,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AddrNetwork::Ipv4 => "ipv4",
+            AddrNetwork::Ipv6 => "ipv6",
+            AddrNetwork::Onion => "onion",
+            AddrNetwork::I2p => "i2p",
+            AddrNetwork::Cjdns => "cjdns",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        AddrNetwork::ALL
+            .into_iter()
+            .find(|network| network.as_str() == value)
+    }
+}
+
+impl NetAddr {
+    pub fn from_addrv2(addr: &AddrV2, port: u16) -> Option<Self> {
+        match addr {
+            AddrV2::Ipv4(ip) => Some(NetAddr::Ip(SocketAddr::new(IpAddr::V4(*ip), port))),
+            AddrV2::Ipv6(ip) => Some(NetAddr::Ip(SocketAddr::new(IpAddr::V6(*ip), port))),
+            AddrV2::TorV3(pubkey) => Some(NetAddr::TorV3(*pubkey, port)),
+            AddrV2::I2p(hash) => Some(NetAddr::I2p(*hash, port)),
+            AddrV2::Cjdns(ip) => Some(NetAddr::Cjdns(*ip, port)),
+            AddrV2::TorV2(_) | AddrV2::Unknown(_, _) => None,
+        }
+    }
+
+    pub fn to_addrv2(self) -> AddrV2 {
+        match self {
+            NetAddr::Ip(addr) => match addr.ip() {
+                IpAddr::V4(ip) => AddrV2::Ipv4(ip),
+                IpAddr::V6(ip) => AddrV2::Ipv6(ip),
+            },
+            NetAddr::TorV3(pubkey, _) => AddrV2::TorV3(pubkey),
+            NetAddr::I2p(hash, _) => AddrV2::I2p(hash),
+            NetAddr::Cjdns(ip, _) => AddrV2::Cjdns(ip),
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            NetAddr::Ip(addr) => addr.port(),
+            NetAddr::TorV3(_, port) | NetAddr::I2p(_, port) | NetAddr::Cjdns(_, port) => *port,
+        }
+    }
+
+    pub fn network(&self) -> AddrNetwork {
+        match self {
+            NetAddr::Ip(addr) if addr.is_ipv4() => AddrNetwork::Ipv4,
+            NetAddr::Ip(_) => AddrNetwork::Ipv6,
+            NetAddr::TorV3(_, _) => AddrNetwork::Onion,
+            NetAddr::I2p(_, _) => AddrNetwork::I2p,
+            NetAddr::Cjdns(_, _) => AddrNetwork::Cjdns,
+        }
+    }
+
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            NetAddr::Ip(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    // Inverse of `Display`; the network is needed to tell CJDNS apart from IPv6.
+    pub fn parse(value: &str, network: AddrNetwork) -> Option<Self> {
+        match network {
+            AddrNetwork::Ipv4 | AddrNetwork::Ipv6 => value.parse().ok().map(NetAddr::Ip),
+            AddrNetwork::Cjdns => value
+                .parse::<SocketAddr>()
+                .ok()
+                .and_then(|addr| match addr.ip() {
+                    IpAddr::V6(ip) => Some(NetAddr::Cjdns(ip, addr.port())),
+                    IpAddr::V4(_) => None,
+                }),
+            AddrNetwork::Onion => {
+                let (host, port) = value.rsplit_once(':')?;
+                let decoded = decode_base32(host.strip_suffix(".onion")?)?;
+                if decoded.len() != 35 || decoded[34] != TORV3_VERSION {
+                    return None;
+                }
+                let mut pubkey = [0u8; 32];
+                pubkey.copy_from_slice(&decoded[..32]);
+                if decoded[32..34] != torv3_checksum(&pubkey) {
+                    return None;
+                }
+                Some(NetAddr::TorV3(pubkey, port.parse().ok()?))
+            }
+            AddrNetwork::I2p => {
+                let (host, port) = value.rsplit_once(':')?;
+                let decoded = decode_base32(host.strip_suffix(".b32.i2p")?)?;
+                let hash: [u8; 32] = decoded.try_into().ok()?;
+                Some(NetAddr::I2p(hash, port.parse().ok()?))
+            }
+        }
+    }
+}
+
+impl From<SocketAddr> for NetAddr {
+    fn from(addr: SocketAddr) -> Self {
+        NetAddr::Ip(addr)
+    }
+}
+
+impl fmt::Display for NetAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetAddr::Ip(addr) => write!(f, "{}", addr),
+            NetAddr::TorV3(pubkey, port) => {
+                let mut data = pubkey.to_vec();
+                data.extend_from_slice(&torv3_checksum(pubkey));
+                data.push(TORV3_VERSION);
+                write!(f, "{}.onion:{}", encode_base32(&data), port)
+            }
+            NetAddr::I2p(hash, port) => write!(f, "{}.b32.i2p:{}", encode_base32(hash), port),
+            NetAddr::Cjdns(ip, port) => write!(f, "[{}]:{}", ip, port),
+        }
+    }
+}
+
+// CHECKSUM = SHA3-256(".onion checksum" | PUBKEY | VERSION)[:2]
+fn torv3_checksum(pubkey: &[u8; 32]) -> [u8; 2] {
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update([TORV3_VERSION]);
+    let digest = hasher.finalize();
+    [digest[0], digest[1]]
+}
+
+fn encode_base32(data: &[u8]) -> String {
+    BASE32_NOPAD.encode(data).to_lowercase()
+}
+
+fn decode_base32(value: &str) -> Option<Vec<u8>> {
+    BASE32_NOPAD
+        .decode(value.to_uppercase().as_bytes())
+        .ok()
+}
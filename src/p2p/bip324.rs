@@ -0,0 +1,488 @@
+use anyhow::Result;
+use bitcoin::p2p::Magic;
+use bitcoin::secp256k1::{All, Secp256k1, SecretKey};
+use bitcoin::secp256k1::ellswift::{ElligatorSwift, ElligatorSwiftParty};
+use chacha20::ChaCha20;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20poly1305::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::sync::LazyLock;
+
+pub const ELLSWIFT_LEN: usize = 64;
+pub const GARBAGE_TERMINATOR_LEN: usize = 16;
+pub const MAX_GARBAGE_LEN: usize = 4095;
+pub const LENGTH_FIELD_LEN: usize = 3;
+pub const HEADER_LEN: usize = 1;
+pub const TAG_LEN: usize = 16;
+// Prefix every v1 connection starts with: magic followed by the padded "version" command.
+pub const V1_PREFIX_LEN: usize = 16;
+
+const REKEY_INTERVAL: u64 = 224;
+
+static SECP: LazyLock<Secp256k1<All>> = LazyLock::new(Secp256k1::new);
+const IGNORE_BIT: u8 = 0x80;
+
+// Index is the short message ID; empty slots are unassigned.
+const SHORT_IDS: [&str; 29] = [
+    "",
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+pub fn short_id(command: &str) -> Option<u8> {
+    SHORT_IDS
+        .iter()
+        .skip(1)
+        .position(|name| *name == command)
+        .map(|index| (index + 1) as u8)
+}
+
+pub fn command_for_short_id(id: u8) -> Option<&'static str> {
+    SHORT_IDS
+        .get(id as usize)
+        .copied()
+        .filter(|name| !name.is_empty())
+}
+
+pub fn v1_prefix(magic: Magic) -> [u8; V1_PREFIX_LEN] {
+    let mut prefix = [0u8; V1_PREFIX_LEN];
+    prefix[..4].copy_from_slice(&magic.to_bytes());
+    prefix[4..11].copy_from_slice(b"version");
+    prefix
+}
+
+// Our half of the ElligatorSwift key exchange.
+pub struct KeyExchange {
+    secret_key: SecretKey,
+    ellswift: ElligatorSwift,
+    initiator: bool,
+}
+
+impl KeyExchange {
+    pub fn new(initiator: bool) -> Self {
+        let secret_key = loop {
+            if let Ok(key) = SecretKey::from_slice(&rand::random::<[u8; 32]>()) {
+                break key;
+            }
+        };
+        let ellswift = ElligatorSwift::from_seckey(&SECP, secret_key, Some(rand::random()));
+        Self {
+            secret_key,
+            ellswift,
+            initiator,
+        }
+    }
+
+    pub fn public_bytes(&self) -> [u8; ELLSWIFT_LEN] {
+        self.ellswift.to_array()
+    }
+
+    pub fn complete(self, their_ellswift: [u8; ELLSWIFT_LEN], magic: Magic) -> Result<Cipher> {
+        let theirs = ElligatorSwift::from_array(their_ellswift);
+        let (ellswift_a, ellswift_b, party) = if self.initiator {
+            (self.ellswift, theirs, ElligatorSwiftParty::A)
+        } else {
+            (theirs, self.ellswift, ElligatorSwiftParty::B)
+        };
+        let shared_secret =
+            ElligatorSwift::shared_secret(ellswift_a, ellswift_b, self.secret_key, party, None);
+
+        let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+        salt.extend_from_slice(&magic.to_bytes());
+        let hkdf = Hkdf::<Sha256>::new(Some(&salt), shared_secret.as_secret_bytes());
+        let expand = |info: &[u8]| -> Result<[u8; 32]> {
+            let mut okm = [0u8; 32];
+            hkdf.expand(info, &mut okm)
+                .map_err(|e| anyhow::anyhow!("hkdf expand failed: {}", e))?;
+            Ok(okm)
+        };
+
+        let initiator_l = expand(b"initiator_L")?;
+        let initiator_p = expand(b"initiator_P")?;
+        let responder_l = expand(b"responder_L")?;
+        let responder_p = expand(b"responder_P")?;
+        let terminators = expand(b"garbage_terminators")?;
+        let session_id = expand(b"session_id")?;
+
+        let mut initiator_terminator = [0u8; GARBAGE_TERMINATOR_LEN];
+        let mut responder_terminator = [0u8; GARBAGE_TERMINATOR_LEN];
+        initiator_terminator.copy_from_slice(&terminators[..GARBAGE_TERMINATOR_LEN]);
+        responder_terminator.copy_from_slice(&terminators[GARBAGE_TERMINATOR_LEN..]);
+
+        let cipher = if self.initiator {
+            Cipher {
+                send_length: FsChaCha20::new(initiator_l),
+                send_packet: FsChaCha20Poly1305::new(initiator_p),
+                recv_length: FsChaCha20::new(responder_l),
+                recv_packet: FsChaCha20Poly1305::new(responder_p),
+                send_garbage_terminator: initiator_terminator,
+                recv_garbage_terminator: responder_terminator,
+                session_id,
+            }
+        } else {
+            Cipher {
+                send_length: FsChaCha20::new(responder_l),
+                send_packet: FsChaCha20Poly1305::new(responder_p),
+                recv_length: FsChaCha20::new(initiator_l),
+                recv_packet: FsChaCha20Poly1305::new(initiator_p),
+                send_garbage_terminator: responder_terminator,
+                recv_garbage_terminator: initiator_terminator,
+                session_id,
+            }
+        };
+
+        Ok(cipher)
+    }
+}
+
+pub fn random_garbage() -> Vec<u8> {
+    let len = rand::random::<usize>() % (MAX_GARBAGE_LEN + 1);
+    (0..len).map(|_| rand::random()).collect()
+}
+
+// Packet ciphers for an established v2 session.
+pub struct Cipher {
+    send_length: FsChaCha20,
+    send_packet: FsChaCha20Poly1305,
+    recv_length: FsChaCha20,
+    recv_packet: FsChaCha20Poly1305,
+    send_garbage_terminator: [u8; GARBAGE_TERMINATOR_LEN],
+    recv_garbage_terminator: [u8; GARBAGE_TERMINATOR_LEN],
+    session_id: [u8; 32],
+}
+
+impl Cipher {
+    pub fn send_garbage_terminator(&self) -> &[u8; GARBAGE_TERMINATOR_LEN] {
+        &self.send_garbage_terminator
+    }
+
+    pub fn recv_garbage_terminator(&self) -> &[u8; GARBAGE_TERMINATOR_LEN] {
+        &self.recv_garbage_terminator
+    }
+
+    // Same on both ends; comparing it out of band rules out a man in the middle.
+    pub fn session_id(&self) -> &[u8; 32] {
+        &self.session_id
+    }
+
+    pub fn encrypt_packet(&mut self, contents: &[u8], aad: &[u8], ignore: bool) -> Result<Vec<u8>> {
+        if contents.len() >= 1 << (8 * LENGTH_FIELD_LEN) {
+            anyhow::bail!("v2 packet too large: {} bytes", contents.len());
+        }
+
+        let mut length = (contents.len() as u32).to_le_bytes()[..LENGTH_FIELD_LEN].to_vec();
+        self.send_length.crypt(&mut length);
+
+        let mut plaintext = Vec::with_capacity(HEADER_LEN + contents.len());
+        plaintext.push(if ignore { IGNORE_BIT } else { 0 });
+        plaintext.extend_from_slice(contents);
+
+        let mut packet = length;
+        packet.extend(self.send_packet.encrypt(&plaintext, aad)?);
+        Ok(packet)
+    }
+
+    // Decrypts the 3-byte length prefix; must be called exactly once per packet.
+    pub fn decrypt_length(&mut self, encrypted: [u8; LENGTH_FIELD_LEN]) -> usize {
+        let mut length = encrypted;
+        self.recv_length.crypt(&mut length);
+        u32::from_le_bytes([length[0], length[1], length[2], 0]) as usize
+    }
+
+    // Decrypts header, contents and tag. Returns `None` for decoy packets.
+    pub fn decrypt_packet(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut plaintext = self.recv_packet.decrypt(ciphertext, aad)?;
+        if plaintext.is_empty() {
+            anyhow::bail!("v2 packet missing header");
+        }
+        if plaintext[0] & IGNORE_BIT != 0 {
+            return Ok(None);
+        }
+        plaintext.remove(0);
+        Ok(Some(plaintext))
+    }
+}
+
+struct FsChaCha20 {
+    cipher: ChaCha20,
+    chunk_counter: u64,
+    rekey_counter: u64,
+}
+
+impl FsChaCha20 {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            cipher: ChaCha20::new(&key.into(), &Self::nonce(0).into()),
+            chunk_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    fn nonce(rekey_counter: u64) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[4..].copy_from_slice(&rekey_counter.to_le_bytes());
+        nonce
+    }
+
+    fn crypt(&mut self, chunk: &mut [u8]) {
+        self.cipher.apply_keystream(chunk);
+        self.chunk_counter += 1;
+        if self.chunk_counter == REKEY_INTERVAL {
+            let mut key = [0u8; 32];
+            self.cipher.apply_keystream(&mut key);
+            self.chunk_counter = 0;
+            self.rekey_counter += 1;
+            self.cipher = ChaCha20::new(&key.into(), &Self::nonce(self.rekey_counter).into());
+        }
+    }
+}
+
+struct FsChaCha20Poly1305 {
+    key: [u8; 32],
+    packet_counter: u64,
+}
+
+impl FsChaCha20Poly1305 {
+    fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            packet_counter: 0,
+        }
+    }
+
+    fn nonce(&self, packet_index: u32) -> [u8; 12] {
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&packet_index.to_le_bytes());
+        nonce[4..].copy_from_slice(&(self.packet_counter / REKEY_INTERVAL).to_le_bytes());
+        nonce
+    }
+
+    fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce((self.packet_counter % REKEY_INTERVAL) as u32);
+        let ciphertext = ChaCha20Poly1305::new(&self.key.into())
+            .encrypt(&nonce.into(), Payload { msg: plaintext, aad })
+            .map_err(|_| anyhow::anyhow!("v2 packet encryption failed"))?;
+        self.advance()?;
+        Ok(ciphertext)
+    }
+
+    fn decrypt(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let nonce = self.nonce((self.packet_counter % REKEY_INTERVAL) as u32);
+        let plaintext = ChaCha20Poly1305::new(&self.key.into())
+            .decrypt(&nonce.into(), Payload { msg: ciphertext, aad })
+            .map_err(|_| anyhow::anyhow!("v2 packet authentication failed"))?;
+        self.advance()?;
+        Ok(plaintext)
+    }
+
+    fn advance(&mut self) -> Result<()> {
+        if (self.packet_counter + 1).is_multiple_of(REKEY_INTERVAL) {
+            let nonce = self.nonce(u32::MAX);
+            let rekey = ChaCha20Poly1305::new(&self.key.into())
+                .encrypt(&nonce.into(), [0u8; 32].as_slice())
+                .map_err(|_| anyhow::anyhow!("v2 rekey failed"))?;
+            self.key.copy_from_slice(&rekey[..32]);
+        }
+        self.packet_counter += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hex::{DisplayHex, FromHex};
+
+    // A row of BIP324's packet_encoding_test_vectors.csv. All rows use mainnet magic and
+    // `multiply` 1. Packets before `idx` are encrypted with empty contents.
+    struct Vector {
+        idx: u32,
+        priv_ours: &'static str,
+        ellswift_ours: &'static str,
+        ellswift_theirs: &'static str,
+        initiating: bool,
+        contents: &'static str,
+        aad: &'static str,
+        ignore: bool,
+        send_garbage_terminator: &'static str,
+        recv_garbage_terminator: &'static str,
+        session_id: &'static str,
+        ciphertext_endswith: &'static str,
+    }
+
+    const VECTORS: [Vector; 4] = [
+        Vector {
+            idx: 1,
+            priv_ours: "61062ea5071d800bbfd59e2e8b53d47d194b095ae5a4df04936b49772ef0d4d7",
+            ellswift_ours: "ec0adff257bbfe500c188c80b4fdd640f6b45a482bbc15fc7cef5931deff0aa1\
+                            86f6eb9bba7b85dc4dcc28b28722de1e3d9108b985e2967045668f66098e475b",
+            ellswift_theirs: "a4a94dfce69b4a2a0a099313d10f9f7e7d649d60501c9e1d274c300e0d89aafa\
+                              ffffffffffffffffffffffffffffffffffffffffffffffffffffffff8faf88d5",
+            initiating: true,
+            contents: "8e",
+            aad: "",
+            ignore: false,
+            send_garbage_terminator: "faef555dfcdb936425d84aba524758f3",
+            recv_garbage_terminator: "02cb8ff24307a6e27de3b4e7ea3fa65b",
+            session_id: "ce72dffb015da62b0d0f5474cab8bc72605225b0cee3f62312ec680ec5f41ba5",
+            ciphertext_endswith: "7530d2a18720162ac09c25329a60d75adf36eda3c3",
+        },
+        Vector {
+            idx: 999,
+            priv_ours: "1f9c581b35231838f0f17cf0c979835baccb7f3abbbb96ffcc318ab71e6e126f",
+            ellswift_ours: "a1855e10e94e00baa23041d916e259f7044e491da6171269694763f018c7e636\
+                            93d29575dcb464ac816baa1be353ba12e3876cba7628bd0bd8e755e721eb0140",
+            ellswift_theirs: "fffffffffffffffffffffffffffffffffffffffffffffffffffffffefffffc2f\
+                              0000000000000000000000000000000000000000000000000000000000000000",
+            initiating: false,
+            contents: "3eb1d4e98035cfd8eeb29bac969ed3824a",
+            aad: "",
+            ignore: false,
+            send_garbage_terminator: "efb64fd80acd3825ac9bc2a67216535a",
+            recv_garbage_terminator: "b3cb553453bceb002897e751ff7588bf",
+            session_id: "9267c54560607de73f18c563b76a2442718879c52dd39852885d4a3c9912c9ea",
+            ciphertext_endswith: "1da1bcf589f9b61872f45b7fa5371dd3f8bdf5d515b0c5f9fe9f0044afb8dc0a\
+                                  a1cd39a8c4",
+        },
+        // The last packet before both ciphers rekey.
+        Vector {
+            idx: 223,
+            priv_ours: "6c77432d1fda31e9f942f8af44607e10f3ad38a65f8a4bddae823e5eff90dc38",
+            ellswift_ours: "d2685070c1e6376e633e825296634fd461fa9e5bdf2109bcebd735e5a91f3e58\
+                            7c5cb782abb797fbf6bb5074fd1542a474f2a45b673763ec2db7fb99b737bbb9",
+            ellswift_theirs: "56bd0c06f10352c3a1a9f4b4c92f6fa2b26df124b57878353c1fc691c51abea7\
+                              7c8817daeeb9fa546b77c8daf79d89b22b0e1b87574ece42371f00237aa9d83a",
+            initiating: false,
+            contents: "7e0e78eb6990b059e6cf0ded66ea93ef82e72aa2f18ac24f2fc6ebab561ae557\
+                       420729da103f64cecfa20527e15f9fb669a49bbbf274ef0389b3e43c8c44e5f6\
+                       0bf2ac38e2b55e7ec4273dba15ba41d21f8f5b3ee1688b3c29951218caf847a9\
+                       7fb50d75a86515d445699497d968164bf740012679b8962de573be941c62b7ef",
+            aad: "",
+            ignore: true,
+            send_garbage_terminator: "cf2e25f23501399f30738d7eee652b90",
+            recv_garbage_terminator: "225a477a28a54ea7671d2b217a9c29db",
+            session_id: "7ec02fea8c1484e3d0875f978c5f36d63545e2e4acf56311394422f4b66af612",
+            ciphertext_endswith: "729847a3e9eba7a5bff454b5de3b393431ee360736b6c030d7a5bd01d1203d2e\
+                                  98f528543fd2bf886ccaa1ada5e215a730a36b3f4abfc4e252c89eb01d9512f9\
+                                  4916dae8a76bf16e4da28986ffe159090fe5267ee3394300b7ccf4dfad389a26\
+                                  321b3a3423e4594a82ccfbad16d6561ecb8772b0cb040280ff999a29e3d9d4fd",
+        },
+        // The first packet after the second rekey.
+        Vector {
+            idx: 448,
+            priv_ours: "a6ec25127ca1aa4cf16b20084ba1e6516baae4d32422288e9b36d8bddd2de35a",
+            ellswift_ours: "ffffffffffffffffffffffffffffffffffffffffffffffffffffffff053d7ecc\
+                            a53e33e185a8b9be4e7699a97c6ff4c795522e5918ab7cd6b6884f67e683f3dc",
+            ellswift_theirs: "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffa7730be3\
+                              0000000000000000000000000000000000000000000000000000000000000000",
+            initiating: true,
+            contents: "00cf68f8f7ac49ffaa02c4864fdf6dfe7bbf2c740b88d98c50ebafe32c92f342\
+                       7f57601ffcb21a3435979287db8fee6c302926741f9d5e464c647eeb9b7acaed\
+                       a46e00abd7506fc9a719847e9a7328215801e96198dac141a15c7c2f68e0690d\
+                       d1176292a0dded04d1f548aad88f1aebdc0a8f87da4bb22df32dd7c160c225b8\
+                       43e83f6525d6d484f502f16d923124fc538794e21da2eb689d18d87406ecced5\
+                       b9f92137239ed1d37bcfa7836641a83cf5e0a1cf63f51b06f158e499a459ede4\
+                       1c",
+            aad: "",
+            ignore: false,
+            send_garbage_terminator: "fead69be77825a23daec377c362aa560",
+            recv_garbage_terminator: "511d4980526c5e64aa7187462faeafdd",
+            session_id: "acb8f084ea763ddd1b92ac4ed23bf44de20b84ab677d4e4e6666a6090d40353d",
+            ciphertext_endswith: "77b4656934a82de1a593d8481f020194ddafd8cac441f9d72aeb8721e6a14f49\
+                                  698ca6d9b2b6d59d07a01aa552fd4d5b68d0d1617574c77dea10bfadbaa31b83\
+                                  885b7ceac2fd45e3e4a331c51a74e7b1698d81b64c87c73c5b9258b4d83297f9\
+                                  debc2e9aa07f8572ff434dc792b83ecf07b3197de8dc9cf7be56acb59c66cff5",
+        },
+    ];
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        Vec::from_hex(hex).unwrap()
+    }
+
+    #[test]
+    fn packet_encoding_vectors() {
+        for vector in &VECTORS {
+            let key_exchange = KeyExchange {
+                secret_key: SecretKey::from_slice(&bytes(vector.priv_ours)).unwrap(),
+                ellswift: ElligatorSwift::from_array(
+                    bytes(vector.ellswift_ours).try_into().unwrap(),
+                ),
+                initiator: vector.initiating,
+            };
+            let their_ellswift = bytes(vector.ellswift_theirs).try_into().unwrap();
+            let mut cipher = key_exchange.complete(their_ellswift, Magic::BITCOIN).unwrap();
+
+            assert_eq!(
+                cipher.send_garbage_terminator().to_lower_hex_string(),
+                vector.send_garbage_terminator,
+                "vector {}",
+                vector.idx
+            );
+            assert_eq!(
+                cipher.recv_garbage_terminator().to_lower_hex_string(),
+                vector.recv_garbage_terminator,
+                "vector {}",
+                vector.idx
+            );
+            assert_eq!(
+                cipher.session_id().to_lower_hex_string(),
+                vector.session_id,
+                "vector {}",
+                vector.idx
+            );
+
+            for _ in 0..vector.idx {
+                cipher.encrypt_packet(&[], &[], false).unwrap();
+            }
+            let ciphertext = cipher
+                .encrypt_packet(&bytes(vector.contents), &bytes(vector.aad), vector.ignore)
+                .unwrap();
+            assert!(
+                ciphertext
+                    .to_lower_hex_string()
+                    .ends_with(vector.ciphertext_endswith),
+                "vector {}",
+                vector.idx
+            );
+        }
+    }
+
+    #[test]
+    fn short_ids_round_trip() {
+        for (id, command) in SHORT_IDS.iter().enumerate().skip(1) {
+            assert_eq!(short_id(command), Some(id as u8));
+            assert_eq!(command_for_short_id(id as u8), Some(*command));
+        }
+        assert_eq!(command_for_short_id(0), None);
+        assert_eq!(command_for_short_id(SHORT_IDS.len() as u8), None);
+        assert_eq!(short_id("version"), None);
+    }
+}
@@ -0,0 +1,246 @@
+use anyhow::Result;
+use bitcoin::hashes::{Hash, sha256};
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use tokio::net::TcpStream;
+use tokio_socks::tcp::Socks5Stream;
+
+// Bitcoin Core's NET_INTERNAL range. Hostname targets are mapped into it so they
+// can share the SocketAddr-keyed peer bookkeeping with ordinary addresses.
+const INTERNAL_PREFIX: [u8; 6] = [0xfd, 0x6b, 0x88, 0xc0, 0x87, 0x24];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DialTarget {
+    Addr(SocketAddr),
+    Host { host: String, port: u16 },
+}
+
+impl DialTarget {
+    pub fn parse(value: &str, default_port: u16) -> Result<Self> {
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(DialTarget::Addr(addr));
+        }
+        if let Ok(ip) = value.parse::<IpAddr>() {
+            return Ok(DialTarget::Addr(SocketAddr::new(ip, default_port)));
+        }
+
+        let (host, port) = match value.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse::<u16>()?),
+            None => (value, default_port),
+        };
+        if host.is_empty() {
+            anyhow::bail!("missing host in {:?}", value);
+        }
+        Ok(DialTarget::Host {
+            host: host.to_lowercase(),
+            port,
+        })
+    }
+
+    // Identity used for peer bookkeeping; hostnames get a stable internal address.
+    pub fn peer_addr(&self) -> SocketAddr {
+        match self {
+            DialTarget::Addr(addr) => *addr,
+            DialTarget::Host { host, port } => {
+                let hash = sha256::Hash::hash(host.as_bytes());
+                let mut octets = [0u8; 16];
+                octets[..6].copy_from_slice(&INTERNAL_PREFIX);
+                octets[6..].copy_from_slice(&hash.as_byte_array()[..10]);
+                SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), *port)
+            }
+        }
+    }
+
+    pub fn is_onion(&self) -> bool {
+        matches!(self, DialTarget::Host { host, .. } if host.ends_with(".onion"))
+    }
+}
+
+impl fmt::Display for DialTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DialTarget::Addr(addr) => write!(f, "{}", addr),
+            DialTarget::Host { host, port } => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+pub fn is_internal_addr(addr: SocketAddr) -> bool {
+    match addr.ip() {
+        IpAddr::V6(ip) => ip.octets()[..6] == INTERNAL_PREFIX,
+        IpAddr::V4(_) => false,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    pub addr: SocketAddr,
+    // Random SOCKS5 credentials per connection make Tor use a separate circuit for each.
+    pub randomize_credentials: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Dialer {
+    proxy: Option<ProxyConfig>,
+}
+
+impl Dialer {
+    pub fn new(proxy: Option<ProxyConfig>) -> Self {
+        Self { proxy }
+    }
+
+    pub async fn connect(&self, target: &DialTarget) -> Result<TcpStream> {
+        let Some(proxy) = &self.proxy else {
+            return match target {
+                DialTarget::Addr(addr) => Ok(TcpStream::connect(addr).await?),
+                DialTarget::Host { .. } if target.is_onion() => {
+                    anyhow::bail!("cannot reach {} without a proxy", target)
+                }
+                DialTarget::Host { host, port } => {
+                    Ok(TcpStream::connect((host.as_str(), *port)).await?)
+                }
+            };
+        };
+
+        let stream = match (target, proxy.randomize_credentials) {
+            (DialTarget::Addr(addr), false) => Socks5Stream::connect(proxy.addr, *addr).await?,
+            (DialTarget::Host { host, port }, false) => {
+                Socks5Stream::connect(proxy.addr, (host.as_str(), *port)).await?
+            }
+            (DialTarget::Addr(addr), true) => {
+                let (user, pass) = isolation_credentials();
+                Socks5Stream::connect_with_password(proxy.addr, *addr, &user, &pass).await?
+            }
+            (DialTarget::Host { host, port }, true) => {
+                let (user, pass) = isolation_credentials();
+                Socks5Stream::connect_with_password(
+                    proxy.addr,
+                    (host.as_str(), *port),
+                    &user,
+                    &pass,
+                )
+                .await?
+            }
+        };
+
+        Ok(stream.into_inner())
+    }
+}
+
+fn isolation_credentials() -> (String, String) {
+    (
+        format!("{:016x}", rand::random::<u64>()),
+        format!("{:016x}", rand::random::<u64>()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    // What the stand-in proxy was sent for one connection.
+    struct Seen {
+        credentials: Option<(String, String)>,
+        request: Vec<u8>,
+    }
+
+    async fn read_string(stream: &mut TcpStream) -> String {
+        let mut value = vec![0u8; stream.read_u8().await.unwrap() as usize];
+        stream.read_exact(&mut value).await.unwrap();
+        String::from_utf8(value).unwrap()
+    }
+
+    // Accepts one SOCKS5 connection, picking password auth whenever it is offered, and
+    // grants the CONNECT request.
+    async fn socks5_stand_in(listener: &TcpListener) -> Seen {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(stream.read_u8().await.unwrap(), 0x05);
+        let mut methods = vec![0u8; stream.read_u8().await.unwrap() as usize];
+        stream.read_exact(&mut methods).await.unwrap();
+
+        let credentials = if methods.contains(&0x02) {
+            stream.write_all(&[0x05, 0x02]).await.unwrap();
+            assert_eq!(stream.read_u8().await.unwrap(), 0x01);
+            let user = read_string(&mut stream).await;
+            let pass = read_string(&mut stream).await;
+            stream.write_all(&[0x01, 0x00]).await.unwrap();
+            Some((user, pass))
+        } else {
+            stream.write_all(&[0x05, 0x00]).await.unwrap();
+            None
+        };
+
+        let mut request = vec![0u8; 4];
+        stream.read_exact(&mut request).await.unwrap();
+        let addr_len = match request[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let len = stream.read_u8().await.unwrap();
+                request.push(len);
+                len as usize
+            }
+            atyp => panic!("unknown address type {}", atyp),
+        };
+        let mut addr_and_port = vec![0u8; addr_len + 2];
+        stream.read_exact(&mut addr_and_port).await.unwrap();
+        request.extend(addr_and_port);
+
+        stream
+            .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        Seen {
+            credentials,
+            request,
+        }
+    }
+
+    async fn dial(randomize_credentials: bool, targets: &[DialTarget]) -> Vec<Seen> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dialer = Dialer::new(Some(ProxyConfig {
+            addr: listener.local_addr().unwrap(),
+            randomize_credentials,
+        }));
+        let mut seen = Vec::new();
+        for target in targets {
+            let (stream, proxied) =
+                tokio::join!(dialer.connect(target), socks5_stand_in(&listener));
+            stream.unwrap();
+            seen.push(proxied);
+        }
+        seen
+    }
+
+    #[tokio::test]
+    async fn connects_to_ip_through_proxy() {
+        let target = DialTarget::parse("203.0.113.5:8333", 8333).unwrap();
+        let seen = dial(false, &[target]).await;
+        assert!(seen[0].credentials.is_none());
+        assert_eq!(seen[0].request, [0x05, 0x01, 0x00, 0x01, 203, 0, 113, 5, 0x20, 0x8d]);
+    }
+
+    #[tokio::test]
+    async fn resolves_onion_hosts_at_the_proxy() {
+        let host = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+        let target = DialTarget::parse(host, 8333).unwrap();
+        let seen = dial(false, &[target]).await;
+
+        let mut expected = vec![0x05, 0x01, 0x00, 0x03, host.len() as u8];
+        expected.extend_from_slice(host.as_bytes());
+        expected.extend_from_slice(&8333u16.to_be_bytes());
+        assert_eq!(seen[0].request, expected);
+    }
+
+    #[tokio::test]
+    async fn randomizes_credentials_per_connection() {
+        let target = DialTarget::parse("203.0.113.5:8333", 8333).unwrap();
+        let seen = dial(true, &[target.clone(), target]).await;
+        let first = seen[0].credentials.as_ref().unwrap();
+        let second = seen[1].credentials.as_ref().unwrap();
+        assert_ne!(first.0, second.0);
+        assert_ne!(first.1, second.1);
+    }
+}
@@ -1,23 +1,36 @@
+use super::address::NetAddr;
+use super::bip324;
+use bitcoin::consensus::encode::VarInt;
 use bitcoin::consensus::{Decodable, Encodable};
-use bitcoin::p2p::address::{AddrV2, AddrV2Message};
+use bitcoin::hashes::{Hash, sha256d};
+use bitcoin::p2p::address::AddrV2Message;
 use bitcoin::p2p::message::RawNetworkMessage;
-pub use bitcoin::p2p::message_blockdata::Inventory;
+pub use bitcoin::p2p::message_blockdata::{GetHeadersMessage, Inventory};
 use bitcoin::p2p::message_network::VersionMessage;
 use bitcoin::p2p::{Magic, ServiceFlags};
-use std::net::{IpAddr, SocketAddr};
+use bitcoin::bip152::{BlockTransactions, BlockTransactionsRequest, HeaderAndShortIds};
+use bitcoin::block::Header;
+use bitcoin::p2p::message_compact_blocks::{BlockTxn, CmpctBlock, GetBlockTxn};
+pub use bitcoin::p2p::message_compact_blocks::SendCmpct;
+use bitcoin::{Block, Transaction, Wtxid};
+use std::net::SocketAddr;
 
-pub const MAGIC: Magic = Magic::BITCOIN;
 // Explicitly advertise a modern protocol version so peers send newer capability
 // messages (e.g., feefilter, wtxidrelay, sendaddrv2/addrv2) during handshake.
 pub const ADVERTISED_PROTOCOL_VERSION: u32 = 70016;
 
+// BIP331 sendpackages bit for ancestor package relay.
+pub const PKG_RELAY_ANCPKG: u64 = 1 << 0;
+// BIP331 inventory type for requesting a tx's ancestor package info.
+pub const MSG_ANCPKGINFO: u32 = 6;
+// Largest package BIP331 peers accept (MAX_PACKAGE_COUNT in Bitcoin Core).
+pub const MAX_PACKAGE_COUNT: usize = 25;
+
 #[derive(Debug, Clone)]
 pub struct PeerVersion {
     pub version: u32,
     pub services: ServiceFlags,
-    pub timestamp: i64,
     pub user_agent: String,
-    pub start_height: i32,
     pub relay: bool,
 }
 
@@ -26,9 +39,7 @@ impl PeerVersion {
         Self {
             version: msg.version,
             services: msg.services,
-            timestamp: msg.timestamp,
             user_agent: msg.user_agent.clone(),
-            start_height: msg.start_height,
             relay: msg.relay,
         }
     }
@@ -45,7 +56,23 @@ pub enum Message {
     FeeFilter(i64),
     Inv(Vec<Inventory>),
     GetData(Vec<Inventory>),
+    NotFound(Vec<Inventory>),
+    MemPool,
     Tx(bitcoin::Transaction),
+    SendHeaders,
+    GetHeaders(GetHeadersMessage),
+    Headers(Vec<Header>),
+    // BIP152 compact blocks.
+    SendCmpct(SendCmpct),
+    CmpctBlock(HeaderAndShortIds),
+    GetBlockTxn(BlockTransactionsRequest),
+    BlockTxn(BlockTransactions),
+    Block(Block),
+    // BIP331 package relay; rust-bitcoin has no types for these.
+    SendPackages(u64),
+    AncPkgInfo(Vec<Wtxid>),
+    GetPkgTxns(Vec<Wtxid>),
+    PkgTxns(Vec<Transaction>),
     GetAddr,
     Addr(Vec<AddressEntry>),
     AddrV2(Vec<AddressEntry>),
@@ -55,7 +82,7 @@ pub enum Message {
 #[derive(Debug, Clone)]
 pub struct AddressEntry {
     pub services: ServiceFlags,
-    pub addr: SocketAddr,
+    pub addr: NetAddr,
     pub timestamp: u32,
 }
 
@@ -65,12 +92,15 @@ pub fn build_version_message(
     start_height: i32,
     user_agent: &str,
 ) -> VersionMessage {
+    // We accept BIP324 v2 connections and relay full witness data, so advertise both
+    // alongside pruned-node service.
+    let services = ServiceFlags::NETWORK_LIMITED | ServiceFlags::WITNESS | ServiceFlags::P2P_V2;
     VersionMessage {
         version: ADVERTISED_PROTOCOL_VERSION,
-        services: ServiceFlags::NETWORK_LIMITED,
+        services,
         timestamp: chrono::Utc::now().timestamp(),
         receiver: bitcoin::p2p::address::Address::new(&their_addr, ServiceFlags::NONE),
-        sender: bitcoin::p2p::address::Address::new(&our_addr, ServiceFlags::NETWORK_LIMITED),
+        sender: bitcoin::p2p::address::Address::new(&our_addr, services),
         nonce: rand::random(),
         user_agent: user_agent.into(),
         start_height,
@@ -78,7 +108,7 @@ pub fn build_version_message(
     }
 }
 
-pub fn parse_message(data: &[u8]) -> anyhow::Result<Message> {
+pub fn parse_message(data: &[u8], magic: Magic) -> anyhow::Result<Message> {
     use std::io::Cursor;
 
     if data.len() < 24 {
@@ -87,7 +117,7 @@ pub fn parse_message(data: &[u8]) -> anyhow::Result<Message> {
 
     let mut cursor = Cursor::new(data);
     let raw_msg: RawNetworkMessage = Decodable::consensus_decode(&mut cursor)?;
-    if raw_msg.magic() != &MAGIC {
+    if raw_msg.magic() != &magic {
         anyhow::bail!("unexpected network magic: {:?}", raw_msg.magic());
     }
 
@@ -101,7 +131,29 @@ pub fn parse_message(data: &[u8]) -> anyhow::Result<Message> {
         bitcoin::p2p::message::NetworkMessage::FeeFilter(feerate) => Message::FeeFilter(*feerate),
         bitcoin::p2p::message::NetworkMessage::Inv(inv) => Message::Inv(inv.clone()),
         bitcoin::p2p::message::NetworkMessage::GetData(data) => Message::GetData(data.clone()),
+        bitcoin::p2p::message::NetworkMessage::NotFound(inv) => Message::NotFound(inv.clone()),
+        bitcoin::p2p::message::NetworkMessage::MemPool => Message::MemPool,
         bitcoin::p2p::message::NetworkMessage::Tx(tx) => Message::Tx(tx.clone()),
+        bitcoin::p2p::message::NetworkMessage::SendHeaders => Message::SendHeaders,
+        bitcoin::p2p::message::NetworkMessage::GetHeaders(getheaders) => {
+            Message::GetHeaders(getheaders.clone())
+        }
+        bitcoin::p2p::message::NetworkMessage::Headers(headers) => {
+            Message::Headers(headers.clone())
+        }
+        bitcoin::p2p::message::NetworkMessage::SendCmpct(sendcmpct) => {
+            Message::SendCmpct(*sendcmpct)
+        }
+        bitcoin::p2p::message::NetworkMessage::CmpctBlock(cmpct) => {
+            Message::CmpctBlock(cmpct.compact_block.clone())
+        }
+        bitcoin::p2p::message::NetworkMessage::GetBlockTxn(request) => {
+            Message::GetBlockTxn(request.txs_request.clone())
+        }
+        bitcoin::p2p::message::NetworkMessage::BlockTxn(response) => {
+            Message::BlockTxn(response.transactions.clone())
+        }
+        bitcoin::p2p::message::NetworkMessage::Block(block) => Message::Block(block.clone()),
         bitcoin::p2p::message::NetworkMessage::GetAddr => Message::GetAddr,
         bitcoin::p2p::message::NetworkMessage::Addr(addrs) => {
             let entries = addrs
@@ -109,7 +161,7 @@ pub fn parse_message(data: &[u8]) -> anyhow::Result<Message> {
                 .filter_map(|a| {
                     a.1.socket_addr().ok().map(|addr| AddressEntry {
                         services: a.1.services,
-                        addr,
+                        addr: NetAddr::Ip(addr),
                         timestamp: a.0,
                     })
                 })
@@ -120,7 +172,7 @@ pub fn parse_message(data: &[u8]) -> anyhow::Result<Message> {
             let entries = addrs
                 .iter()
                 .filter_map(|a| {
-                    a.socket_addr().ok().map(|addr| AddressEntry {
+                    NetAddr::from_addrv2(&a.addr, a.port).map(|addr| AddressEntry {
                         services: a.services,
                         addr,
                         timestamp: a.time,
@@ -129,6 +181,9 @@ pub fn parse_message(data: &[u8]) -> anyhow::Result<Message> {
                 .collect();
             Message::AddrV2(entries)
         }
+        bitcoin::p2p::message::NetworkMessage::Unknown { command, payload } => {
+            parse_package_message(command.as_ref(), payload)?
+        }
         other => Message::Unknown {
             command: format!("{:?}", other),
         },
@@ -137,6 +192,62 @@ pub fn parse_message(data: &[u8]) -> anyhow::Result<Message> {
     Ok(msg)
 }
 
+fn parse_package_message(command: &str, payload: &[u8]) -> anyhow::Result<Message> {
+    use bitcoin::consensus::deserialize;
+
+    let msg = match command {
+        "sendpackages" => Message::SendPackages(deserialize(payload)?),
+        "ancpkginfo" => Message::AncPkgInfo(decode_wtxids(payload)?),
+        "getpkgtxns" => Message::GetPkgTxns(decode_wtxids(payload)?),
+        "pkgtxns" => Message::PkgTxns(deserialize(payload)?),
+        _ => Message::Unknown {
+            command: command.to_string(),
+        },
+    };
+    Ok(msg)
+}
+
+fn decode_wtxids(payload: &[u8]) -> anyhow::Result<Vec<Wtxid>> {
+    use std::io::Cursor;
+
+    let mut cursor = Cursor::new(payload);
+    let count = VarInt::consensus_decode(&mut cursor)?.0 as usize;
+    if count > MAX_PACKAGE_COUNT {
+        anyhow::bail!("package of {} txs exceeds limit", count);
+    }
+    let wtxids = (0..count)
+        .map(|_| Wtxid::consensus_decode(&mut cursor))
+        .collect::<Result<Vec<_>, _>>()?;
+    if cursor.position() as usize != payload.len() {
+        anyhow::bail!("trailing bytes after wtxid list");
+    }
+    Ok(wtxids)
+}
+
+fn encode_wtxids(wtxids: &[Wtxid]) -> anyhow::Result<Vec<u8>> {
+    let mut payload = Vec::with_capacity(9 + wtxids.len() * 32);
+    VarInt(wtxids.len() as u64).consensus_encode(&mut payload)?;
+    for wtxid in wtxids {
+        wtxid.consensus_encode(&mut payload)?;
+    }
+    Ok(payload)
+}
+
+// v1 header (magic, command, length, checksum) followed by the payload.
+fn frame_v1(magic: Magic, command: &str, payload: &[u8]) -> Vec<u8> {
+    let mut name = [0u8; 12];
+    name[..command.len()].copy_from_slice(command.as_bytes());
+    let checksum = sha256d::Hash::hash(payload);
+
+    let mut data = Vec::with_capacity(24 + payload.len());
+    data.extend_from_slice(&magic.to_bytes());
+    data.extend_from_slice(&name);
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(&checksum.as_byte_array()[..4]);
+    data.extend_from_slice(payload);
+    data
+}
+
 pub fn serialize_message(msg: &Message, magic: Magic) -> anyhow::Result<Vec<u8>> {
     let network_msg = match msg {
         Message::Version(v) => bitcoin::p2p::message::NetworkMessage::Version(v.clone()),
@@ -148,16 +259,58 @@ pub fn serialize_message(msg: &Message, magic: Magic) -> anyhow::Result<Vec<u8>>
         Message::FeeFilter(feerate) => bitcoin::p2p::message::NetworkMessage::FeeFilter(*feerate),
         Message::Inv(inv) => bitcoin::p2p::message::NetworkMessage::Inv(inv.clone()),
         Message::GetData(data) => bitcoin::p2p::message::NetworkMessage::GetData(data.clone()),
+        Message::NotFound(inv) => bitcoin::p2p::message::NetworkMessage::NotFound(inv.clone()),
+        Message::MemPool => bitcoin::p2p::message::NetworkMessage::MemPool,
         Message::Tx(tx) => bitcoin::p2p::message::NetworkMessage::Tx(tx.clone()),
+        Message::SendHeaders => bitcoin::p2p::message::NetworkMessage::SendHeaders,
+        Message::GetHeaders(getheaders) => {
+            bitcoin::p2p::message::NetworkMessage::GetHeaders(getheaders.clone())
+        }
+        Message::Headers(headers) => {
+            bitcoin::p2p::message::NetworkMessage::Headers(headers.clone())
+        }
+        Message::SendCmpct(sendcmpct) => {
+            bitcoin::p2p::message::NetworkMessage::SendCmpct(*sendcmpct)
+        }
+        Message::CmpctBlock(compact_block) => {
+            bitcoin::p2p::message::NetworkMessage::CmpctBlock(CmpctBlock {
+                compact_block: compact_block.clone(),
+            })
+        }
+        Message::GetBlockTxn(request) => {
+            bitcoin::p2p::message::NetworkMessage::GetBlockTxn(GetBlockTxn {
+                txs_request: request.clone(),
+            })
+        }
+        Message::BlockTxn(response) => bitcoin::p2p::message::NetworkMessage::BlockTxn(BlockTxn {
+            transactions: response.clone(),
+        }),
+        Message::Block(block) => bitcoin::p2p::message::NetworkMessage::Block(block.clone()),
+        // NetworkMessage::Unknown would length-prefix the payload, so frame these directly.
+        Message::SendPackages(versions) => {
+            return Ok(frame_v1(magic, "sendpackages", &versions.to_le_bytes()));
+        }
+        Message::AncPkgInfo(wtxids) => {
+            return Ok(frame_v1(magic, "ancpkginfo", &encode_wtxids(wtxids)?));
+        }
+        Message::GetPkgTxns(wtxids) => {
+            return Ok(frame_v1(magic, "getpkgtxns", &encode_wtxids(wtxids)?));
+        }
+        Message::PkgTxns(txs) => {
+            return Ok(frame_v1(magic, "pkgtxns", &bitcoin::consensus::serialize(txs)));
+        }
         Message::GetAddr => bitcoin::p2p::message::NetworkMessage::GetAddr,
         Message::Addr(addrs) => {
+            // addr (v1) can only carry IP addresses.
             let addresses: Vec<(u32, bitcoin::p2p::address::Address)> = addrs
                 .iter()
-                .map(|a| {
-                    (
-                        a.timestamp,
-                        bitcoin::p2p::address::Address::new(&a.addr, a.services),
-                    )
+                .filter_map(|a| {
+                    a.addr.socket_addr().map(|addr| {
+                        (
+                            a.timestamp,
+                            bitcoin::p2p::address::Address::new(&addr, a.services),
+                        )
+                    })
                 })
                 .collect();
             bitcoin::p2p::message::NetworkMessage::Addr(addresses)
@@ -165,17 +318,11 @@ pub fn serialize_message(msg: &Message, magic: Magic) -> anyhow::Result<Vec<u8>>
         Message::AddrV2(addrs) => {
             let addresses: Vec<AddrV2Message> = addrs
                 .iter()
-                .map(|a| {
-                    let addr = match a.addr.ip() {
-                        IpAddr::V4(ip) => AddrV2::Ipv4(ip),
-                        IpAddr::V6(ip) => AddrV2::Ipv6(ip),
-                    };
-                    AddrV2Message {
-                        time: a.timestamp,
-                        services: a.services,
-                        addr,
-                        port: a.addr.port(),
-                    }
+                .map(|a| AddrV2Message {
+                    time: a.timestamp,
+                    services: a.services,
+                    addr: a.addr.to_addrv2(),
+                    port: a.addr.port(),
                 })
                 .collect();
             bitcoin::p2p::message::NetworkMessage::AddrV2(addresses)
@@ -190,3 +337,47 @@ pub fn serialize_message(msg: &Message, magic: Magic) -> anyhow::Result<Vec<u8>>
     raw.consensus_encode(&mut bytes)?;
     Ok(bytes)
 }
+
+// v2 packet contents are a short message ID (or 0x00 plus the 12-byte command)
+// followed by the payload; we reuse the v1 codec and only swap the framing.
+pub fn serialize_message_v2(msg: &Message) -> anyhow::Result<Vec<u8>> {
+    let v1 = serialize_message(msg, Magic::BITCOIN)?;
+    let command = &v1[4..16];
+    let payload = &v1[24..];
+    let name_len = command.iter().position(|b| *b == 0).unwrap_or(command.len());
+    let name = std::str::from_utf8(&command[..name_len])?;
+
+    let mut contents = Vec::with_capacity(13 + payload.len());
+    match bip324::short_id(name) {
+        Some(id) => contents.push(id),
+        None => {
+            contents.push(0);
+            contents.extend_from_slice(command);
+        }
+    }
+    contents.extend_from_slice(payload);
+    Ok(contents)
+}
+
+pub fn parse_message_v2(contents: &[u8], magic: Magic) -> anyhow::Result<Message> {
+    let Some((&id, rest)) = contents.split_first() else {
+        anyhow::bail!("empty v2 message");
+    };
+
+    let (command, payload) = if id == 0 {
+        if rest.len() < 12 {
+            anyhow::bail!("v2 message too short for command");
+        }
+        let name_len = rest[..12].iter().position(|b| *b == 0).unwrap_or(12);
+        (std::str::from_utf8(&rest[..name_len])?, &rest[12..])
+    } else {
+        let Some(name) = bip324::command_for_short_id(id) else {
+            return Ok(Message::Unknown {
+                command: format!("short id {}", id),
+            });
+        };
+        (name, rest)
+    };
+
+    parse_message(&frame_v1(magic, command, payload), magic)
+}
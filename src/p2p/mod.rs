@@ -1,3 +1,6 @@
+pub mod address;
+pub mod bip324;
+pub mod dialer;
 pub mod message;
 pub mod peer;
 
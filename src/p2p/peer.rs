@@ -1,20 +1,32 @@
+use super::address::NetAddr;
+use super::bip324;
+use super::dialer::{self, DialTarget, Dialer};
 use super::message::{
-    AddressEntry, MAGIC, Message, PeerVersion, build_version_message, parse_message,
-    serialize_message,
+    AddressEntry, Message, PKG_RELAY_ANCPKG, PeerVersion, SendCmpct, build_version_message,
+    parse_message, parse_message_v2, serialize_message, serialize_message_v2,
 };
+use crate::ban::Misbehavior;
+use crate::compact::COMPACT_BLOCK_VERSION;
+use crate::config::Network;
 use crate::db::{AddressDb, NodeInfo, NodeType};
 use anyhow::Result;
-use chrono::Utc;
+use bitcoin::hex::DisplayHex;
+use bitcoin::p2p::Magic;
+use chrono::{DateTime, Utc};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tokio::sync::mpsc;
+use tokio::sync::{Notify, mpsc};
 use tokio::time::{Duration, Instant as TokioInstant, interval_at};
 use tracing::{debug, info, warn};
 
 const MAX_MESSAGE_SIZE: usize = 4 * 1024 * 1024; // 4MB
 const OUTBOUND_QUEUE_CAPACITY: usize = 2048;
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+// How long a ping may go unanswered before the peer is dropped (TIMEOUT_INTERVAL in Core).
+const PING_TIMEOUT: Duration = Duration::from_secs(20 * 60);
 
 #[derive(Debug)]
 pub enum PeerEvent {
@@ -35,6 +47,16 @@ pub enum PeerEvent {
         kind: AddressMessageKind,
         addrs: Vec<AddressEntry>,
     },
+    // Round trip of a keepalive ping, measured when its pong arrived.
+    Pong {
+        addr: SocketAddr,
+        rtt: Duration,
+    },
+    // Something the peer sent that an honest node would not; the manager keeps the score.
+    Misbehaving {
+        addr: SocketAddr,
+        misbehavior: Misbehavior,
+    },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -43,12 +65,43 @@ pub enum AddressMessageKind {
     AddrV2,
 }
 
+// What the connection has been good for lately; read when picking an inbound peer to evict.
+#[derive(Debug)]
+struct Activity {
+    // Latest and lowest ping round trips, in microseconds; u64::MAX until the first pong.
+    ping_micros: AtomicU64,
+    min_ping_micros: AtomicU64,
+    // Unix times of the last new tx and new block the peer sent us; 0 if none yet.
+    last_tx: AtomicI64,
+    last_block: AtomicI64,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            ping_micros: AtomicU64::new(u64::MAX),
+            min_ping_micros: AtomicU64::new(u64::MAX),
+            last_tx: AtomicI64::new(0),
+            last_block: AtomicI64::new(0),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PeerHandle {
     addr: SocketAddr,
     sender: mpsc::Sender<Message>,
     node_type: NodeType,
+    v2_transport: bool,
     user_agent: String,
+    inbound: bool,
+    connected_at: DateTime<Utc>,
+    disconnect: Arc<Notify>,
+    // BIP133 minimum feerate (sat/kvB) the peer wants announced; 0 until it sends one.
+    fee_filter: Arc<AtomicU64>,
+    wtxid_relay: bool,
+    package_relay: bool,
+    activity: Arc<Activity>,
 }
 
 impl PeerHandle {
@@ -70,12 +123,82 @@ impl PeerHandle {
     pub fn user_agent(&self) -> &str {
         &self.user_agent
     }
+
+    pub fn v2_transport(&self) -> bool {
+        self.v2_transport
+    }
+
+    pub fn is_inbound(&self) -> bool {
+        self.inbound
+    }
+
+    pub fn connected_at(&self) -> DateTime<Utc> {
+        self.connected_at
+    }
+
+    // Both sides sent BIP339 wtxidrelay before verack, so txs are announced by wtxid.
+    pub fn wtxid_relay(&self) -> bool {
+        self.wtxid_relay
+    }
+
+    // Both sides sent BIP331 sendpackages with ancestor package support, on top of wtxidrelay.
+    pub fn package_relay(&self) -> bool {
+        self.package_relay
+    }
+
+    pub fn fee_filter(&self) -> u64 {
+        self.fee_filter.load(Ordering::Relaxed)
+    }
+
+    pub fn set_fee_filter(&self, sat_per_kvb: u64) {
+        self.fee_filter.store(sat_per_kvb, Ordering::Relaxed);
+    }
+
+    // Round trip of the last answered ping.
+    pub fn ping(&self) -> Option<Duration> {
+        micros_to_duration(self.activity.ping_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn min_ping(&self) -> Option<Duration> {
+        micros_to_duration(self.activity.min_ping_micros.load(Ordering::Relaxed))
+    }
+
+    pub fn last_tx(&self) -> i64 {
+        self.activity.last_tx.load(Ordering::Relaxed)
+    }
+
+    pub fn last_block(&self) -> i64 {
+        self.activity.last_block.load(Ordering::Relaxed)
+    }
+
+    // The peer sent us a tx we had not seen.
+    pub fn mark_tx(&self) {
+        self.activity
+            .last_tx
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    // The peer sent us a block we had not seen.
+    pub fn mark_block(&self) {
+        self.activity
+            .last_block
+            .store(Utc::now().timestamp(), Ordering::Relaxed);
+    }
+
+    // Asks the peer task to close the connection; it reports back via `PeerEvent::Disconnected`.
+    pub fn disconnect(&self) {
+        self.disconnect.notify_one();
+    }
 }
 
 pub struct Peer {
     addr: SocketAddr,
     stream: TcpStream,
     our_addr: SocketAddr,
+    magic: Magic,
+    transport: Transport,
+    // Bytes read off the socket but not yet consumed (handshake lookahead).
+    read_buf: Vec<u8>,
     db: Arc<AddressDb>,
     event_tx: mpsc::UnboundedSender<PeerEvent>,
     to_peer_rx: mpsc::Receiver<Message>,
@@ -83,35 +206,60 @@ pub struct Peer {
     node_type: NodeType,
     version: Option<PeerVersion>,
     user_agent: String,
+    inbound: bool,
+    connected_at: DateTime<Utc>,
+    disconnect: Arc<Notify>,
+    fee_filter: Arc<AtomicU64>,
+    wtxid_relay: bool,
+    package_relay: bool,
+    activity: Arc<Activity>,
+    // Nonce and send time of the ping awaiting its pong.
+    ping_sent: Option<(u64, TokioInstant)>,
+}
+
+enum Transport {
+    V1,
+    V2 {
+        cipher: Box<bip324::Cipher>,
+        // Decrypted length of a packet whose body has not fully arrived yet.
+        pending_len: Option<usize>,
+    },
 }
 
 impl Peer {
+    #[allow(clippy::too_many_arguments)]
     pub async fn connect(
-        addr: SocketAddr,
+        target: DialTarget,
         our_addr: SocketAddr,
+        network: Network,
         user_agent: String,
         db: Arc<AddressDb>,
         event_tx: mpsc::UnboundedSender<PeerEvent>,
         start_height: i32,
+        dialer: Arc<Dialer>,
     ) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
-        let local_addr = stream.local_addr().unwrap_or(our_addr);
-        info!("Connected to peer {}", addr);
-
-        let (to_peer_tx, to_peer_rx) = mpsc::channel(OUTBOUND_QUEUE_CAPACITY);
-
-        let mut peer = Self {
+        let addr = target.peer_addr();
+        let try_v2 = db.supports_v2_transport(addr).unwrap_or(false);
+        let stream = dialer.connect(&target).await?;
+        let mut peer = Self::new(
             addr,
             stream,
-            our_addr: local_addr,
-            db,
-            event_tx,
-            to_peer_rx,
-            to_peer_tx,
-            node_type: NodeType::Unknown,
-            version: None,
-            user_agent,
-        };
+            our_addr,
+            network,
+            user_agent.clone(),
+            db.clone(),
+            event_tx.clone(),
+        );
+
+        if try_v2
+            && let Err(e) = peer.v2_initiate().await
+        {
+            // v1-only nodes drop the connection on our key; redial in plaintext.
+            debug!("v2 handshake with {} failed, falling back to v1: {}", target, e);
+            let stream = dialer.connect(&target).await?;
+            peer = Self::new(addr, stream, our_addr, network, user_agent, db, event_tx);
+        }
+        info!("Connected to peer {}", target);
 
         peer.handshake(start_height).await?;
 
@@ -121,21 +269,48 @@ impl Peer {
     pub async fn accept(
         stream: TcpStream,
         our_addr: SocketAddr,
+        network: Network,
         user_agent: String,
         db: Arc<AddressDb>,
         event_tx: mpsc::UnboundedSender<PeerEvent>,
         start_height: i32,
     ) -> Result<Self> {
         let addr = stream.peer_addr()?;
-        let local_addr = stream.local_addr().unwrap_or(our_addr);
         info!("Accepted connection from {}", addr);
 
+        let mut peer = Self::new(addr, stream, our_addr, network, user_agent, db, event_tx);
+        peer.inbound = true;
+
+        // v1 initiators always open with a version message; anything else is a v2 key.
+        peer.fill_read_buf(bip324::V1_PREFIX_LEN).await?;
+        if peer.read_buf[..bip324::V1_PREFIX_LEN] != bip324::v1_prefix(peer.magic) {
+            peer.v2_respond().await?;
+        }
+
+        peer.handshake(start_height).await?;
+
+        Ok(peer)
+    }
+
+    fn new(
+        addr: SocketAddr,
+        stream: TcpStream,
+        our_addr: SocketAddr,
+        network: Network,
+        user_agent: String,
+        db: Arc<AddressDb>,
+        event_tx: mpsc::UnboundedSender<PeerEvent>,
+    ) -> Self {
+        let local_addr = stream.local_addr().unwrap_or(our_addr);
         let (to_peer_tx, to_peer_rx) = mpsc::channel(OUTBOUND_QUEUE_CAPACITY);
 
-        let mut peer = Self {
+        Self {
             addr,
             stream,
             our_addr: local_addr,
+            magic: network.magic(),
+            transport: Transport::V1,
+            read_buf: Vec::new(),
             db,
             event_tx,
             to_peer_rx,
@@ -143,11 +318,96 @@ impl Peer {
             node_type: NodeType::Unknown,
             version: None,
             user_agent,
+            inbound: false,
+            connected_at: Utc::now(),
+            disconnect: Arc::new(Notify::new()),
+            fee_filter: Arc::new(AtomicU64::new(0)),
+            wtxid_relay: false,
+            package_relay: false,
+            activity: Arc::new(Activity::new()),
+            ping_sent: None,
+        }
+    }
+
+    fn is_v2(&self) -> bool {
+        matches!(self.transport, Transport::V2 { .. })
+    }
+
+    async fn v2_initiate(&mut self) -> Result<()> {
+        let key_exchange = bip324::KeyExchange::new(true);
+        let garbage = bip324::random_garbage();
+
+        let mut out = key_exchange.public_bytes().to_vec();
+        out.extend_from_slice(&garbage);
+        self.write_raw(&out).await?;
+
+        let their_key = self.read_exact_buffered(bip324::ELLSWIFT_LEN).await?;
+        self.v2_finish(key_exchange, &their_key, &garbage).await
+    }
+
+    async fn v2_respond(&mut self) -> Result<()> {
+        let key_exchange = bip324::KeyExchange::new(false);
+        let their_key = self.read_exact_buffered(bip324::ELLSWIFT_LEN).await?;
+        let garbage = bip324::random_garbage();
+
+        let mut out = key_exchange.public_bytes().to_vec();
+        out.extend_from_slice(&garbage);
+        self.write_raw(&out).await?;
+
+        self.v2_finish(key_exchange, &their_key, &garbage).await
+    }
+
+    async fn v2_finish(
+        &mut self,
+        key_exchange: bip324::KeyExchange,
+        their_key: &[u8],
+        our_garbage: &[u8],
+    ) -> Result<()> {
+        let mut their_ellswift = [0u8; bip324::ELLSWIFT_LEN];
+        their_ellswift.copy_from_slice(their_key);
+        let mut cipher = key_exchange.complete(their_ellswift, self.magic)?;
+
+        // Garbage terminator followed by the (empty) version packet authenticating our garbage.
+        let mut out = cipher.send_garbage_terminator().to_vec();
+        out.extend(cipher.encrypt_packet(&[], our_garbage, false)?);
+        self.write_raw(&out).await?;
+
+        let terminator = *cipher.recv_garbage_terminator();
+        let session_id = *cipher.session_id();
+        self.transport = Transport::V2 {
+            cipher: Box::new(cipher),
+            pending_len: None,
         };
 
-        peer.handshake(start_height).await?;
+        let their_garbage = loop {
+            if let Some(pos) = self
+                .read_buf
+                .windows(bip324::GARBAGE_TERMINATOR_LEN)
+                .position(|window| window == terminator)
+            {
+                let garbage: Vec<u8> = self.read_buf.drain(..pos).collect();
+                self.read_buf.drain(..bip324::GARBAGE_TERMINATOR_LEN);
+                break garbage;
+            }
+            if self.read_buf.len() >= bip324::MAX_GARBAGE_LEN + bip324::GARBAGE_TERMINATOR_LEN {
+                anyhow::bail!("v2 garbage terminator not found");
+            }
+            let wanted = self.read_buf.len() + 1;
+            self.fill_read_buf(wanted).await?;
+        };
 
-        Ok(peer)
+        // Only the first packet authenticates the garbage; skip any decoys before version.
+        let mut aad = their_garbage;
+        while self.recv_packet(&aad).await?.is_none() {
+            aad.clear();
+        }
+
+        debug!(
+            "Established v2 transport with {} (session id {})",
+            self.addr,
+            session_id.as_hex()
+        );
+        Ok(())
     }
 
     async fn handshake(&mut self, start_height: i32) -> Result<()> {
@@ -178,10 +438,17 @@ impl Peer {
         );
 
         // Advertise optional capabilities that are negotiated between version and verack.
-        if peer_version.version >= 70016 {
+        let sent_wtxid_relay = peer_version.version >= 70016;
+        if sent_wtxid_relay {
             self.send_message(&Message::SendAddrV2).await?;
             self.send_message(&Message::WtxidRelay).await?;
         }
+        // BIP331 is only offered to peers that want tx relay at all.
+        let sent_package_relay = sent_wtxid_relay && peer_version.relay;
+        if sent_package_relay {
+            self.send_message(&Message::SendPackages(PKG_RELAY_ANCPKG))
+                .await?;
+        }
 
         // Send verack
         self.send_message(&Message::Verack).await?;
@@ -193,9 +460,18 @@ impl Peer {
                 Some(Message::Ping(nonce)) => {
                     self.send_message(&Message::Pong(nonce)).await?;
                 }
-                Some(message @ Message::SendAddrV2)
-                | Some(message @ Message::WtxidRelay)
-                | Some(message @ Message::FeeFilter(_)) => {
+                Some(Message::WtxidRelay) => {
+                    self.wtxid_relay = sent_wtxid_relay;
+                    let _ = self.event_tx.send(PeerEvent::Message {
+                        addr: self.addr,
+                        message: Message::WtxidRelay,
+                    });
+                }
+                Some(Message::SendPackages(versions)) => {
+                    self.package_relay =
+                        sent_package_relay && versions & PKG_RELAY_ANCPKG != 0;
+                }
+                Some(message @ Message::SendAddrV2) | Some(message @ Message::FeeFilter(_)) => {
                     let _ = self.event_tx.send(PeerEvent::Message {
                         addr: self.addr,
                         message,
@@ -206,10 +482,22 @@ impl Peer {
             }
         }
 
+        // Package relay builds on wtxid relay; without it the negotiation is void.
+        self.package_relay &= self.wtxid_relay;
+
+        // BIP130: have new blocks announced with headers rather than inv.
+        self.send_message(&Message::SendHeaders).await?;
+        // BIP152: we can take compact blocks; high-bandwidth mode is chosen per peer later.
+        self.send_message(&Message::SendCmpct(SendCmpct {
+            send_compact: false,
+            version: COMPACT_BLOCK_VERSION,
+        }))
+        .await?;
+
         // Update database
         let user_agent = peer_version.user_agent.clone();
         let node_info = NodeInfo {
-            addr: self.addr,
+            addr: NetAddr::Ip(self.addr),
             node_type: self.node_type,
             user_agent: Some(user_agent),
             version: Some(peer_version.version as i32),
@@ -218,8 +506,12 @@ impl Peer {
             last_connected: Some(Utc::now()),
             connection_failures: 0,
             is_reachable: true,
+            v2_transport: Some(self.is_v2()),
         };
-        let _ = self.db.insert_or_update(&node_info)?;
+        // Hostname targets only have a synthetic address; don't persist it.
+        if !dialer::is_internal_addr(self.addr) {
+            let _ = self.db.insert_or_update(&node_info)?;
+        }
 
         // Notify manager
         self.event_tx.send(PeerEvent::Connected {
@@ -235,21 +527,35 @@ impl Peer {
             addr: self.addr,
             sender: self.to_peer_tx.clone(),
             node_type: self.node_type,
+            v2_transport: self.is_v2(),
             user_agent: self
                 .version
                 .as_ref()
                 .map(|v| v.user_agent.clone())
                 .unwrap_or_default(),
+            inbound: self.inbound,
+            connected_at: self.connected_at,
+            disconnect: self.disconnect.clone(),
+            fee_filter: self.fee_filter.clone(),
+            wtxid_relay: self.wtxid_relay,
+            package_relay: self.package_relay,
+            activity: self.activity.clone(),
         }
     }
 
     pub async fn run(mut self) {
         let mut buf = [0u8; 8192];
-        let mut accumulated = Vec::new();
-        let mut keepalive = interval_at(
-            TokioInstant::now() + Duration::from_secs(30),
-            Duration::from_secs(30),
-        );
+        let mut accumulated = std::mem::take(&mut self.read_buf);
+        let mut keepalive = interval_at(TokioInstant::now() + PING_INTERVAL, PING_INTERVAL);
+
+        // Messages that arrived together with the handshake.
+        if let Err(e) = self.process_buffer(&mut accumulated).await {
+            let _ = self.event_tx.send(PeerEvent::Disconnected {
+                addr: self.addr,
+                reason: format!("Transport error: {}", e),
+            });
+            return;
+        }
 
         loop {
             tokio::select! {
@@ -265,7 +571,13 @@ impl Peer {
                         }
                         Ok(n) => {
                             accumulated.extend_from_slice(&buf[..n]);
-                            self.process_buffer(&mut accumulated).await;
+                            if let Err(e) = self.process_buffer(&mut accumulated).await {
+                                let _ = self.event_tx.send(PeerEvent::Disconnected {
+                                    addr: self.addr,
+                                    reason: format!("Transport error: {}", e),
+                                });
+                                break;
+                            }
                         }
                         Err(e) => {
                             let _ = self.event_tx.send(PeerEvent::Disconnected {
@@ -289,8 +601,30 @@ impl Peer {
                     }
                 }
 
+                _ = self.disconnect.notified() => {
+                    let _ = self.event_tx.send(PeerEvent::Disconnected {
+                        addr: self.addr,
+                        reason: "Disconnect requested".to_string(),
+                    });
+                    break;
+                }
+
                 _ = keepalive.tick() => {
-                    if let Err(e) = self.send_message(&Message::Ping(rand::random())).await {
+                    // Like Core, no new ping goes out while one is unanswered.
+                    if let Some((_, sent_at)) = self.ping_sent {
+                        if sent_at.elapsed() < PING_TIMEOUT {
+                            continue;
+                        }
+                        warn!("Peer {} did not answer ping within {:?}", self.addr, PING_TIMEOUT);
+                        let _ = self.event_tx.send(PeerEvent::Disconnected {
+                            addr: self.addr,
+                            reason: "Ping timeout".to_string(),
+                        });
+                        break;
+                    }
+                    let nonce = rand::random();
+                    self.ping_sent = Some((nonce, TokioInstant::now()));
+                    if let Err(e) = self.send_message(&Message::Ping(nonce)).await {
                         warn!("Failed to send ping to {}: {}", self.addr, e);
                         let _ = self.event_tx.send(PeerEvent::Disconnected {
                             addr: self.addr,
@@ -303,34 +637,40 @@ impl Peer {
         }
     }
 
-    async fn process_buffer(&mut self, buf: &mut Vec<u8>) {
+    async fn process_buffer(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        if self.is_v2() {
+            return self.process_buffer_v2(buf).await;
+        }
+
         // Bitcoin P2P messages have a header of 24 bytes
         // 4 magic, 12 command, 4 length, 4 checksum
         loop {
             if buf.len() < 24 {
-                return;
+                return Ok(());
+            }
+
+            // Without the magic we cannot tell where the next message starts.
+            if buf[..4] != self.magic.to_bytes() {
+                self.misbehaving(Misbehavior::BadMagic);
+                anyhow::bail!("Unexpected network magic: {:02x?}", &buf[..4]);
             }
 
             let payload_len = u32::from_le_bytes([buf[16], buf[17], buf[18], buf[19]]) as usize;
 
             if payload_len > MAX_MESSAGE_SIZE {
-                warn!(
-                    "Oversized message from {}: {} bytes",
-                    self.addr, payload_len
-                );
-                buf.clear();
-                return;
+                self.misbehaving(Misbehavior::OversizedMessage);
+                anyhow::bail!("Oversized message: {} bytes", payload_len);
             }
 
             let total_len = 24 + payload_len;
             if buf.len() < total_len {
-                return;
+                return Ok(());
             }
 
             let message_data = buf[..total_len].to_vec();
             buf.drain(..total_len);
 
-            match parse_message(&message_data) {
+            match parse_message(&message_data, self.magic) {
                 Ok(msg) => {
                     debug!(
                         "Received {:?} from {}",
@@ -341,16 +681,106 @@ impl Peer {
                 }
                 Err(e) => {
                     debug!("Failed to parse message from {}: {}", self.addr, e);
+                    self.misbehaving(Misbehavior::ParseFailure);
                 }
             }
         }
     }
 
+    async fn process_buffer_v2(&mut self, buf: &mut Vec<u8>) -> Result<()> {
+        // v2 packets: 3 byte encrypted length, then header, contents and 16 byte tag.
+        // Unlike v1 the stream cannot be resynchronized, so errors are fatal.
+        loop {
+            let contents = {
+                let Transport::V2 {
+                    cipher,
+                    pending_len,
+                } = &mut self.transport
+                else {
+                    return Ok(());
+                };
+
+                let contents_len = match *pending_len {
+                    Some(len) => len,
+                    None => {
+                        if buf.len() < bip324::LENGTH_FIELD_LEN {
+                            return Ok(());
+                        }
+                        let mut length = [0u8; bip324::LENGTH_FIELD_LEN];
+                        length.copy_from_slice(&buf[..bip324::LENGTH_FIELD_LEN]);
+                        buf.drain(..bip324::LENGTH_FIELD_LEN);
+                        let len = cipher.decrypt_length(length);
+                        if len > MAX_MESSAGE_SIZE {
+                            let _ = self.event_tx.send(PeerEvent::Misbehaving {
+                                addr: self.addr,
+                                misbehavior: Misbehavior::OversizedMessage,
+                            });
+                            anyhow::bail!("Oversized v2 packet: {} bytes", len);
+                        }
+                        *pending_len = Some(len);
+                        len
+                    }
+                };
+
+                let total_len = bip324::HEADER_LEN + contents_len + bip324::TAG_LEN;
+                if buf.len() < total_len {
+                    return Ok(());
+                }
+                let packet: Vec<u8> = buf.drain(..total_len).collect();
+                *pending_len = None;
+                cipher.decrypt_packet(&packet, &[])?
+            };
+
+            let Some(contents) = contents else {
+                continue;
+            };
+
+            match parse_message_v2(&contents, self.magic) {
+                Ok(msg) => {
+                    debug!(
+                        "Received {:?} from {}",
+                        std::mem::discriminant(&msg),
+                        self.addr
+                    );
+                    self.handle_message(msg).await;
+                }
+                Err(e) => {
+                    debug!("Failed to parse v2 message from {}: {}", self.addr, e);
+                    self.misbehaving(Misbehavior::ParseFailure);
+                }
+            }
+        }
+    }
+
+    fn misbehaving(&self, misbehavior: Misbehavior) {
+        let _ = self.event_tx.send(PeerEvent::Misbehaving {
+            addr: self.addr,
+            misbehavior,
+        });
+    }
+
     async fn handle_message(&mut self, msg: Message) {
         match msg {
             Message::Ping(nonce) => {
                 let _ = self.send_message(&Message::Pong(nonce)).await;
             }
+            Message::Pong(nonce) => {
+                if let Some((sent_nonce, sent_at)) = self.ping_sent
+                    && sent_nonce == nonce
+                {
+                    self.ping_sent = None;
+                    let rtt = sent_at.elapsed();
+                    let micros = rtt.as_micros() as u64;
+                    self.activity.ping_micros.store(micros, Ordering::Relaxed);
+                    self.activity
+                        .min_ping_micros
+                        .fetch_min(micros, Ordering::Relaxed);
+                    let _ = self.event_tx.send(PeerEvent::Pong {
+                        addr: self.addr,
+                        rtt,
+                    });
+                }
+            }
             Message::Addr(entries) => {
                 let _ = self.event_tx.send(PeerEvent::Addresses {
                     addr: self.addr,
@@ -376,15 +806,31 @@ impl Peer {
     }
 
     async fn send_message(&mut self, msg: &Message) -> Result<()> {
-        let data = serialize_message(msg, MAGIC)?;
-        self.stream.write_all(&data).await?;
+        let data = match &mut self.transport {
+            Transport::V1 => serialize_message(msg, self.magic)?,
+            Transport::V2 { cipher, .. } => {
+                cipher.encrypt_packet(&serialize_message_v2(msg)?, &[], false)?
+            }
+        };
+        self.write_raw(&data).await
+    }
+
+    async fn write_raw(&mut self, data: &[u8]) -> Result<()> {
+        self.stream.write_all(data).await?;
         self.stream.flush().await?;
         Ok(())
     }
 
     async fn recv_message(&mut self) -> Result<Option<Message>> {
-        let mut header = [0u8; 24];
-        self.stream.read_exact(&mut header).await?;
+        if self.is_v2() {
+            loop {
+                if let Some(contents) = self.recv_packet(&[]).await? {
+                    return Ok(Some(parse_message_v2(&contents, self.magic)?));
+                }
+            }
+        }
+
+        let header = self.read_exact_buffered(24).await?;
 
         let payload_len =
             u32::from_le_bytes([header[16], header[17], header[18], header[19]]) as usize;
@@ -393,15 +839,56 @@ impl Peer {
             anyhow::bail!("Payload too large: {}", payload_len);
         }
 
-        let mut payload = vec![0u8; payload_len];
-        if payload_len > 0 {
-            self.stream.read_exact(&mut payload).await?;
-        }
+        let payload = self.read_exact_buffered(payload_len).await?;
 
         let mut full_message = Vec::with_capacity(24 + payload_len);
         full_message.extend_from_slice(&header);
         full_message.extend_from_slice(&payload);
 
-        Ok(Some(parse_message(&full_message)?))
+        Ok(Some(parse_message(&full_message, self.magic)?))
+    }
+
+    // Reads one v2 packet during the handshake. Returns `None` for decoy packets.
+    async fn recv_packet(&mut self, aad: &[u8]) -> Result<Option<Vec<u8>>> {
+        let length_bytes = self.read_exact_buffered(bip324::LENGTH_FIELD_LEN).await?;
+        let Transport::V2 { cipher, .. } = &mut self.transport else {
+            anyhow::bail!("v2 packet on v1 transport");
+        };
+        let mut length = [0u8; bip324::LENGTH_FIELD_LEN];
+        length.copy_from_slice(&length_bytes);
+        let contents_len = cipher.decrypt_length(length);
+        if contents_len > MAX_MESSAGE_SIZE {
+            anyhow::bail!("Payload too large: {}", contents_len);
+        }
+
+        let packet = self
+            .read_exact_buffered(bip324::HEADER_LEN + contents_len + bip324::TAG_LEN)
+            .await?;
+        let Transport::V2 { cipher, .. } = &mut self.transport else {
+            anyhow::bail!("v2 packet on v1 transport");
+        };
+        cipher.decrypt_packet(&packet, aad)
     }
+
+    async fn fill_read_buf(&mut self, len: usize) -> Result<()> {
+        let mut chunk = [0u8; 8192];
+        while self.read_buf.len() < len {
+            let n = self.stream.read(&mut chunk).await?;
+            if n == 0 {
+                anyhow::bail!("Connection closed");
+            }
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        }
+        Ok(())
+    }
+
+    async fn read_exact_buffered(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.fill_read_buf(len).await?;
+        Ok(self.read_buf.drain(..len).collect())
+    }
+}
+
+// u64::MAX stands for not measured yet.
+fn micros_to_duration(micros: u64) -> Option<Duration> {
+    (micros != u64::MAX).then(|| Duration::from_micros(micros))
 }
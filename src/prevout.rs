@@ -0,0 +1,227 @@
+use anyhow::Result;
+use bitcoin::{Amount, OutPoint};
+use http_body_util::{BodyExt, Empty};
+use hyper::Request;
+use hyper::body::Bytes;
+use hyper::client::conn::http1::SendRequest;
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+use tracing::debug;
+
+// Bitcoin Core refuses getutxos requests with more outpoints than this.
+const MAX_OUTPOINTS_PER_REQUEST: usize = 15;
+// Lookups run off the event loop, but txs queued behind a slow one still wait for it.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+// Output values remembered; a value never changes once the output exists.
+const CACHE_LIMIT: usize = 100_000;
+
+// Looks up spent output values through a Bitcoin Core node started with `-rest`.
+pub struct RestPrevoutSource {
+    addr: SocketAddr,
+}
+
+// One answered lookup, handed back with the context it was requested with.
+pub struct Lookup<T> {
+    pub context: T,
+    pub outpoints: Vec<OutPoint>,
+    // Value per outpoint, `None` where the node has no such unspent output; `None`
+    // altogether if the node could not be asked.
+    pub values: Option<Vec<Option<Amount>>>,
+}
+
+// Handle to the lookup task started by `RestPrevoutSource::spawn`.
+pub struct PrevoutLookups<T> {
+    requests: mpsc::UnboundedSender<(T, Vec<OutPoint>)>,
+    cache: Arc<Mutex<PrevoutCache>>,
+}
+
+impl<T> PrevoutLookups<T> {
+    // Answers come back in request order, cached values included.
+    pub fn lookup(&self, context: T, outpoints: Vec<OutPoint>) {
+        let _ = self.requests.send((context, outpoints));
+    }
+
+    pub fn cached(&self, outpoint: &OutPoint) -> Option<Amount> {
+        self.cache.lock().unwrap().values.get(outpoint).copied()
+    }
+}
+
+#[derive(Default)]
+struct PrevoutCache {
+    values: HashMap<OutPoint, Amount>,
+    order: VecDeque<OutPoint>,
+}
+
+impl PrevoutCache {
+    fn insert(&mut self, outpoint: OutPoint, value: Amount) {
+        if self.values.insert(outpoint, value).is_some() {
+            return;
+        }
+        self.order.push_back(outpoint);
+        while self.order.len() > CACHE_LIMIT {
+            if let Some(oldest) = self.order.pop_front() {
+                self.values.remove(&oldest);
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GetUtxosResponse {
+    bitmap: String,
+    utxos: Vec<Utxo>,
+}
+
+#[derive(Deserialize)]
+struct Utxo {
+    value: f64,
+}
+
+impl RestPrevoutSource {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self { addr }
+    }
+
+    // Starts the task that answers lookups one at a time over a single kept-alive connection.
+    pub fn spawn<T: Send + 'static>(
+        self,
+    ) -> (PrevoutLookups<T>, mpsc::UnboundedReceiver<Lookup<T>>) {
+        let (request_tx, mut request_rx) = mpsc::unbounded_channel::<(T, Vec<OutPoint>)>();
+        let (result_tx, result_rx) = mpsc::unbounded_channel();
+        let cache = Arc::new(Mutex::new(PrevoutCache::default()));
+        let mut client = RestClient {
+            addr: self.addr,
+            sender: None,
+            cache: cache.clone(),
+        };
+
+        tokio::spawn(async move {
+            while let Some((context, outpoints)) = request_rx.recv().await {
+                let values = match timeout(LOOKUP_TIMEOUT, client.values(&outpoints)).await {
+                    Ok(Ok(values)) => Some(values),
+                    Ok(Err(e)) => {
+                        debug!("Prevout lookup failed: {}", e);
+                        client.sender = None;
+                        None
+                    }
+                    Err(_) => {
+                        debug!("Prevout lookup timed out");
+                        client.sender = None;
+                        None
+                    }
+                };
+                let lookup = Lookup {
+                    context,
+                    outpoints,
+                    values,
+                };
+                if result_tx.send(lookup).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let lookups = PrevoutLookups {
+            requests: request_tx,
+            cache,
+        };
+        (lookups, result_rx)
+    }
+}
+
+struct RestClient {
+    addr: SocketAddr,
+    sender: Option<SendRequest<Empty<Bytes>>>,
+    cache: Arc<Mutex<PrevoutCache>>,
+}
+
+impl RestClient {
+    // Cached values first; the rest are fetched and cached if found.
+    async fn values(&mut self, outpoints: &[OutPoint]) -> Result<Vec<Option<Amount>>> {
+        let mut values: Vec<Option<Amount>> = {
+            let cache = self.cache.lock().unwrap();
+            outpoints
+                .iter()
+                .map(|outpoint| cache.values.get(outpoint).copied())
+                .collect()
+        };
+        let uncached: Vec<usize> = (0..outpoints.len())
+            .filter(|&i| values[i].is_none())
+            .collect();
+
+        for chunk in uncached.chunks(MAX_OUTPOINTS_PER_REQUEST) {
+            let requested: Vec<OutPoint> = chunk.iter().map(|&i| outpoints[i]).collect();
+            let fetched = self.fetch(&requested).await?;
+            let mut cache = self.cache.lock().unwrap();
+            for (&i, value) in chunk.iter().zip(fetched) {
+                if let Some(value) = value {
+                    cache.insert(outpoints[i], value);
+                }
+                values[i] = value;
+            }
+        }
+        Ok(values)
+    }
+
+    // Asks for confirmed outputs only. With checkmempool, outputs a mempool tx spends would
+    // read as missing, so every replacement would look like an orphan; outputs of
+    // unconfirmed txs reading as missing is what lets us fetch parents we never saw.
+    async fn fetch(&mut self, outpoints: &[OutPoint]) -> Result<Vec<Option<Amount>>> {
+        let path = outpoints
+            .iter()
+            .map(|outpoint| format!("{}-{}", outpoint.txid, outpoint.vout))
+            .collect::<Vec<_>>()
+            .join("/");
+        let uri = format!("/rest/getutxos/{}.json", path);
+
+        let request = Request::get(uri)
+            .header(hyper::header::HOST, self.addr.to_string())
+            .body(Empty::<Bytes>::new())?;
+        let sender = self.sender().await?;
+        sender.ready().await?;
+        let response = sender.send_request(request).await?;
+        if !response.status().is_success() {
+            anyhow::bail!("getutxos returned {}", response.status());
+        }
+        let body = response.into_body().collect().await?.to_bytes();
+        let parsed: GetUtxosResponse = serde_json::from_slice(&body)?;
+
+        // The bitmap has one '0'/'1' per requested outpoint; utxos lists only the found ones.
+        let mut utxos = parsed.utxos.into_iter();
+        parsed
+            .bitmap
+            .chars()
+            .take(outpoints.len())
+            .map(|bit| match bit {
+                '1' => {
+                    let utxo = utxos
+                        .next()
+                        .ok_or_else(|| anyhow::anyhow!("getutxos bitmap/utxos mismatch"))?;
+                    Ok(Some(Amount::from_btc(utxo.value)?))
+                }
+                _ => Ok(None),
+            })
+            .collect()
+    }
+
+    // The open connection, or a new one if the node closed it.
+    async fn sender(&mut self) -> Result<&mut SendRequest<Empty<Bytes>>> {
+        match self.sender.take() {
+            Some(sender) if !sender.is_closed() => Ok(self.sender.insert(sender)),
+            _ => {
+                let stream = TcpStream::connect(self.addr).await?;
+                let (sender, connection) =
+                    hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+                tokio::spawn(connection);
+                Ok(self.sender.insert(sender))
+            }
+        }
+    }
+}
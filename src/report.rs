@@ -0,0 +1,146 @@
+use crate::classify::TxCategory;
+use crate::db::{AddressDb, NodeType};
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use bitcoin::{BlockHash, Txid};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+// Blocks summarized by /reports/mined unless `?blocks=` asks otherwise; a day at most.
+const DEFAULT_REPORT_BLOCKS: usize = 6;
+const MAX_REPORT_BLOCKS: usize = 144;
+
+// What our relay log says about one confirmed tx.
+#[derive(Debug, Clone)]
+pub struct MinedTx {
+    pub txid: Txid,
+    // The tx itself reached us before the block did.
+    pub received: bool,
+    pub relayed: bool,
+    // Type of the peer the tx arrived from, if we still remember it.
+    pub first_sender: Option<NodeType>,
+    // Whether a Knots peer announced it; `None` if the relay log no longer tracked the tx.
+    pub knots_announced: Option<bool>,
+    pub categories: Vec<TxCategory>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct MinedCounts {
+    pub txs: usize,
+    pub received: usize,
+    pub relayed: usize,
+    // Tracked by the relay log with no Knots announcement.
+    pub never_announced_by_knots: usize,
+    // No longer tracked, so whether Knots announced them is unknown.
+    pub untracked: usize,
+}
+
+impl MinedCounts {
+    fn add(&mut self, other: &MinedCounts) {
+        self.txs += other.txs;
+        self.received += other.received;
+        self.relayed += other.relayed;
+        self.never_announced_by_knots += other.never_announced_by_knots;
+        self.untracked += other.untracked;
+    }
+}
+
+// Mined txs of one block that share a first sender and categories.
+pub struct MinedGroup {
+    pub block_hash: BlockHash,
+    pub height: u32,
+    pub first_sender: Option<NodeType>,
+    pub categories: Vec<TxCategory>,
+    pub counts: MinedCounts,
+}
+
+// Served next to /metrics with --enable-admin-api, which has no authentication either.
+pub fn router(db: Arc<AddressDb>) -> Router {
+    Router::new()
+        .route("/reports/mined", get(mined_report))
+        .with_state(db)
+}
+
+#[derive(Default, Serialize)]
+struct Summary {
+    #[serde(flatten)]
+    counts: MinedCounts,
+    first_sender: BTreeMap<&'static str, usize>,
+    categories: BTreeMap<&'static str, MinedCounts>,
+}
+
+impl Summary {
+    fn add(&mut self, group: &MinedGroup) {
+        self.counts.add(&group.counts);
+        if let Some(node_type) = group.first_sender {
+            *self.first_sender.entry(node_type.as_str()).or_default() += group.counts.txs;
+        }
+        for category in &group.categories {
+            self.categories
+                .entry(category.as_str())
+                .or_default()
+                .add(&group.counts);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BlockSummary {
+    block_hash: String,
+    height: u32,
+    #[serde(flatten)]
+    summary: Summary,
+}
+
+#[derive(Deserialize)]
+struct MinedQuery {
+    blocks: Option<usize>,
+}
+
+// Confirmed txs of the most recent blocks, per block and in total: how many passed through
+// us, who sent them first, and how many no Knots peer ever announced.
+async fn mined_report(
+    State(db): State<Arc<AddressDb>>,
+    Query(query): Query<MinedQuery>,
+) -> Response {
+    let limit = query
+        .blocks
+        .unwrap_or(DEFAULT_REPORT_BLOCKS)
+        .clamp(1, MAX_REPORT_BLOCKS);
+
+    let groups = match tokio::task::spawn_blocking(move || db.load_mined_groups(limit)).await {
+        Ok(Ok(groups)) => groups,
+        Ok(Err(e)) => return error(e.to_string()),
+        Err(e) => return error(e.to_string()),
+    };
+    let mut blocks: Vec<BlockSummary> = Vec::new();
+    let mut total = Summary::default();
+    for group in &groups {
+        let block_hash = group.block_hash.to_string();
+        if blocks.last().is_none_or(|block| block.block_hash != block_hash) {
+            blocks.push(BlockSummary {
+                block_hash,
+                height: group.height,
+                summary: Summary::default(),
+            });
+        }
+        if let Some(block) = blocks.last_mut() {
+            block.summary.add(group);
+        }
+        total.add(group);
+    }
+    Json(json!({ "blocks": blocks, "total": total })).into_response()
+}
+
+fn error(message: String) -> Response {
+    (
+        StatusCode::INTERNAL_SERVER_ERROR,
+        Json(json!({ "error": message })),
+    )
+        .into_response()
+}
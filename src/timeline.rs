@@ -0,0 +1,376 @@
+use crate::db::NodeType;
+use bitcoin::BlockHash;
+use chrono::{DateTime, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+const TIMELINE_LIMIT: usize = 20_000;
+// Popular txs are announced by nearly every peer; later announcers add little.
+const MAX_ANNOUNCEMENTS_PER_TX: usize = 128;
+// Blocks come every ten minutes; the last few hours are plenty to catch late announcers.
+const BLOCK_TIMELINE_LIMIT: usize = 32;
+// Announced blocks whose header has not validated yet, overall and per announcing peer.
+const UNVALIDATED_BLOCK_LIMIT: usize = 256;
+const UNVALIDATED_BLOCKS_PER_PEER: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct Announcement {
+    pub peer: SocketAddr,
+    pub node_type: NodeType,
+    pub at: DateTime<Utc>,
+    // Time since the first announcement of this tx from any peer.
+    pub delay: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub peer: SocketAddr,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Arrival {
+    pub peer: SocketAddr,
+    pub node_type: NodeType,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Relay {
+    pub at: DateTime<Utc>,
+    pub peers: usize,
+}
+
+// What we observed for one tx, keyed by the hash it was first announced under.
+#[derive(Debug, Clone)]
+pub struct TxRecord {
+    pub txid: Option<[u8; 32]>,
+    pub wtxid: Option<[u8; 32]>,
+    pub first_seen: DateTime<Utc>,
+    first_seen_instant: Instant,
+    pub announcements: Vec<Announcement>,
+    // Kept separately since `announcements` is capped.
+    pub knots_announced: bool,
+    pub request: Option<Request>,
+    pub arrival: Option<Arrival>,
+    pub relay: Option<Relay>,
+}
+
+impl TxRecord {
+    fn new(now: Instant) -> Self {
+        Self {
+            txid: None,
+            wtxid: None,
+            first_seen: Utc::now(),
+            first_seen_instant: now,
+            announcements: Vec::new(),
+            knots_announced: false,
+            request: None,
+            arrival: None,
+            relay: None,
+        }
+    }
+}
+
+// Bounded first-seen timeline of recently announced transactions.
+#[derive(Default)]
+pub struct TxTimeline {
+    records: HashMap<[u8; 32], TxRecord>,
+    // wtxid -> txid once the tx has arrived and both hashes are known.
+    aliases: HashMap<[u8; 32], [u8; 32]>,
+    order: VecDeque<[u8; 32]>,
+}
+
+impl TxTimeline {
+    // Returns the delay since the first announcement, or `None` if this peer already announced it.
+    pub fn record_announcement(
+        &mut self,
+        key: [u8; 32],
+        peer: SocketAddr,
+        node_type: NodeType,
+    ) -> Option<Duration> {
+        let now = Instant::now();
+        let record = self.record_mut(key, now);
+        record.knots_announced |= node_type == NodeType::Knots;
+        if record.announcements.iter().any(|a| a.peer == peer) {
+            return None;
+        }
+
+        let delay = now.duration_since(record.first_seen_instant);
+        if record.announcements.len() < MAX_ANNOUNCEMENTS_PER_TX {
+            record.announcements.push(Announcement {
+                peer,
+                node_type,
+                at: Utc::now(),
+                delay,
+            });
+        }
+        Some(delay)
+    }
+
+    pub fn record_request(&mut self, key: [u8; 32], peer: SocketAddr) {
+        let record = self.record_mut(key, Instant::now());
+        record.request.get_or_insert(Request {
+            peer,
+            at: Utc::now(),
+        });
+    }
+
+    // Links the txid and wtxid records; a tx may have been announced under either.
+    pub fn record_arrival(
+        &mut self,
+        txid: [u8; 32],
+        wtxid: [u8; 32],
+        peer: SocketAddr,
+        node_type: NodeType,
+    ) {
+        let now = Instant::now();
+        if txid != wtxid
+            && let Some(by_wtxid) = self.records.remove(&wtxid)
+        {
+            self.order.retain(|key| key != &wtxid);
+            let record = self.record_mut(txid, now);
+            merge(record, by_wtxid);
+        }
+
+        let record = self.record_mut(txid, now);
+        record.txid = Some(txid);
+        record.wtxid = Some(wtxid);
+        record.arrival.get_or_insert(Arrival {
+            peer,
+            node_type,
+            at: Utc::now(),
+        });
+        if txid != wtxid {
+            self.aliases.insert(wtxid, txid);
+        }
+    }
+
+    pub fn record_relay(&mut self, txid: [u8; 32], peers: usize) {
+        if let Some(record) = self.records.get_mut(&txid) {
+            record.relay.get_or_insert(Relay {
+                at: Utc::now(),
+                peers,
+            });
+        }
+    }
+
+    // Looks up by txid or wtxid.
+    pub fn get(&self, key: &[u8; 32]) -> Option<&TxRecord> {
+        let key = self.aliases.get(key).unwrap_or(key);
+        self.records.get(key)
+    }
+
+    fn record_mut(&mut self, key: [u8; 32], now: Instant) -> &mut TxRecord {
+        if !self.records.contains_key(&key) {
+            self.order.push_back(key);
+            while self.order.len() > TIMELINE_LIMIT {
+                if let Some(oldest) = self.order.pop_front()
+                    && let Some(record) = self.records.remove(&oldest)
+                    && let Some(wtxid) = record.wtxid
+                {
+                    self.aliases.remove(&wtxid);
+                }
+            }
+        }
+        self.records.entry(key).or_insert_with(|| TxRecord::new(now))
+    }
+}
+
+// Folds `from` into `into`, re-basing announcement delays on the earlier first-seen time.
+fn merge(into: &mut TxRecord, from: TxRecord) {
+    let first = into.first_seen_instant.min(from.first_seen_instant);
+    let into_shift = into.first_seen_instant - first;
+    let from_shift = from.first_seen_instant - first;
+
+    for announcement in &mut into.announcements {
+        announcement.delay += into_shift;
+    }
+    into.announcements
+        .extend(from.announcements.into_iter().map(|mut announcement| {
+            announcement.delay += from_shift;
+            announcement
+        }));
+    into.announcements.sort_by_key(|a| a.delay);
+    into.announcements.truncate(MAX_ANNOUNCEMENTS_PER_TX);
+    into.knots_announced |= from.knots_announced;
+
+    if from.first_seen_instant < into.first_seen_instant {
+        into.first_seen = from.first_seen;
+        into.first_seen_instant = from.first_seen_instant;
+    }
+    into.request = match (into.request.take(), from.request) {
+        (Some(a), Some(b)) => Some(if a.at <= b.at { a } else { b }),
+        (a, b) => a.or(b),
+    };
+}
+
+// Every announcement of one block (inv, headers or cmpctblock), in the order they arrived.
+struct BlockRecord {
+    first_seen: Instant,
+    announcements: Vec<Announcement>,
+    // Announcements already written to the database.
+    persisted: usize,
+}
+
+// A block announcement held back until the block's header validates.
+struct UnvalidatedAnnouncement {
+    peer: SocketAddr,
+    node_type: NodeType,
+    seen: Instant,
+    at: DateTime<Utc>,
+}
+
+// Who announced recent blocks first. Unlike txs, every announcer is kept. Any hash can be
+// announced, so announcements wait in a separate buffer until the header validates.
+#[derive(Default)]
+pub struct BlockTimeline {
+    records: HashMap<BlockHash, BlockRecord>,
+    order: VecDeque<BlockHash>,
+    unvalidated: HashMap<BlockHash, Vec<UnvalidatedAnnouncement>>,
+    unvalidated_order: VecDeque<BlockHash>,
+}
+
+impl BlockTimeline {
+    // Whether the block has announcements, validated or not.
+    pub fn contains(&self, hash: &BlockHash) -> bool {
+        self.records.contains_key(hash) || self.unvalidated.contains_key(hash)
+    }
+
+    // Holds an announcement until `take_validated` finds the block's header valid. A peer
+    // can only have a few unvalidated blocks at once, so made-up hashes cannot push out
+    // everyone else's.
+    pub fn record_announcement(
+        &mut self,
+        hash: BlockHash,
+        peer: SocketAddr,
+        node_type: NodeType,
+    ) {
+        let announcement = UnvalidatedAnnouncement {
+            peer,
+            node_type,
+            seen: Instant::now(),
+            at: Utc::now(),
+        };
+        if let Some(announcements) = self.unvalidated.get_mut(&hash) {
+            if !announcements.iter().any(|a| a.peer == peer) {
+                announcements.push(announcement);
+            }
+            return;
+        }
+        let from_peer = self
+            .unvalidated
+            .values()
+            .filter(|announcements| announcements.iter().any(|a| a.peer == peer))
+            .count();
+        if from_peer >= UNVALIDATED_BLOCKS_PER_PEER {
+            return;
+        }
+        self.unvalidated.insert(hash, vec![announcement]);
+        self.unvalidated_order.push_back(hash);
+        while self.unvalidated_order.len() > UNVALIDATED_BLOCK_LIMIT {
+            if let Some(oldest) = self.unvalidated_order.pop_front() {
+                self.unvalidated.remove(&oldest);
+            }
+        }
+    }
+
+    // Moves announcements of blocks `is_valid` accepts into the timeline. Returns each new
+    // announcer's node type, position among the announcers (0 for the first) and delay since
+    // the first announcement.
+    pub fn take_validated(
+        &mut self,
+        is_valid: impl Fn(&BlockHash) -> bool,
+    ) -> Vec<(NodeType, usize, Duration)> {
+        let validated: Vec<BlockHash> = self
+            .unvalidated_order
+            .iter()
+            .filter(|hash| is_valid(hash))
+            .copied()
+            .collect();
+        if validated.is_empty() {
+            return Vec::new();
+        }
+        self.unvalidated_order.retain(|hash| !validated.contains(hash));
+
+        let mut observed = Vec::new();
+        for hash in validated {
+            let Some(announcements) = self.unvalidated.remove(&hash) else {
+                continue;
+            };
+            for announcement in announcements {
+                let node_type = announcement.node_type;
+                if let Some((position, delay)) = self.add_announcement(hash, announcement) {
+                    observed.push((node_type, position, delay));
+                }
+            }
+        }
+        observed
+    }
+
+    // Peers that announced the block, first announcer first.
+    pub fn announcers(&self, hash: &BlockHash) -> Vec<SocketAddr> {
+        self.records.get(hash).map_or_else(Vec::new, |record| {
+            record.announcements.iter().map(|a| a.peer).collect()
+        })
+    }
+
+    // Returns the peer's position among the announcers (0 for the first) and the delay since
+    // the first announcement, or `None` if this peer already announced it.
+    fn add_announcement(
+        &mut self,
+        hash: BlockHash,
+        announcement: UnvalidatedAnnouncement,
+    ) -> Option<(usize, Duration)> {
+        if !self.records.contains_key(&hash) {
+            self.order.push_back(hash);
+            while self.order.len() > BLOCK_TIMELINE_LIMIT {
+                if let Some(oldest) = self.order.pop_front() {
+                    self.records.remove(&oldest);
+                }
+            }
+        }
+        let record = self.records.entry(hash).or_insert_with(|| BlockRecord {
+            first_seen: announcement.seen,
+            announcements: Vec::new(),
+            persisted: 0,
+        });
+        if record.announcements.iter().any(|a| a.peer == announcement.peer) {
+            return None;
+        }
+
+        let delay = announcement.seen.duration_since(record.first_seen);
+        record.announcements.push(Announcement {
+            peer: announcement.peer,
+            node_type: announcement.node_type,
+            at: announcement.at,
+            delay,
+        });
+        Some((record.announcements.len() - 1, delay))
+    }
+
+    // Announcements not yet persisted, with their positions, for blocks `is_valid` accepts.
+    // Blocks whose header never validated are never written.
+    pub fn take_unpersisted(
+        &mut self,
+        is_valid: impl Fn(&BlockHash) -> bool,
+    ) -> Vec<(BlockHash, usize, Announcement)> {
+        let mut unpersisted = Vec::new();
+        for hash in &self.order {
+            let Some(record) = self.records.get_mut(hash) else {
+                continue;
+            };
+            if record.persisted == record.announcements.len() || !is_valid(hash) {
+                continue;
+            }
+            for (position, announcement) in
+                record.announcements.iter().enumerate().skip(record.persisted)
+            {
+                unpersisted.push((*hash, position, announcement.clone()));
+            }
+            record.persisted = record.announcements.len();
+        }
+        unpersisted
+    }
+}
@@ -0,0 +1,143 @@
+use crate::p2p::PeerHandle;
+use crate::p2p::message::Inventory;
+use bitcoin::{Txid, Wtxid};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Largest inv message peers accept (MAX_INV_SZ in Bitcoin Core).
+const MAX_INV_SZ: usize = 50_000;
+// Average flush intervals, as in Bitcoin Core. Inbound peers are cheap for an observer to
+// open, so they share one slower timer and learn about a tx at the same moment.
+const INBOUND_INVENTORY_INTERVAL: Duration = Duration::from_secs(5);
+const OUTBOUND_INVENTORY_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueuedInv {
+    pub txid: Txid,
+    pub wtxid: Wtxid,
+    // sat/kvB, `None` if the input values are unknown.
+    pub feerate: Option<u64>,
+    // Generations of ancestors found in the tx cache; parents sort before children.
+    pub depth: u32,
+}
+
+struct PeerQueue {
+    entries: Vec<QueuedInv>,
+    // Wtxids in `entries`, so a tx is queued at most once per peer.
+    queued: HashSet<Wtxid>,
+    next_flush: Instant,
+}
+
+// Per-peer tx announcements waiting for their Poisson-timed flush.
+pub struct TrickleState {
+    queues: HashMap<SocketAddr, PeerQueue>,
+    inbound_next_flush: Instant,
+}
+
+impl Default for TrickleState {
+    fn default() -> Self {
+        Self {
+            queues: HashMap::new(),
+            inbound_next_flush: Instant::now() + poisson_delay(INBOUND_INVENTORY_INTERVAL),
+        }
+    }
+}
+
+impl TrickleState {
+    pub fn push(&mut self, peer: SocketAddr, entry: QueuedInv) {
+        let queue = self.queues.entry(peer).or_insert_with(|| PeerQueue {
+            entries: Vec::new(),
+            queued: HashSet::new(),
+            next_flush: Instant::now() + poisson_delay(OUTBOUND_INVENTORY_INTERVAL),
+        });
+        if queue.queued.insert(entry.wtxid) {
+            queue.entries.push(entry);
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.queues.values().map(|queue| queue.entries.len()).sum()
+    }
+
+    pub fn disconnected(&mut self, peer: SocketAddr) {
+        self.queues.remove(&peer);
+    }
+
+    // Drops queued announcements of txs that made it into a block.
+    pub fn remove_confirmed(&mut self, txids: &HashSet<Txid>) {
+        for queue in self.queues.values_mut() {
+            let queued = &mut queue.queued;
+            queue.entries.retain(|entry| {
+                let confirmed = txids.contains(&entry.txid);
+                if confirmed {
+                    queued.remove(&entry.wtxid);
+                }
+                !confirmed
+            });
+        }
+    }
+
+    // Drains the queues whose timer fired into inv batches.
+    pub fn take_due(
+        &mut self,
+        peers: &[PeerHandle],
+        now: Instant,
+    ) -> Vec<(PeerHandle, Vec<Vec<Inventory>>)> {
+        let inbound_due = now >= self.inbound_next_flush;
+        if inbound_due {
+            self.inbound_next_flush = now + poisson_delay(INBOUND_INVENTORY_INTERVAL);
+        }
+
+        let mut due = Vec::new();
+        for peer in peers {
+            let Some(queue) = self.queues.get_mut(&peer.addr()) else {
+                continue;
+            };
+            let flush = if peer.is_inbound() {
+                inbound_due
+            } else if now >= queue.next_flush {
+                queue.next_flush = now + poisson_delay(OUTBOUND_INVENTORY_INTERVAL);
+                true
+            } else {
+                false
+            };
+            if !flush || queue.entries.is_empty() {
+                continue;
+            }
+
+            let mut entries = std::mem::take(&mut queue.entries);
+            queue.queued.clear();
+            // Parents first, then highest feerate first; unknown feerates go last.
+            entries.sort_by(|a, b| {
+                a.depth
+                    .cmp(&b.depth)
+                    .then_with(|| b.feerate.cmp(&a.feerate))
+            });
+
+            let batches = entries
+                .chunks(MAX_INV_SZ)
+                .map(|chunk| {
+                    chunk
+                        .iter()
+                        .map(|entry| {
+                            if peer.wtxid_relay() {
+                                Inventory::WTx(entry.wtxid)
+                            } else {
+                                Inventory::Transaction(entry.txid)
+                            }
+                        })
+                        .collect()
+                })
+                .collect();
+            due.push((peer.clone(), batches));
+        }
+        due
+    }
+}
+
+// Exponentially distributed delay, so flushes form a Poisson process with the given mean.
+fn poisson_delay(mean: Duration) -> Duration {
+    let uniform: f64 = rand::random();
+    mean.mul_f64(-(1.0 - uniform).ln())
+}
@@ -0,0 +1,245 @@
+use bitcoin::hashes::Hash;
+use bitcoin::{Txid, Wtxid};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Bitcoin Core's limits on outstanding getdata requests and remembered announcements per peer.
+const MAX_PEER_TX_REQUEST_IN_FLIGHT: usize = 100;
+const MAX_PEER_TX_ANNOUNCEMENTS: usize = 5000;
+// How long an announcer gets to deliver before we ask the next one.
+const TX_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+// Inbound peers are cheap to open, so an outbound announcer gets a head start (as in Core).
+pub const INBOUND_PEER_TX_DELAY: Duration = Duration::from_secs(2);
+// Announcers of given-up keys remembered, so they are not asked for the same key again and a
+// late delivery still counts as requested.
+const GIVEN_UP_LIMIT: usize = 50_000;
+
+// Announcements name a tx by txid or (BIP339) wtxid; each kind is tracked separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TxKey {
+    Txid(Txid),
+    Wtxid(Wtxid),
+}
+
+impl TxKey {
+    pub fn to_byte_array(self) -> [u8; 32] {
+        match self {
+            TxKey::Txid(txid) => txid.to_byte_array(),
+            TxKey::Wtxid(wtxid) => wtxid.to_byte_array(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    // Waiting until `reqtime`, or for an earlier choice to fail.
+    Candidate,
+    Requested { expiry: Instant },
+    // Timed out, answered notfound or sent a bad copy; never asked again for this key.
+    Failed,
+}
+
+struct Announcement {
+    peer: SocketAddr,
+    // Outbound peers are asked first; we picked them, an attacker did not.
+    preferred: bool,
+    reqtime: Instant,
+    state: State,
+}
+
+#[derive(Default)]
+pub struct DueRequests {
+    pub requests: HashMap<SocketAddr, Vec<TxKey>>,
+    pub timed_out: usize,
+    // Requests sent to a later announcer after an earlier one failed.
+    pub fallbacks: usize,
+    // Keys dropped because every announcer failed.
+    pub given_up: Vec<TxKey>,
+}
+
+// Tracks every announcer of each wanted tx, modelled on Bitcoin Core's TxRequestTracker:
+// at most one request per tx is in flight, and a failed request moves on to the next peer.
+#[derive(Default)]
+pub struct TxRequestTracker {
+    // Announcements per key, in arrival order.
+    announcements: HashMap<TxKey, Vec<Announcement>>,
+    announced_by_peer: HashMap<SocketAddr, usize>,
+    in_flight_by_peer: HashMap<SocketAddr, usize>,
+    // Announcers of keys dropped because every announcer failed, oldest first.
+    given_up: HashSet<(TxKey, SocketAddr)>,
+    given_up_order: VecDeque<(TxKey, SocketAddr)>,
+}
+
+impl TxRequestTracker {
+    // Remembers that `peer` can serve `key`; it becomes requestable after `delay`. A peer
+    // that already failed to deliver a given-up key is not tracked for it again.
+    pub fn received_inv(
+        &mut self,
+        key: TxKey,
+        peer: SocketAddr,
+        preferred: bool,
+        delay: Duration,
+        now: Instant,
+    ) {
+        if self.given_up.contains(&(key, peer)) {
+            return;
+        }
+        let announced = self.announced_by_peer.entry(peer).or_default();
+        if *announced >= MAX_PEER_TX_ANNOUNCEMENTS {
+            return;
+        }
+
+        let announcements = self.announcements.entry(key).or_default();
+        if announcements.iter().any(|a| a.peer == peer) {
+            return;
+        }
+        announcements.push(Announcement {
+            peer,
+            preferred,
+            reqtime: now + delay,
+            state: State::Candidate,
+        });
+        *announced += 1;
+    }
+
+    // The tx arrived (from anyone), so no announcer needs to be asked any more.
+    pub fn received_response(&mut self, key: TxKey) {
+        if let Some(announcements) = self.announcements.remove(&key) {
+            for announcement in announcements {
+                self.release(&announcement);
+            }
+        }
+    }
+
+    // `peer` cannot deliver `key`; the next tick asks another announcer.
+    pub fn received_notfound(&mut self, key: TxKey, peer: SocketAddr) {
+        let Some(announcements) = self.announcements.get_mut(&key) else {
+            return;
+        };
+        if let Some(announcement) = announcements.iter_mut().find(|a| a.peer == peer) {
+            if matches!(announcement.state, State::Requested { .. }) {
+                decrement(&mut self.in_flight_by_peer, peer);
+            }
+            announcement.state = State::Failed;
+        }
+    }
+
+    // Whether `key` was requested from `peer`, even if the request has since failed or the
+    // key was given up on.
+    pub fn requested_from(&self, key: TxKey, peer: SocketAddr) -> bool {
+        self.given_up.contains(&(key, peer))
+            || self.announcements.get(&key).is_some_and(|announcements| {
+                announcements
+                    .iter()
+                    .any(|a| a.peer == peer && a.state != State::Candidate)
+            })
+    }
+
+    pub fn disconnected(&mut self, peer: SocketAddr) {
+        self.announced_by_peer.remove(&peer);
+        self.in_flight_by_peer.remove(&peer);
+        self.announcements.retain(|_, announcements| {
+            announcements.retain(|a| a.peer != peer);
+            !announcements.is_empty()
+        });
+    }
+
+    // Expires overdue requests, then picks one announcer for every key without a request in
+    // flight: outbound before inbound, then the least loaded (slow peers pile up requests),
+    // then the earliest announcer. Peers at the in-flight cap are skipped, and keys whose
+    // announcers all failed are given up on.
+    pub fn requests_due(&mut self, now: Instant) -> DueRequests {
+        let mut due = DueRequests::default();
+        let mut given_up = Vec::new();
+        let in_flight = &mut self.in_flight_by_peer;
+        let announced = &mut self.announced_by_peer;
+
+        self.announcements.retain(|key, announcements| {
+            for announcement in announcements.iter_mut() {
+                if let State::Requested { expiry } = announcement.state
+                    && expiry <= now
+                {
+                    announcement.state = State::Failed;
+                    decrement(in_flight, announcement.peer);
+                    due.timed_out += 1;
+                }
+            }
+
+            if announcements.iter().all(|a| a.state == State::Failed) {
+                for announcement in announcements.iter() {
+                    decrement(announced, announcement.peer);
+                    given_up.push((*key, announcement.peer));
+                }
+                due.given_up.push(*key);
+                return false;
+            }
+            if announcements
+                .iter()
+                .any(|a| matches!(a.state, State::Requested { .. }))
+            {
+                return true;
+            }
+
+            let chosen = announcements
+                .iter()
+                .enumerate()
+                .filter(|(_, a)| a.state == State::Candidate && a.reqtime <= now)
+                .map(|(index, a)| (index, a, in_flight.get(&a.peer).copied().unwrap_or(0)))
+                .filter(|(_, _, load)| *load < MAX_PEER_TX_REQUEST_IN_FLIGHT)
+                .min_by_key(|(index, a, load)| (!a.preferred, *load, *index))
+                .map(|(index, _, _)| index);
+            if let Some(index) = chosen {
+                let announcement = &mut announcements[index];
+                announcement.state = State::Requested {
+                    expiry: now + TX_REQUEST_TIMEOUT,
+                };
+                *in_flight.entry(announcement.peer).or_default() += 1;
+                due.requests
+                    .entry(announcement.peer)
+                    .or_default()
+                    .push(*key);
+                if announcements.iter().any(|a| a.state == State::Failed) {
+                    due.fallbacks += 1;
+                }
+            }
+            true
+        });
+
+        for entry in given_up {
+            if self.given_up.insert(entry) {
+                self.given_up_order.push_back(entry);
+            }
+        }
+        while self.given_up_order.len() > GIVEN_UP_LIMIT {
+            if let Some(oldest) = self.given_up_order.pop_front() {
+                self.given_up.remove(&oldest);
+            }
+        }
+        due
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight_by_peer.values().sum()
+    }
+
+    pub fn tracked(&self) -> usize {
+        self.announcements.len()
+    }
+
+    fn release(&mut self, announcement: &Announcement) {
+        decrement(&mut self.announced_by_peer, announcement.peer);
+        if matches!(announcement.state, State::Requested { .. }) {
+            decrement(&mut self.in_flight_by_peer, announcement.peer);
+        }
+    }
+}
+
+fn decrement(counts: &mut HashMap<SocketAddr, usize>, peer: SocketAddr) {
+    if let Some(count) = counts.get_mut(&peer) {
+        *count = count.saturating_sub(1);
+        if *count == 0 {
+            counts.remove(&peer);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::classify;
+use bitcoin::{Amount, Transaction};
+use std::collections::HashSet;
+
+// Consensus limits from Bitcoin Core's CheckTransaction.
+const MAX_BLOCK_WEIGHT: u64 = 4_000_000;
+const WITNESS_SCALE_FACTOR: u64 = 4;
+const MAX_SCRIPT_SIZE: usize = 10_000;
+// Policy limits, at Bitcoin Core 29 defaults.
+const TX_MAX_STANDARD_VERSION: i32 = 3;
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+const MIN_STANDARD_TX_NONWITNESS_SIZE: usize = 65;
+const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1650;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    // Invalid under any policy; the sender is misbehaving.
+    NoInputs,
+    NoOutputs,
+    Oversize,
+    OutputValue,
+    DuplicateInputs,
+    Coinbase,
+    NullPrevout,
+    ScriptSigSize,
+    // Valid but non-standard; only rejected with `--policy-mode standard`.
+    Version,
+    TxWeight,
+    TxSizeSmall,
+    ScriptSig,
+    ScriptTemplate,
+    Dust,
+    OpReturn,
+}
+
+impl Rejection {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Rejection::NoInputs => "no_inputs",
+            Rejection::NoOutputs => "no_outputs",
+            Rejection::Oversize => "oversize",
+            Rejection::OutputValue => "output_value",
+            Rejection::DuplicateInputs => "duplicate_inputs",
+            Rejection::Coinbase => "coinbase",
+            Rejection::NullPrevout => "null_prevout",
+            Rejection::ScriptSigSize => "scriptsig_size",
+            Rejection::Version => "version",
+            Rejection::TxWeight => "tx_weight",
+            Rejection::TxSizeSmall => "tx_size_small",
+            Rejection::ScriptSig => "scriptsig",
+            Rejection::ScriptTemplate => "script_template",
+            Rejection::Dust => "dust",
+            Rejection::OpReturn => "op_return",
+        }
+    }
+}
+
+// Context-free checks any relayable tx passes, regardless of policy.
+pub fn check_sanity(tx: &Transaction) -> Result<(), Rejection> {
+    if tx.input.is_empty() {
+        return Err(Rejection::NoInputs);
+    }
+    if tx.output.is_empty() {
+        return Err(Rejection::NoOutputs);
+    }
+    if tx.base_size() as u64 * WITNESS_SCALE_FACTOR > MAX_BLOCK_WEIGHT
+        || tx.weight().to_wu() > MAX_BLOCK_WEIGHT
+    {
+        return Err(Rejection::Oversize);
+    }
+
+    let mut total = Amount::ZERO;
+    for output in &tx.output {
+        if output.value > Amount::MAX_MONEY {
+            return Err(Rejection::OutputValue);
+        }
+        total = total
+            .checked_add(output.value)
+            .filter(|total| *total <= Amount::MAX_MONEY)
+            .ok_or(Rejection::OutputValue)?;
+    }
+
+    let mut spent = HashSet::with_capacity(tx.input.len());
+    if !tx
+        .input
+        .iter()
+        .all(|input| spent.insert(input.previous_output))
+    {
+        return Err(Rejection::DuplicateInputs);
+    }
+    // Coinbases only exist inside blocks.
+    if tx.is_coinbase() {
+        return Err(Rejection::Coinbase);
+    }
+    if tx.input.iter().any(|input| input.previous_output.is_null()) {
+        return Err(Rejection::NullPrevout);
+    }
+    if tx
+        .input
+        .iter()
+        .any(|input| input.script_sig.len() > MAX_SCRIPT_SIZE)
+    {
+        return Err(Rejection::ScriptSigSize);
+    }
+    Ok(())
+}
+
+// Bitcoin Core's IsStandardTx, minus the checks that need the spent outputs.
+pub fn check_standard(tx: &Transaction) -> Result<(), Rejection> {
+    if !(1..=TX_MAX_STANDARD_VERSION).contains(&tx.version.0) {
+        return Err(Rejection::Version);
+    }
+    if tx.weight().to_wu() > MAX_STANDARD_TX_WEIGHT {
+        return Err(Rejection::TxWeight);
+    }
+    if tx.base_size() < MIN_STANDARD_TX_NONWITNESS_SIZE {
+        return Err(Rejection::TxSizeSmall);
+    }
+    if tx.input.iter().any(|input| {
+        input.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE || !input.script_sig.is_push_only()
+    }) {
+        return Err(Rejection::ScriptSig);
+    }
+
+    let mut op_returns = 0;
+    for output in &tx.output {
+        let script = &output.script_pubkey;
+        if !classify::is_standard_output(script) {
+            return Err(Rejection::ScriptTemplate);
+        }
+        if script.is_op_return() {
+            op_returns += 1;
+            if op_returns > 1 || script.len() > classify::MAX_OP_RETURN_RELAY {
+                return Err(Rejection::OpReturn);
+            }
+        } else if output.value < script.minimal_non_dust() {
+            return Err(Rejection::Dust);
+        }
+    }
+    Ok(())
+}